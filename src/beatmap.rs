@@ -0,0 +1,995 @@
+use crate::audio::{ gather_beats, select_onsets };
+use crate::constants::{
+    APPROACH_TIME,
+    BEATMAP_DIR,
+    CIRCLE_MAX_RADIUS,
+    DEFAULT_TICK_INTERVAL,
+    MAX_FILL_GAP,
+};
+use crate::structs::{
+    AnalysisParams,
+    AnalysisProgress,
+    Band,
+    BandParams,
+    Beatmap,
+    BeatmapCircle,
+    BeatmapDifficulty,
+    BeatmapMetadata,
+    BeatGrid,
+    ChannelMode,
+    CurveType,
+    DifficultyLevel,
+    LoadError,
+    Onset,
+    SliderPath,
+    SnapDivision,
+    SongAnalysis,
+    SongEntry,
+    StarRating,
+    Sustain,
+};
+use crate::osu::{ is_osu_chart, load_osu_beatmap, parse_osu_file };
+use crate::patterns::{ place_circles, spinner_sections };
+use crate::rating::beatmap_star_rating;
+use std::fs::{ self, File };
+use std::io::{ self, BufRead, BufReader, BufWriter, Read, Write };
+use std::path::{ Path, PathBuf };
+use macroquad::math::Vec2;
+use rand::{ Rng, SeedableRng };
+use rand_chacha::ChaCha8Rng;
+
+/// Current version of the on-disk beatmap format.
+///
+/// Version 2: beats are detected on downmixed audio instead of interleaved samples.
+/// Version 3: the estimated beat grid is stored with the map.
+/// Version 4: multi-band analysis, circles carry their band and onset strength.
+/// Version 5: circles are placed from a stored seed.
+/// Version 6: flow-aware circle patterns, one file per difficulty level.
+/// Version 7: the star rating is stored with the map.
+/// Version 8: sliders, placed at sustained low-frequency sections.
+/// Version 9: spinners, placed in long sustained or building-up gaps.
+/// Version 10: Insane maps fill short gaps on the beat grid.
+pub const BEATMAP_VERSION: u32 = 10;
+
+/// First line of every beatmap file, followed by the format version
+const BEATMAP_HEADER: &str = "yum-osu beatmap v";
+
+impl Default for BeatmapDifficulty {
+    fn default() -> Self {
+        Self {
+            approach_time: APPROACH_TIME,
+            circle_radius: CIRCLE_MAX_RADIUS,
+            overall_difficulty: 5.0,
+        }
+    }
+}
+
+impl DifficultyLevel {
+    pub const ALL: [DifficultyLevel; 4] = [
+        DifficultyLevel::Easy,
+        DifficultyLevel::Normal,
+        DifficultyLevel::Hard,
+        DifficultyLevel::Insane,
+    ];
+
+    pub fn from_name(name: &str) -> Option<DifficultyLevel> {
+        DifficultyLevel::ALL.into_iter().find(|level| format!("{:?}", level) == name)
+    }
+
+    /// The next harder or easier level, staying at the ends of the list
+    pub fn stepped(self, step: i32) -> DifficultyLevel {
+        let index = DifficultyLevel::ALL.iter()
+            .position(|&level| level == self)
+            .unwrap_or(0) as i32;
+        DifficultyLevel::ALL[(index + step).clamp(0, 3) as usize]
+    }
+
+    /// Bands whose onsets become circles
+    fn circle_bands(self) -> &'static [Band] {
+        match self {
+            DifficultyLevel::Easy | DifficultyLevel::Normal => &[Band::Kick],
+            DifficultyLevel::Hard => &[Band::Kick, Band::Snare],
+            DifficultyLevel::Insane => &[Band::Kick, Band::Snare, Band::HiHat],
+        }
+    }
+
+    /// Shortest time between two circles, closer onsets are merged into the stronger one
+    fn min_gap(self) -> f64 {
+        match self {
+            DifficultyLevel::Easy => 0.45,
+            DifficultyLevel::Normal => 0.3,
+            DifficultyLevel::Hard => 0.15,
+            DifficultyLevel::Insane => 0.09,
+        }
+    }
+
+    /// Onsets weaker than this are left out
+    fn min_strength(self) -> f32 {
+        match self {
+            DifficultyLevel::Easy => 0.3,
+            DifficultyLevel::Normal => 0.15,
+            DifficultyLevel::Hard | DifficultyLevel::Insane => 0.0,
+        }
+    }
+
+    /// Grid division the gaps between circles are filled on, when the tempo is known
+    fn fill_division(self) -> Option<SnapDivision> {
+        match self {
+            DifficultyLevel::Insane => Some(SnapDivision::Half),
+            _ => None,
+        }
+    }
+
+    /// Approach time, circle size and OD of maps at this level
+    pub fn difficulty(self) -> BeatmapDifficulty {
+        let (approach_time, circle_radius, overall_difficulty) = match self {
+            DifficultyLevel::Easy => (1.8, 110.0, 3.0),
+            DifficultyLevel::Normal => (APPROACH_TIME, CIRCLE_MAX_RADIUS, 5.0),
+            DifficultyLevel::Hard => (1.1, 85.0, 7.0),
+            DifficultyLevel::Insane => (0.8, 70.0, 8.5),
+        };
+        BeatmapDifficulty { approach_time, circle_radius, overall_difficulty }
+    }
+}
+
+/// Load the cached beatmap of a song at a difficulty level, or analyse the song with
+/// `params` and cache the maps of every level.
+///
+/// The cached map is only used when it was written by the current format version,
+/// with the same analysis parameters and for the exact same audio file. Circles
+/// are placed with `seed_override`, or with a seed derived from the audio when it is
+/// `None`, so everyone playing the same song gets the same map.
+///
+/// Fails with `LoadError::Cancelled` when the analysis was cancelled.
+pub fn load_or_generate_beatmap(
+    song_path: &str,
+    level: DifficultyLevel,
+    params: &AnalysisParams,
+    seed_override: Option<u64>,
+    progress: &AnalysisProgress
+) -> Result<Beatmap, LoadError> {
+    let audio_hash = hash_audio_file(song_path).map_err(|source| LoadError::Open {
+        path: song_path.to_string(),
+        source,
+    })?;
+    let seed = seed_override.unwrap_or(audio_hash);
+    let cache_path = beatmap_path_for(song_path, level);
+
+    if let Some(beatmap) = cached_beatmap(&cache_path, audio_hash, params) {
+        if beatmap.seed == Some(seed) {
+            println!("Loaded cached beatmap: {}", cache_path.display());
+            return Ok(beatmap);
+        }
+        // The onsets are still valid, only the placement changed
+        let beatmap = reseed_beatmap(beatmap, seed);
+        if let Err(err) = write_beatmap(&beatmap, &cache_path) {
+            println!("Failed to save beatmap {}: {}", cache_path.display(), err);
+        }
+        return Ok(beatmap);
+    }
+
+    let analysis = gather_beats(song_path, params, progress)?;
+    let mut requested = None;
+    for beatmap_level in DifficultyLevel::ALL {
+        let beatmap = generate_beatmap(song_path, audio_hash, seed, params, &analysis, beatmap_level);
+        let path = beatmap_path_for(song_path, beatmap_level);
+        if let Err(err) = write_beatmap(&beatmap, &path) {
+            println!("Failed to save beatmap {}: {}", path.display(), err);
+        }
+        if beatmap_level == level {
+            requested = Some(beatmap);
+        }
+    }
+    Ok(requested.expect("every level is generated"))
+}
+
+/// Load the beatmap a replay was recorded on without analysing anything: the imported
+/// chart, or the cached map of a generated level
+pub fn load_saved_beatmap(
+    song_path: &str,
+    level: Option<DifficultyLevel>
+) -> Result<Beatmap, LoadError> {
+    if is_osu_chart(song_path) {
+        return load_osu_beatmap(song_path);
+    }
+    let cache_path = beatmap_path_for(song_path, level.unwrap_or(DifficultyLevel::Normal));
+    read_beatmap(&cache_path).map_err(|source| LoadError::Open {
+        path: cache_path.display().to_string(),
+        source,
+    })
+}
+
+/// The beatmap cached at `cache_path`, if it was written by the current format version
+/// for the audio with `audio_hash` and analysed with `params`
+fn cached_beatmap(cache_path: &Path, audio_hash: u64, params: &AnalysisParams) -> Option<Beatmap> {
+    match read_beatmap(cache_path) {
+        Ok(beatmap) if
+            beatmap.audio_hash == audio_hash &&
+            beatmap.analysis.as_ref() == Some(params)
+        => Some(beatmap),
+        Ok(_) => {
+            println!("Cached beatmap is out of date, re-analysing: {}", cache_path.display());
+            None
+        }
+        Err(err) if err.kind() == io::ErrorKind::NotFound => None,
+        Err(err) => {
+            println!("Ignoring unreadable beatmap {}: {}", cache_path.display(), err);
+            None
+        }
+    }
+}
+
+/// Build the beatmap of one difficulty level from the analysis of a song
+fn generate_beatmap(
+    song_path: &str,
+    audio_hash: u64,
+    seed: u64,
+    params: &AnalysisParams,
+    analysis: &SongAnalysis,
+    level: DifficultyLevel
+) -> Beatmap {
+    let mut beatmap = Beatmap {
+        version: BEATMAP_VERSION,
+        metadata: BeatmapMetadata {
+            title: song_title(song_path),
+            audio_path: song_path.to_string(),
+            level: Some(level),
+            chart_path: None,
+        },
+        difficulty: level.difficulty(),
+        beat_grid: analysis.beat_grid,
+        analysis: Some(params.clone()),
+        audio_hash,
+        seed: Some(seed),
+        star_rating: None,
+        circles: circles_from_analysis(analysis, level, &mut placement_rng(seed)),
+    };
+    beatmap.star_rating = Some(beatmap_star_rating(&beatmap));
+    beatmap
+}
+
+/// Place the circles of a generated beatmap again with another seed.
+///
+/// Every slider keeps its timing, as if it had been generated from a sustain that
+/// ends with it, and every spinner stays where it is.
+fn reseed_beatmap(mut beatmap: Beatmap, seed: u64) -> Beatmap {
+    let onsets: Vec<Onset> = beatmap.circles
+        .iter()
+        .filter(|circle| circle.spinner_duration.is_none())
+        .map(|circle| Onset { time: circle.time, band: circle.band, strength: circle.strength })
+        .collect();
+    let sustains: Vec<Sustain> = beatmap.circles
+        .iter()
+        .filter_map(|circle| {
+            let slider = circle.slider.as_ref()?;
+            Some(Sustain { start: circle.time, end: circle.time + slider.duration() })
+        })
+        .collect();
+    let spinners: Vec<(f64, f64)> = beatmap.circles
+        .iter()
+        .filter_map(|circle| Some((circle.time, circle.time + circle.spinner_duration?)))
+        .collect();
+    beatmap.circles = place_circles(
+        &onsets,
+        &sustains,
+        &spinners,
+        tick_interval(beatmap.beat_grid),
+        &mut placement_rng(seed)
+    );
+    beatmap.seed = Some(seed);
+    beatmap.star_rating = Some(beatmap_star_rating(&beatmap));
+    beatmap
+}
+
+/// Random numbers for circle placement.
+///
+/// ChaCha gives the same sequence for a seed on every platform and `rand` version,
+/// unlike `StdRng`, so a seed always describes the same map.
+pub fn placement_rng(seed: u64) -> ChaCha8Rng {
+    ChaCha8Rng::seed_from_u64(seed)
+}
+
+/// Place a circle for the onsets a difficulty level keeps, a slider for those that
+/// start a sustain and a spinner in long gaps between them over a sustain or build-up.
+///
+/// Easier levels only keep strong kicks far enough apart, harder ones add the
+/// snare and hi-hat onsets and allow them closer together.
+pub fn circles_from_analysis(
+    analysis: &SongAnalysis,
+    level: DifficultyLevel,
+    rng: &mut impl Rng
+) -> Vec<BeatmapCircle> {
+    let strong: Vec<Onset> = analysis.onsets
+        .iter()
+        .filter(|onset| onset.strength >= level.min_strength())
+        .copied()
+        .collect();
+    let mut onsets = select_onsets(&strong, level.circle_bands(), level.min_gap());
+    if let (Some(grid), Some(division)) = (analysis.beat_grid, level.fill_division()) {
+        onsets = fill_gaps(&onsets, &grid, division, &analysis.sustains, level.min_gap());
+    }
+    let spinners = spinner_sections(&onsets, &analysis.onsets, &analysis.sustains);
+    place_circles(
+        &onsets,
+        &analysis.sustains,
+        &spinners,
+        tick_interval(analysis.beat_grid),
+        rng
+    )
+}
+
+/// Add onsets on the divisions of the beat grid between onsets at most `MAX_FILL_GAP`
+/// beats apart, keeping `min_gap` to their neighbours.
+///
+/// Longer gaps are breaks in the music and sustains become sliders, so both are left
+/// as they are. An added onset takes the band of the onset before it and half the
+/// strength of the weaker neighbour, so it is placed like a light stream note.
+fn fill_gaps(
+    onsets: &[Onset],
+    grid: &BeatGrid,
+    division: SnapDivision,
+    sustains: &[Sustain],
+    min_gap: f64
+) -> Vec<Onset> {
+    let step = grid.beat_length() / (division.divisions_per_beat() as f64);
+    let mut filled: Vec<Onset> = Vec::with_capacity(onsets.len() * 2);
+    for pair in onsets.windows(2) {
+        let (before, after) = (pair[0], pair[1]);
+        filled.push(before);
+        if after.time - before.time > MAX_FILL_GAP * grid.beat_length() {
+            continue;
+        }
+
+        let first = ((before.time - grid.offset) / step).floor() + 1.0;
+        let mut time = grid.offset + first * step;
+        let mut previous = before.time;
+        while after.time - time > min_gap {
+            let sustained = sustains
+                .iter()
+                .any(|sustain| time >= sustain.start && time <= sustain.end);
+            if time - previous > min_gap && !sustained {
+                filled.push(Onset {
+                    time,
+                    band: before.band,
+                    strength: before.strength.min(after.strength) * 0.5,
+                });
+                previous = time;
+            }
+            time += step;
+        }
+    }
+    filled.extend(onsets.last());
+    filled
+}
+
+/// Seconds between slider ticks: one per beat, when the tempo is known
+fn tick_interval(beat_grid: Option<BeatGrid>) -> f64 {
+    beat_grid.map_or(DEFAULT_TICK_INTERVAL, |grid| grid.beat_length())
+}
+
+/// A song of the selection list, with the tempo and star ratings known without
+/// analysing it: those of an imported chart or of the cached maps of each level
+pub fn song_entry(song_path: &str) -> SongEntry {
+    let beatmaps: Vec<Beatmap> = if is_osu_chart(song_path) {
+        parse_osu_file(Path::new(song_path))
+            .map(|chart| chart.beatmap)
+            .into_iter()
+            .collect()
+    } else {
+        DifficultyLevel::ALL.into_iter()
+            .filter_map(|level| read_beatmap(&beatmap_path_for(song_path, level)).ok())
+            .collect()
+    };
+
+    SongEntry {
+        path: song_path.to_string(),
+        bpm: beatmaps
+            .iter()
+            .find_map(|beatmap| beatmap.beat_grid)
+            .map(|grid| grid.bpm),
+        star_ratings: beatmaps
+            .iter()
+            .filter_map(|beatmap| {
+                beatmap.star_rating.map(|rating| (beatmap.metadata.level, rating.stars))
+            })
+            .collect(),
+    }
+}
+
+impl SongEntry {
+    /// Stars of the map played at a difficulty level, if known
+    pub fn stars(&self, level: DifficultyLevel) -> Option<f64> {
+        self.star_ratings
+            .iter()
+            .find(|(rated_level, _)| rated_level.is_none_or(|rated| rated == level))
+            .map(|&(_, stars)| stars)
+    }
+}
+
+const FNV_OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+
+/// Fold bytes into a 64-bit FNV-1a hash
+fn fnv1a(mut hash: u64, bytes: &[u8]) -> u64 {
+    for &byte in bytes {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    hash
+}
+
+/// Hash the raw bytes of an audio file (64-bit FNV-1a, stable across builds)
+pub fn hash_audio_file(path: &str) -> io::Result<u64> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut hash = FNV_OFFSET_BASIS;
+    let mut buffer = [0u8; 8192];
+
+    loop {
+        let read = reader.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        hash = fnv1a(hash, &buffer[..read]);
+    }
+
+    Ok(hash)
+}
+
+/// Hash the audio, circles, sliders and spinners of a beatmap, so a replay can be matched to
+/// the exact map
+pub fn hash_beatmap(beatmap: &Beatmap) -> u64 {
+    let mut hash = fnv1a(FNV_OFFSET_BASIS, &beatmap.audio_hash.to_le_bytes());
+    for circle in &beatmap.circles {
+        hash = fnv1a(hash, &circle.time.to_le_bytes());
+        hash = fnv1a(hash, &circle.x.to_le_bytes());
+        hash = fnv1a(hash, &circle.y.to_le_bytes());
+        if let Some(slider) = &circle.slider {
+            hash = fnv1a(hash, format!("{:?}", slider.curve).as_bytes());
+            for point in &slider.control_points {
+                hash = fnv1a(hash, &point.x.to_le_bytes());
+                hash = fnv1a(hash, &point.y.to_le_bytes());
+            }
+            hash = fnv1a(hash, &slider.length.to_le_bytes());
+            hash = fnv1a(hash, &slider.spans.to_le_bytes());
+            hash = fnv1a(hash, &slider.span_duration.to_le_bytes());
+            hash = fnv1a(hash, &slider.tick_interval.to_le_bytes());
+        }
+        if let Some(duration) = circle.spinner_duration {
+            hash = fnv1a(hash, &duration.to_le_bytes());
+        }
+    }
+    hash
+}
+
+/// Location of the cached beatmap for a song at a difficulty level.
+///
+/// The name carries a hash of the song's full path, so songs with the same file name
+/// in different folders keep their own maps.
+pub fn beatmap_path_for(song_path: &str, level: DifficultyLevel) -> PathBuf {
+    let path_hash = fnv1a(FNV_OFFSET_BASIS, song_path.as_bytes());
+    Path::new(BEATMAP_DIR).join(
+        format!("{} {:016x} [{:?}].yosu", song_title(song_path), path_hash, level)
+    )
+}
+
+/// The song's file name without its extension
+fn song_title(song_path: &str) -> String {
+    Path::new(song_path)
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().to_string())
+        .unwrap_or_else(|| song_path.to_string())
+}
+
+/// Write a beatmap to disk in the versioned text format
+pub fn write_beatmap(beatmap: &Beatmap, path: &Path) -> io::Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut out = BufWriter::new(File::create(path)?);
+
+    writeln!(out, "{}{}", BEATMAP_HEADER, beatmap.version)?;
+
+    writeln!(out, "\n[Metadata]")?;
+    writeln!(out, "Title: {}", beatmap.metadata.title)?;
+    writeln!(out, "AudioPath: {}", beatmap.metadata.audio_path)?;
+    if let Some(level) = beatmap.metadata.level {
+        writeln!(out, "Level: {:?}", level)?;
+    }
+    writeln!(out, "AudioHash: {:016x}", beatmap.audio_hash)?;
+    if let Some(seed) = beatmap.seed {
+        writeln!(out, "Seed: {:016x}", seed)?;
+    }
+
+    let difficulty = &beatmap.difficulty;
+    writeln!(out, "\n[Difficulty]")?;
+    writeln!(out, "ApproachTime: {}", difficulty.approach_time)?;
+    writeln!(out, "CircleRadius: {}", difficulty.circle_radius)?;
+    writeln!(out, "OverallDifficulty: {}", difficulty.overall_difficulty)?;
+    if let Some(rating) = &beatmap.star_rating {
+        writeln!(out, "StarRating: {},{},{}", rating.aim, rating.speed, rating.stars)?;
+    }
+
+    if let Some(grid) = &beatmap.beat_grid {
+        writeln!(out, "\n[Timing]")?;
+        writeln!(out, "BPM: {}", grid.bpm)?;
+        writeln!(out, "Offset: {}", grid.offset)?;
+    }
+
+    if let Some(analysis) = &beatmap.analysis {
+        writeln!(out, "\n[Analysis]")?;
+        writeln!(out, "ChannelMode: {:?}", analysis.channel_mode)?;
+        writeln!(out, "BufferSize: {}", analysis.buffer_size)?;
+        writeln!(out, "HopSize: {}", analysis.hop_size)?;
+        writeln!(out, "Silence: {}", analysis.silence)?;
+        writeln!(out, "MinBeatGap: {}", analysis.min_beat_gap)?;
+        writeln!(out, "MinStrength: {}", analysis.min_strength)?;
+        writeln!(out, "SustainLevel: {}", analysis.sustain_level)?;
+        writeln!(out, "MinSustain: {}", analysis.min_sustain)?;
+        match analysis.snap_division {
+            Some(division) => writeln!(out, "SnapDivision: {:?}", division)?,
+            None => writeln!(out, "SnapDivision: None")?,
+        }
+        // One line per band: name, centre frequency, Q factor and onset threshold
+        for band in &analysis.bands {
+            writeln!(
+                out,
+                "Band: {:?},{},{},{}",
+                band.band,
+                band.center_freq,
+                band.q_factor,
+                band.threshold
+            )?;
+        }
+    }
+
+    // One circle per line: time in seconds, normalised x and y position, band and strength.
+    // Sliders add their curve, control points, length, spans, span duration and tick interval,
+    // spinners the word Spinner and their duration.
+    writeln!(out, "\n[Circles]")?;
+    for circle in &beatmap.circles {
+        write!(
+            out,
+            "{},{},{},{:?},{}",
+            circle.time,
+            circle.x,
+            circle.y,
+            circle.band,
+            circle.strength
+        )?;
+        if let Some(slider) = &circle.slider {
+            let points: Vec<String> = slider.control_points
+                .iter()
+                .map(|point| format!("{}:{}", point.x, point.y))
+                .collect();
+            write!(
+                out,
+                ",{:?},{},{},{},{},{}",
+                slider.curve,
+                points.join("|"),
+                slider.length,
+                slider.spans,
+                slider.span_duration,
+                slider.tick_interval
+            )?;
+        }
+        if let Some(duration) = circle.spinner_duration {
+            write!(out, ",Spinner,{}", duration)?;
+        }
+        writeln!(out)?;
+    }
+
+    out.flush()
+}
+
+/// Read a beatmap written by `write_beatmap`.
+///
+/// Files from a different format version are rejected so they get regenerated.
+pub fn read_beatmap(path: &Path) -> io::Result<Beatmap> {
+    let mut lines = BufReader::new(File::open(path)?).lines();
+
+    let header = lines.next().transpose()?.unwrap_or_default();
+    let version: u32 = header
+        .trim()
+        .strip_prefix(BEATMAP_HEADER)
+        .and_then(|version| version.parse().ok())
+        .ok_or_else(|| invalid_data("missing beatmap header"))?;
+    if version != BEATMAP_VERSION {
+        return Err(invalid_data(&format!("unsupported beatmap version {}", version)));
+    }
+
+    let mut beatmap = Beatmap {
+        version,
+        metadata: BeatmapMetadata {
+            title: String::new(),
+            audio_path: String::new(),
+            level: None,
+            chart_path: None,
+        },
+        difficulty: BeatmapDifficulty::default(),
+        beat_grid: None,
+        analysis: None,
+        audio_hash: 0,
+        seed: None,
+        star_rating: None,
+        circles: Vec::new(),
+    };
+    let mut section = String::new();
+    let mut bands: Vec<BandParams> = Vec::new();
+
+    for line in lines {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        if line.starts_with('[') && line.ends_with(']') {
+            section = line[1..line.len() - 1].to_string();
+            continue;
+        }
+
+        if section == "Circles" {
+            beatmap.circles.push(parse_circle(line)?);
+            continue;
+        }
+
+        let (key, value) = line
+            .split_once(':')
+            .ok_or_else(|| invalid_data(&format!("malformed line: {}", line)))?;
+        let value = value.trim();
+
+        if section == "Analysis" {
+            let analysis = beatmap.analysis.get_or_insert_with(AnalysisParams::default);
+            match key.trim() {
+                "ChannelMode" => {
+                    analysis.channel_mode = match value {
+                        "Downmix" => ChannelMode::Downmix,
+                        "PerChannel" => ChannelMode::PerChannel,
+                        _ => {
+                            return Err(invalid_data(&format!("unknown channel mode: {}", value)));
+                        }
+                    };
+                }
+                "BufferSize" => {
+                    analysis.buffer_size = parse_value(value)?;
+                }
+                "HopSize" => {
+                    analysis.hop_size = parse_value(value)?;
+                }
+                "Silence" => {
+                    analysis.silence = parse_value(value)?;
+                }
+                "MinBeatGap" => {
+                    analysis.min_beat_gap = parse_value(value)?;
+                }
+                "Band" => {
+                    let fields: Vec<&str> = value.split(',').map(str::trim).collect();
+                    if fields.len() != 4 {
+                        return Err(invalid_data(&format!("malformed band: {}", value)));
+                    }
+                    bands.push(BandParams {
+                        band: parse_band(fields[0])?,
+                        center_freq: parse_value(fields[1])?,
+                        q_factor: parse_value(fields[2])?,
+                        threshold: parse_value(fields[3])?,
+                    });
+                }
+                "MinStrength" => {
+                    analysis.min_strength = parse_value(value)?;
+                }
+                "SustainLevel" => {
+                    analysis.sustain_level = parse_value(value)?;
+                }
+                "MinSustain" => {
+                    analysis.min_sustain = parse_value(value)?;
+                }
+                "SnapDivision" => {
+                    analysis.snap_division = match value {
+                        "None" => None,
+                        "Whole" => Some(SnapDivision::Whole),
+                        "Half" => Some(SnapDivision::Half),
+                        "Third" => Some(SnapDivision::Third),
+                        "Quarter" => Some(SnapDivision::Quarter),
+                        _ => {
+                            return Err(invalid_data(&format!("unknown snap division: {}", value)));
+                        }
+                    };
+                }
+                _ => {} // Unknown keys are ignored
+            }
+            continue;
+        }
+
+        match (section.as_str(), key.trim()) {
+            ("Metadata", "Title") => {
+                beatmap.metadata.title = value.to_string();
+            }
+            ("Metadata", "AudioPath") => {
+                beatmap.metadata.audio_path = value.to_string();
+            }
+            ("Metadata", "Level") => {
+                beatmap.metadata.level = Some(
+                    DifficultyLevel::from_name(value).ok_or_else(||
+                        invalid_data(&format!("unknown difficulty level: {}", value))
+                    )?
+                );
+            }
+            ("Metadata", "AudioHash") => {
+                beatmap.audio_hash = u64
+                    ::from_str_radix(value, 16)
+                    .map_err(|_| invalid_data("invalid audio hash"))?;
+            }
+            ("Metadata", "Seed") => {
+                beatmap.seed = Some(
+                    u64::from_str_radix(value, 16).map_err(|_| invalid_data("invalid seed"))?
+                );
+            }
+            ("Timing", "BPM") => {
+                beatmap.beat_grid.get_or_insert(BeatGrid { bpm: 0.0, offset: 0.0 }).bpm =
+                    parse_value(value)?;
+            }
+            ("Timing", "Offset") => {
+                beatmap.beat_grid.get_or_insert(BeatGrid { bpm: 0.0, offset: 0.0 }).offset =
+                    parse_value(value)?;
+            }
+            ("Difficulty", "ApproachTime") => {
+                beatmap.difficulty.approach_time = parse_value(value)?;
+            }
+            ("Difficulty", "CircleRadius") => {
+                beatmap.difficulty.circle_radius = parse_value(value)?;
+            }
+            ("Difficulty", "OverallDifficulty") => {
+                beatmap.difficulty.overall_difficulty = parse_value(value)?;
+            }
+            ("Difficulty", "StarRating") => {
+                let fields: Vec<&str> = value.split(',').map(str::trim).collect();
+                if fields.len() != 3 {
+                    return Err(invalid_data(&format!("malformed star rating: {}", value)));
+                }
+                beatmap.star_rating = Some(StarRating {
+                    aim: parse_value(fields[0])?,
+                    speed: parse_value(fields[1])?,
+                    stars: parse_value(fields[2])?,
+                });
+            }
+            _ => {} // Unknown keys are ignored
+        }
+    }
+
+    if let Some(analysis) = &mut beatmap.analysis {
+        analysis.bands = bands;
+    }
+
+    Ok(beatmap)
+}
+
+/// Parse a `time,x,y,band,strength` circle line, followed for sliders by
+/// `curve,x:y|x:y,length,spans,span_duration,tick_interval` and for spinners by
+/// `Spinner,duration`
+fn parse_circle(line: &str) -> io::Result<BeatmapCircle> {
+    let mut fields = line.split(',').map(str::trim);
+    let mut next = || fields.next().ok_or_else(|| invalid_data("missing circle field"));
+
+    let mut circle = BeatmapCircle {
+        time: parse_value(next()?)?,
+        x: parse_value(next()?)?,
+        y: parse_value(next()?)?,
+        band: parse_band(next()?)?,
+        strength: parse_value(next()?)?,
+        slider: None,
+        spinner_duration: None,
+    };
+    if let Ok(curve) = next() {
+        if curve == "Spinner" {
+            circle.spinner_duration = Some(parse_value(next()?)?);
+            return Ok(circle);
+        }
+        circle.slider = Some(SliderPath {
+            curve: CurveType::from_name(curve).ok_or_else(||
+                invalid_data(&format!("unknown slider curve: {}", curve))
+            )?,
+            control_points: next()?
+                .split('|')
+                .map(|point| {
+                    let (x, y) = point.split_once(':').ok_or_else(||
+                        invalid_data(&format!("malformed slider point: {}", point))
+                    )?;
+                    Ok(Vec2::new(parse_value(x)?, parse_value(y)?))
+                })
+                .collect::<io::Result<_>>()?,
+            length: parse_value(next()?)?,
+            spans: parse_value(next()?)?,
+            span_duration: parse_value(next()?)?,
+            tick_interval: parse_value(next()?)?,
+        });
+    }
+    Ok(circle)
+}
+
+fn parse_band(name: &str) -> io::Result<Band> {
+    Band::from_name(name).ok_or_else(|| invalid_data(&format!("unknown band: {}", name)))
+}
+
+pub fn parse_value<T: std::str::FromStr>(value: &str) -> io::Result<T> {
+    value.parse().map_err(|_| invalid_data(&format!("invalid value: {}", value)))
+}
+
+pub fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Onsets every quarter second, pausing during the sustains
+    fn onsets() -> Vec<Onset> {
+        (0..32)
+            .map(|i| Onset { time: 1.0 + (i as f64) * 0.25, band: Band::Kick, strength: 0.8 })
+            .filter(|onset| {
+                sustains()
+                    .iter()
+                    .all(|sustain| onset.time <= sustain.start || onset.time >= sustain.end)
+            })
+            .collect()
+    }
+
+    fn sustains() -> Vec<Sustain> {
+        vec![Sustain { start: 2.0, end: 3.0 }, Sustain { start: 5.0, end: 7.0 }]
+    }
+
+    fn generated_beatmap(seed: u64) -> Beatmap {
+        let mut beatmap = Beatmap {
+            version: BEATMAP_VERSION,
+            metadata: BeatmapMetadata {
+                title: "Seeded".to_string(),
+                audio_path: "src/assets/music/seeded.mp3".to_string(),
+                level: Some(DifficultyLevel::Hard),
+                chart_path: None,
+            },
+            difficulty: DifficultyLevel::Hard.difficulty(),
+            beat_grid: None,
+            analysis: Some(AnalysisParams::default()),
+            audio_hash: 0xfeed_beef,
+            seed: Some(seed),
+            star_rating: None,
+            circles: place_circles(
+                &onsets(),
+                &sustains(),
+                &[(9.5, 11.5)],
+                tick_interval(None),
+                &mut placement_rng(seed)
+            ),
+        };
+        beatmap.star_rating = Some(beatmap_star_rating(&beatmap));
+        beatmap
+    }
+
+    #[test]
+    fn a_seed_always_places_the_same_circles() {
+        let first = generated_beatmap(42);
+        let second = generated_beatmap(42);
+        let other = generated_beatmap(43);
+
+        assert_eq!(hash_beatmap(&first), hash_beatmap(&second));
+        assert_ne!(hash_beatmap(&first), hash_beatmap(&other));
+        assert_eq!(hash_beatmap(&reseed_beatmap(other, 42)), hash_beatmap(&first));
+    }
+
+    #[test]
+    fn the_seed_is_stored_with_the_beatmap() {
+        let beatmap = generated_beatmap(u64::MAX - 1);
+        let path = std::env::temp_dir().join(format!("yum-osu-seed-{}.yosu", std::process::id()));
+        write_beatmap(&beatmap, &path).unwrap();
+        let read = read_beatmap(&path);
+        fs::remove_file(&path).unwrap();
+        let read = read.unwrap();
+
+        assert_eq!(read.seed, beatmap.seed);
+        assert_eq!(read.metadata.level, Some(DifficultyLevel::Hard));
+        assert_eq!(read.star_rating, beatmap.star_rating);
+        assert_eq!(hash_beatmap(&read), hash_beatmap(&beatmap));
+
+        let sliders = |beatmap: &Beatmap| -> Vec<SliderPath> {
+            beatmap.circles
+                .iter()
+                .filter_map(|circle| circle.slider.clone())
+                .collect()
+        };
+        assert_eq!(sliders(&beatmap).len(), 2);
+        assert_eq!(sliders(&read), sliders(&beatmap));
+        let spinners: Vec<Option<f64>> = read.circles
+            .iter()
+            .map(|circle| circle.spinner_duration)
+            .filter(Option::is_some)
+            .collect();
+        assert_eq!(spinners, vec![Some(2.0)]);
+    }
+
+    #[test]
+    fn songs_with_the_same_name_have_their_own_cache() {
+        let first = beatmap_path_for("music/a/song.mp3", DifficultyLevel::Hard);
+        let second = beatmap_path_for("music/b/song.mp3", DifficultyLevel::Hard);
+
+        assert_ne!(first, second);
+        assert_eq!(first, beatmap_path_for("music/a/song.mp3", DifficultyLevel::Hard));
+        assert_ne!(first, beatmap_path_for("music/a/song.mp3", DifficultyLevel::Easy));
+    }
+
+    #[test]
+    fn outdated_cached_beatmaps_are_analysed_again() {
+        let beatmap = generated_beatmap(7);
+        let params = AnalysisParams::default();
+        let path = std::env::temp_dir().join(format!("yum-osu-cache-{}.yosu", std::process::id()));
+        write_beatmap(&beatmap, &path).unwrap();
+
+        let up_to_date = cached_beatmap(&path, beatmap.audio_hash, &params).is_some();
+        let changed_audio = cached_beatmap(&path, beatmap.audio_hash + 1, &params).is_some();
+        let changed_params = cached_beatmap(
+            &path,
+            beatmap.audio_hash,
+            &(AnalysisParams { min_beat_gap: params.min_beat_gap * 2.0, ..params.clone() })
+        ).is_some();
+
+        // A map written by an older format version
+        let contents = fs::read_to_string(&path).unwrap().replacen(
+            &format!("{}{}", BEATMAP_HEADER, BEATMAP_VERSION),
+            &format!("{}{}", BEATMAP_HEADER, BEATMAP_VERSION - 1),
+            1
+        );
+        fs::write(&path, contents).unwrap();
+        let changed_version = cached_beatmap(&path, beatmap.audio_hash, &params).is_some();
+        fs::remove_file(&path).unwrap();
+
+        assert!(up_to_date);
+        assert!(!changed_audio);
+        assert!(!changed_params);
+        assert!(!changed_version);
+    }
+
+    #[test]
+    fn harder_levels_keep_more_onsets() {
+        // Kicks on the beat, snares on the off-beats and hi-hats in between
+        let onsets: Vec<Onset> = (0..64)
+            .map(|i| {
+                let band = [Band::Kick, Band::HiHat, Band::Snare, Band::HiHat][i % 4];
+                let strength = if i % 8 == 0 { 1.0 } else { 0.2 };
+                Onset { time: 1.0 + (i as f64) * 0.125, band, strength }
+            })
+            .collect();
+        let analysis = SongAnalysis { onsets, sustains: Vec::new(), beat_grid: None };
+
+        let counts: Vec<usize> = DifficultyLevel::ALL.iter()
+            .map(|&level| {
+                let circles = circles_from_analysis(&analysis, level, &mut placement_rng(3));
+                for pair in circles.windows(2) {
+                    assert!(pair[1].time - pair[0].time > level.min_gap(), "{:?}", level);
+                }
+                circles.len()
+            })
+            .collect();
+
+        assert_eq!(counts, vec![8, 16, 32, 64]);
+    }
+
+    #[test]
+    fn insane_maps_fill_short_gaps_on_the_beat_grid() {
+        // Kicks on every beat at 120 BPM, then a break of four beats
+        let onsets: Vec<Onset> = [1.0, 1.5, 2.0, 2.5, 4.5, 5.0]
+            .map(|time| Onset { time, band: Band::Kick, strength: 0.8 })
+            .to_vec();
+        let grid = BeatGrid { bpm: 120.0, offset: 1.0 };
+        let analysis = SongAnalysis { onsets, sustains: Vec::new(), beat_grid: Some(grid) };
+
+        let times = |level: DifficultyLevel| -> Vec<f64> {
+            circles_from_analysis(&analysis, level, &mut placement_rng(3))
+                .iter()
+                .map(|circle| circle.time)
+                .collect()
+        };
+
+        assert_eq!(times(DifficultyLevel::Hard), vec![1.0, 1.5, 2.0, 2.5, 4.5, 5.0]);
+        assert_eq!(
+            times(DifficultyLevel::Insane),
+            vec![1.0, 1.25, 1.5, 1.75, 2.0, 2.25, 2.5, 4.5, 4.75, 5.0]
+        );
+    }
+}
@@ -0,0 +1,108 @@
+use crate::constants::*;
+use crate::structs::{ CalibrationResult, CalibrationState, PlaybackClock };
+use rodio::buffer::SamplesBuffer;
+use rodio::Sink;
+
+// Shape of a metronome click
+const CLICK_FREQUENCY: f32 = 1_500.0;
+const CLICK_LENGTH: f64 = 0.03;
+const CLICK_DECAY: f32 = 150.0;
+
+/// Start the metronome and return the calibration that listens to the player's taps
+pub fn start_calibration(sink: &Sink) -> CalibrationState {
+    let beat_length = 60.0 / CALIBRATION_BPM;
+    let beat_times: Vec<f64> = (0..CALIBRATION_BEATS)
+        .map(|beat| CALIBRATION_START + (beat as f64) * beat_length)
+        .collect();
+    let duration = CALIBRATION_START + (CALIBRATION_BEATS as f64) * beat_length;
+
+    let samples = click_track(&beat_times, duration, CALIBRATION_SAMPLE_RATE);
+    let (source, clock) = PlaybackClock::for_source(
+        SamplesBuffer::new(1, CALIBRATION_SAMPLE_RATE, samples)
+    );
+    sink.append(source);
+    sink.play();
+
+    CalibrationState {
+        clock,
+        beat_times,
+        taps: Vec::new(),
+        finished: false,
+        result: None,
+    }
+}
+
+/// Generate mono samples with a short decaying click at every beat time
+pub fn click_track(beat_times: &[f64], duration: f64, sample_rate: u32) -> Vec<f32> {
+    let mut samples = vec![0.0; (duration * (sample_rate as f64)) as usize];
+    let click_samples = (CLICK_LENGTH * (sample_rate as f64)) as usize;
+
+    for &time in beat_times {
+        let start = (time * (sample_rate as f64)) as usize;
+        for (i, sample) in samples.iter_mut().skip(start).take(click_samples).enumerate() {
+            let t = (i as f32) / (sample_rate as f32);
+            *sample = (std::f32::consts::TAU * CLICK_FREQUENCY * t).sin() * (-CLICK_DECAY * t).exp();
+        }
+    }
+    samples
+}
+
+/// Compare the taps with the clicks they were meant for.
+///
+/// Each tap is matched with its nearest click. Taps during the lead-in and taps
+/// more than a quarter beat away from any click are ignored.
+pub fn calibrate(beat_times: &[f64], taps: &[f64], beat_length: f64) -> Option<CalibrationResult> {
+    let counted_beats = beat_times.get(CALIBRATION_LEAD_IN..)?;
+    let errors: Vec<f64> = taps
+        .iter()
+        .filter_map(|&tap| {
+            counted_beats
+                .iter()
+                .map(|&beat| tap - beat)
+                .min_by(|a, b| a.abs().total_cmp(&b.abs()))
+        })
+        .filter(|error| error.abs() < beat_length / 4.0)
+        .collect();
+
+    if errors.is_empty() {
+        return None;
+    }
+
+    let count = errors.len() as f64;
+    let offset = errors.iter().sum::<f64>() / count;
+    let variance = errors.iter().map(|error| (error - offset).powi(2)).sum::<f64>() / count;
+
+    Some(CalibrationResult {
+        offset,
+        jitter: variance.sqrt(),
+        taps: errors.len(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn late_taps_give_a_positive_offset() {
+        let beat_times: Vec<f64> = (0..12).map(|beat| 1.0 + (beat as f64) * 0.5).collect();
+        // Alternate 20ms and 40ms late, plus a stray tap and taps during the lead-in
+        let mut taps: Vec<f64> = beat_times
+            .iter()
+            .enumerate()
+            .map(|(i, beat)| beat + (if i % 2 == 0 { 0.02 } else { 0.04 }))
+            .collect();
+        taps.push(3.25);
+
+        let result = calibrate(&beat_times, &taps, 0.5).unwrap();
+
+        assert_eq!(result.taps, 12 - CALIBRATION_LEAD_IN);
+        assert!((result.offset - 0.03).abs() < 1e-9, "offset: {}", result.offset);
+        assert!((result.jitter - 0.01).abs() < 1e-9, "jitter: {}", result.jitter);
+    }
+
+    #[test]
+    fn no_taps_give_no_result() {
+        assert_eq!(calibrate(&[1.0, 1.5, 2.0, 2.5, 3.0], &[], 0.5), None);
+    }
+}
@@ -2,11 +2,16 @@
 
 use macroquad::prelude::*;
 
-// Timing and shrink behavior
-pub const SHRINK_TIME: f64 = 1.5; // Time it takes for a circle to shrink
-pub const CIRCLE_MAX_RADIUS: f32 = 100.0; // Maximum radius of circles
+// Approach time and circle size of Normal maps
+pub const APPROACH_TIME: f64 = 1.5; // Seconds a circle is shown before its hit time
+pub const CIRCLE_MAX_RADIUS: f32 = 100.0; // Radius of the hit circles
 pub const OUTLINE_THICKNESS: f32 = 2.0; // Thickness of the circle outline
 
+// Approach circles
+pub const APPROACH_CIRCLE_SCALE: f32 = 4.0; // Approach circle size when it appears, in hit radii
+pub const FADE_IN_TIME: f64 = 0.4; // Seconds a circle takes to fade in at normal approach rates
+pub const FULL_FADE_IN_APPROACH: f64 = 0.45; // Shorter approach times fade in faster
+
 // Score display styling
 pub const SCORE_FONT_SIZE: f32 = 40.0; // Size of the score font
 
@@ -17,11 +22,102 @@ pub const DARK_BACKGROUND: Color = Color::new(0.05, 0.05, 0.1, 1.0); // Dark bac
 // Positioning for score display
 pub const DRAW_SCORE_X: f32 = 20.0; // X position for score
 pub const DRAW_SCORE_Y: f32 = 40.0; // Y position for score
+pub const STATS_FONT_SIZE: f32 = 24.0; // Size of the combo, accuracy and judgement text
+pub const STATS_LINE_HEIGHT: f32 = 28.0; // Spacing between the lines below the score
+
+// Hit error histogram on the results screen
+pub const HIT_ERROR_RANGE: f64 = 0.3; // Errors beyond this many seconds land in the outermost bins
+pub const HIT_ERROR_BINS: usize = 31; // Odd so that a perfect hit has its own centre bin
+
+// Generated circle patterns, distances in playfield radii
+pub const DISTANCE_SNAP: f32 = 1.4; // Distance between circles per second between their beats
+pub const JUMP_SCALE_MIN: f32 = 0.5; // Spacing multiplier of the weakest onsets
+pub const JUMP_SCALE_MAX: f32 = 1.5; // Spacing multiplier of the strongest onsets
+pub const MIN_SPACING: f32 = 0.12;
+pub const MAX_SPACING: f32 = 1.4;
+pub const STREAM_GAP: f64 = 0.2; // Beats closer than this are mapped as streams
+pub const MAX_FILL_GAP: f64 = 2.0; // Longest gap in beats that Insane fills on the beat grid
+pub const OVERLAP_HISTORY: usize = 4; // How many previous circles a new circle must not overlap
+pub const OVERLAP_DISTANCE: f32 = 0.5; // Centres closer than this overlap
+pub const OVERLAP_MIN_GAP: f64 = 0.4; // Circles this close in time may overlap, like within a stream
+pub const PLAYFIELD_LIMIT: f32 = 0.95; // Circles stay this far from the playfield centre
+
+// Generated sliders, distances in playfield radii
+pub const SLIDER_MIN_DURATION: f64 = 0.3; // Shorter sustains stay circles
+pub const SLIDER_END_GAP: f64 = 0.15; // Time left between the end of a slider and the next beat
+pub const SLIDER_VELOCITY: f32 = 1.2; // Distance the ball travels per second
+pub const SLIDER_MAX_LENGTH: f32 = 0.8;
+pub const SLIDER_MAX_SPAN: f64 = 0.8; // Longer slides turn back and forth
+pub const DEFAULT_TICK_INTERVAL: f64 = 0.5; // Between slider ticks when the tempo is unknown
+
+// Generated spinners
+pub const SPINNER_MIN_DURATION: f64 = 1.5;
+pub const SPINNER_LEAD_IN: f64 = 0.5; // Time between the beat before a spinner and its start
+pub const SPINNER_RECOVERY: f64 = 0.75; // Time between the end of a spinner and the next beat
+pub const SPINNER_MIN_SUSTAIN: f64 = 0.5; // Share of a gap that must be sustained for a spinner
+
+// Slider play
+pub const SLIDER_FOLLOW_SCALE: f32 = 2.4; // Follow circle radius relative to the circle radius
+pub const SLIDER_BODY_SCALE: f32 = 0.5; // Ball and body radius relative to the circle radius
+pub const SLIDER_END_LENIENCY: f64 = 0.036; // The end is judged this many seconds early
+
+// Spinner play
+pub const SPINNER_MAX_RPS: f64 = 8.0; // Faster spinning is not counted
+pub const SPINNER_RADIUS: f32 = 150.0; // Radius of the spinner ring in pixels
+pub const AUTO_SPIN_RPS: f64 = 6.0; // How fast the autoplay bot spins
+pub const AUTO_SPIN_RADIUS: f32 = 50.0; // Distance from the centre the autoplay bot spins at
+
+// Hit windows
+pub const TOO_EARLY_WINDOW: f64 = 0.4; // Presses this early before a hit time are misses
+
+// Autoplay
+pub const AUTO_LEAD: f64 = 0.008; // Seconds before a circle's hit time the bot presses
+
+// Scoring
+pub const COMBO_SCORE_DIVISOR: u64 = 25; // Every 25 combo adds the base hit value once more
+pub const SLIDER_TICK_SCORE: u64 = 10;
+pub const SLIDER_EDGE_SCORE: u64 = 30; // Slider heads, repeats and ends
+pub const SPINNER_SPIN_SCORE: u64 = 100; // Every rotation up to the required ones
+pub const SPINNER_BONUS_SCORE: u64 = 1000; // Every rotation beyond them
 
 // Song selection and entry heights
 pub const SONG_ENTRY_HEIGHT: f32 = 40.0; // Height of each song entry
 pub const FONT_SIZE: u16 = 30; // General font size for text
 
+// Where generated beatmaps are cached
+pub const BEATMAP_DIR: &str = "src/assets/beatmaps/";
+
+// Where .osu charts and .osz archives are imported from
+pub const OSU_MAPS_DIR: &str = "src/assets/maps/";
+
+// Where replays of finished plays are saved
+pub const REPLAY_DIR: &str = "src/assets/replays/";
+
+// Settings
+pub const DEFAULT_VOLUME: f32 = 1.0;
+pub const DEFAULT_HIT_KEYS: [&str; 2] = ["A", "S"];
+pub const DEFAULT_WINDOW_WIDTH: i32 = 800;
+pub const DEFAULT_WINDOW_HEIGHT: i32 = 600;
+pub const RESOLUTIONS: [(i32, i32); 5] = [
+    (800, 600),
+    (1024, 768),
+    (1280, 720),
+    (1600, 900),
+    (1920, 1080),
+];
+pub const SETTINGS_DIR_NAME: &str = "yum-osu"; // Folder inside the user config directory
+pub const SETTINGS_FILE_NAME: &str = "settings.toml";
+pub const PROFILE_FILE_NAME: &str = "profile.toml";
+pub const PROFILE_BEST_PLAYS: usize = 100; // Best plays kept in the profile
+pub const PROFILE_WEIGHT_DECAY: f64 = 0.95; // Weight of each next best play in the total pp
+
+// Offset calibration metronome
+pub const CALIBRATION_BPM: f64 = 100.0;
+pub const CALIBRATION_BEATS: usize = 32;
+pub const CALIBRATION_LEAD_IN: usize = 4; // First clicks to listen to, their taps are ignored
+pub const CALIBRATION_START: f64 = 1.0; // Seconds of silence before the first click
+pub const CALIBRATION_SAMPLE_RATE: u32 = 44_100;
+
 // Countdown behavior
 pub const COUNTDOWN_DURATION: f64 = 5.0; // Countdown before game starts
 
@@ -36,12 +132,13 @@ pub const NEON_ORANGE: Color = Color::new(1.0, 0.5, 0.0, 1.0); // Neon orange fo
 pub const CYBERPUNK_FONT_SIZE: f32 = 24.0; // Font size for UI text (song selection, buttons, etc.)
 
 pub fn window_conf() -> Conf {
+    let settings = crate::settings::load_settings();
     Conf {
         window_title: "YumOsu!".to_owned(),
-        window_width: 800,
-        window_height: 600,
+        window_width: settings.window_width,
+        window_height: settings.window_height,
         window_resizable: false,
-        fullscreen: false,
+        fullscreen: settings.fullscreen,
         ..Default::default()
     }
 }
@@ -1,121 +1,52 @@
-use crate::structs::{ Circle, FloatingText };
+use crate::structs::{ CheckpointKind, Circle, Slider, Spinner };
 use crate::constants::*;
-use macroquad::prelude::{ Vec2, KeyCode, mouse_position, is_key_pressed, draw_circle, Color };
-use rand::Rng;
-
-/// Initialize circles for a game with animations
-pub fn initialize_circles(
-    beats: &[f64],
-    rng: &mut impl Rng,
-    spawn_radius: f32,
-    center: Vec2,
-    shrink_time: f64,
-    delay: f64
-) -> Vec<Circle> {
-    beats
-        .iter()
-        .map(|&beat_time| {
-            let angle = rng.gen_range(0.0..std::f32::consts::TAU);
-            let distance = rng.gen_range(0.0..spawn_radius);
-
-            let position = Vec2::new(
-                center.x + distance * angle.cos(),
-                center.y + distance * angle.sin()
-            );
-
-            Circle {
-                position,
-                spawn_time: beat_time - shrink_time + delay,
-                hit_time: beat_time + delay,
-                max_radius: CIRCLE_MAX_RADIUS,
-                hit: false,
-                missed: false,
-            }
-        })
-        .collect()
-}
-
-/// Handle key hits with animation and feedback
-pub fn handle_key_hits(circles: &mut Vec<Circle>, elapsed: f64, score: &mut i32, shrink_time: f64) {
-    let mouse_pos: Vec2 = mouse_position().into();
-    let key_pressed = is_key_pressed(KeyCode::A) || is_key_pressed(KeyCode::S);
-
-    for circle in circles.iter_mut().filter(|c| !c.hit) {
-        if let Some(radius) = circle_radius(circle, elapsed, shrink_time) {
-            if mouse_pos.distance(circle.position) < radius && key_pressed {
-                circle.hit = true;
-                *score += calculate_score(circle.hit_time, elapsed);
-                break;
-            }
-        }
-    }
-}
-
-/// Calculate the shrinking radius with animation
-fn circle_radius(circle: &Circle, elapsed: f64, shrink_time: f64) -> Option<f32> {
-    let time_since_spawn = elapsed - circle.spawn_time;
-    if (0.0..=shrink_time).contains(&time_since_spawn) {
-        Some(circle.max_radius * (1.0 - ((time_since_spawn / shrink_time) as f32)))
-    } else {
-        None
-    }
-}
+use macroquad::prelude::{
+    Vec2,
+    draw_arc,
+    draw_circle,
+    draw_circle_lines,
+    draw_line,
+    draw_triangle,
+    Color,
+    WHITE,
+};
 
 /// Calculate the spawn radius based on the screen size
 pub fn calculate_spawn_radius(width: f32, height: f32) -> f32 {
     width.min(height) / 2.0 - 100.0
 }
 
-/// Handle missed circles and animate a "Miss" text
-pub fn handle_missed_circles(
-    circles: &mut Vec<Circle>,
-    elapsed: f64,
-    floating_texts: &mut Vec<FloatingText>,
-    shrink_time: f64
-) {
-    for circle in circles.iter_mut().filter(|c| !c.hit && !c.missed) {
-        let time_since_spawn = elapsed - circle.spawn_time;
-
-        if time_since_spawn > shrink_time {
-            circle.missed = true;
-
-            floating_texts.push(FloatingText {
-                text: "Miss".to_string(),
-                position: circle.position,
-                spawn_time: elapsed,
-                duration: 1.0,
-            });
+/// Draw the circles with their approach circles, and the sliders and spinners.
+///
+/// A hit circle keeps its size and fades in when it appears, while its approach
+/// circle shrinks onto it until the hit time. It stays until it is hit or missed.
+pub fn draw_circles(circles: &[Circle], elapsed: f64, approach_time: f64) {
+    for circle in circles {
+        if let Some(spinner) = &circle.spinner {
+            if elapsed >= circle.spawn_time && !spinner.finished {
+                draw_spinner(spinner, circle.position, elapsed, circle.spawn_time);
+            }
+            continue;
+        }
+        if let Some(slider) = &circle.slider {
+            if elapsed >= circle.spawn_time && !slider.finished {
+                draw_slider(slider, circle.max_radius, elapsed);
+            }
         }
-    }
-}
-
-/// Score calculation based on the hit time and elapsed time
-pub fn calculate_score(hit_time: f64, current_time: f64) -> i32 {
-    let time_difference = (current_time - hit_time).abs();
-    if time_difference < 0.1 {
-        300
-    } else if time_difference < 0.3 {
-        100
-    } else {
-        50
-    }
-}
 
-/// Draw animated circles with stylizing and dynamic color transitions
-pub fn draw_circles(circles: &Vec<Circle>, elapsed: f64, shrink_time: f64) {
-    for circle in circles {
         let time_since_spawn = elapsed - circle.spawn_time;
 
-        if (0.0..=shrink_time).contains(&time_since_spawn) && !circle.hit {
-            // Shrink the circle with a smooth scaling effect
-            let scale = 1.0 - time_since_spawn / shrink_time;
-            let radius = circle.max_radius * (scale as f32);
+        if time_since_spawn >= 0.0 && !circle.hit && !circle.missed {
+            let fade_in = FADE_IN_TIME * (approach_time / FULL_FADE_IN_APPROACH).min(1.0);
+            let opacity = ((time_since_spawn / fade_in) as f32).min(1.0);
+            let remaining = (1.0 - ((time_since_spawn / approach_time) as f32)).max(0.0);
+            let (x, y, radius) = (circle.position.x, circle.position.y, circle.max_radius);
 
             // Draw an animated outline with a pulsing effect
-            let pulse_intensity = 0.5 + (elapsed.sin() as f32) * 0.5;
+            let pulse_intensity = (0.5 + (elapsed.sin() as f32) * 0.5) * opacity;
             draw_circle(
-                circle.position.x,
-                circle.position.y,
+                x,
+                y,
                 radius + OUTLINE_THICKNESS,
                 Color::new(OUTLINE_COLOR.r, OUTLINE_COLOR.g, OUTLINE_COLOR.b, pulse_intensity)
             );
@@ -125,10 +56,91 @@ pub fn draw_circles(circles: &Vec<Circle>, elapsed: f64, shrink_time: f64) {
                 0.0, // Red channel (no red)
                 0.75, // Green channel (neon green/blue)
                 1.0, // Blue channel (maximum neon blue)
-                0.6 - (scale as f32) * 0.5 // Alpha channel: fade the alpha as it shrinks
+                0.6 * opacity // Alpha channel: fade in as the circle appears
             );
+            draw_circle(x, y, radius, color);
 
-            draw_circle(circle.position.x, circle.position.y, radius, color);
+            // The approach circle closes in on the hit circle at the hit time
+            let approach_radius = radius * (1.0 + (APPROACH_CIRCLE_SCALE - 1.0) * remaining);
+            let ring_color = Color::new(WHITE.r, WHITE.g, WHITE.b, opacity);
+            draw_circle_lines(x, y, approach_radius, OUTLINE_THICKNESS * 1.5, ring_color);
         }
     }
 }
+
+/// Draw a slider's body, its ticks and next reverse arrow still to come, and the
+/// ball with its follow circle once it is rolling
+fn draw_slider(slider: &Slider, circle_radius: f32, elapsed: f64) {
+    let body_radius = circle_radius * SLIDER_BODY_SCALE;
+    let body_color = Color::new(NEON_PURPLE.r, NEON_PURPLE.g, NEON_PURPLE.b, 0.35);
+    for pair in slider.path.windows(2) {
+        draw_line(pair[0].x, pair[0].y, pair[1].x, pair[1].y, body_radius * 2.0, body_color);
+    }
+    // Round off the ends and the joints of the straight pieces
+    for point in &slider.path {
+        draw_circle(point.x, point.y, body_radius, body_color);
+    }
+
+    let upcoming = &slider.checkpoints[slider.judged_checkpoints..];
+    for checkpoint in upcoming.iter().filter(|checkpoint| checkpoint.kind == CheckpointKind::Tick) {
+        draw_circle(checkpoint.position.x, checkpoint.position.y, body_radius * 0.2, WHITE);
+    }
+    let next_repeat = upcoming.iter().find(|checkpoint| checkpoint.kind == CheckpointKind::Repeat);
+    if let Some(repeat) = next_repeat {
+        draw_reverse_arrow(&slider.path, repeat.position, body_radius * 0.6);
+    }
+
+    if elapsed >= slider.start_time {
+        let ball = slider.ball_position(elapsed);
+        draw_circle(ball.x, ball.y, body_radius, NEON_PINK);
+        if slider.tracking {
+            let follow_radius = circle_radius * SLIDER_FOLLOW_SCALE;
+            draw_circle_lines(ball.x, ball.y, follow_radius, OUTLINE_THICKNESS, NEON_GREEN);
+        }
+    }
+}
+
+/// Draw a spinner fading in before it starts, then a ring that shrinks until it ends
+/// around an arc filling up with the required rotations
+fn draw_spinner(spinner: &Spinner, center: Vec2, elapsed: f64, spawn_time: f64) {
+    if elapsed < spinner.start_time {
+        let fade = ((elapsed - spawn_time) / (spinner.start_time - spawn_time)) as f32;
+        let color = Color::new(NEON_PURPLE.r, NEON_PURPLE.g, NEON_PURPLE.b, fade.clamp(0.0, 1.0));
+        draw_circle_lines(center.x, center.y, SPINNER_RADIUS, OUTLINE_THICKNESS, color);
+        return;
+    }
+
+    let duration = spinner.end_time - spinner.start_time;
+    let remaining = 1.0 - (((elapsed - spinner.start_time) / duration) as f32).clamp(0.0, 1.0);
+    let ring_radius = SPINNER_RADIUS * remaining;
+    draw_circle_lines(center.x, center.y, ring_radius, OUTLINE_THICKNESS, NEON_PURPLE);
+    draw_arc(
+        center.x,
+        center.y,
+        64,
+        SPINNER_RADIUS,
+        -90.0,
+        OUTLINE_THICKNESS * 3.0,
+        (spinner.progress() as f32) * 360.0,
+        if spinner.progress() >= 1.0 { NEON_GREEN } else { NEON_BLUE }
+    );
+    draw_circle(center.x, center.y, OUTLINE_THICKNESS * 3.0, WHITE);
+}
+
+/// Draw an arrow at the end of the path the ball turns back at, pointing back along it
+fn draw_reverse_arrow(path: &[Vec2], tip: Vec2, size: f32) {
+    let (Some(&first), Some(&last)) = (path.first(), path.last()) else {
+        return;
+    };
+    let inner = if tip.distance(first) < tip.distance(last) {
+        path.get(1)
+    } else {
+        path.iter().rev().nth(1)
+    };
+    let Some(direction) = inner.and_then(|&inner| (inner - tip).try_normalize()) else {
+        return;
+    };
+    let side = direction.perp() * size;
+    let base = tip - direction * size * 0.5;
+    draw_triangle(tip + direction * size, base + side, base - side, WHITE);
+}
@@ -0,0 +1,348 @@
+//! Key and mouse input taken as a queue of events rather than polled once per frame.
+//!
+//! Every press becomes its own replay frame at the time it was received, with the
+//! cursor where it was then, so two presses within one rendered frame are both judged.
+//!
+//! The window delivers the events that piled up during a frame in one batch before
+//! the next one, and neither miniquad nor macroquad tell when the OS received them.
+//! Each event is therefore stamped with its place in the batch, spread evenly over the
+//! time since the previous batch, which is where the OS received it on average.
+
+use crate::structs::{ HitInput, InputEvent, InputKind, InputQueue, ReplayFrame };
+use macroquad::input::utils::{ register_input_subscriber, repeat_all_miniquad_input };
+use macroquad::miniquad::{ EventHandler, KeyMods };
+use macroquad::prelude::{ KeyCode, MouseButton, Vec2 };
+use std::time::Instant;
+
+impl InputQueue {
+    /// Start receiving the window's input events
+    pub fn new() -> Self {
+        InputQueue { subscriber: register_input_subscriber(), last_poll: Instant::now() }
+    }
+
+    /// Every event that arrived since the last call, oldest first.
+    ///
+    /// Must be called every frame, events that are not taken pile up.
+    pub fn poll(&mut self) -> Vec<InputEvent> {
+        let mut collector = EventCollector { deliveries: 0, events: Vec::new() };
+        repeat_all_miniquad_input(&mut collector, self.subscriber);
+        let now = Instant::now();
+        let events = collector.stamp(self.last_poll, now);
+        self.last_poll = now;
+        events
+    }
+}
+
+/// Collects the events macroquad repeats to it with the delivery they came with
+struct EventCollector {
+    deliveries: usize,
+    events: Vec<(usize, InputKind)>,
+}
+
+impl EventCollector {
+    /// Start the next delivery, the events pushed until the next one arrived together
+    fn deliver(&mut self) {
+        self.deliveries += 1;
+    }
+
+    fn push(&mut self, kind: InputKind) {
+        self.events.push((self.deliveries, kind));
+    }
+
+    /// Spread the deliveries evenly over the time from `since` until `now`, in order
+    fn stamp(self, since: Instant, now: Instant) -> Vec<InputEvent> {
+        let interval = now.saturating_duration_since(since);
+        let slots = (self.deliveries + 1) as u32;
+        self.events
+            .into_iter()
+            .map(|(delivery, kind)| InputEvent {
+                received: since + (interval * (delivery as u32)) / slots,
+                kind,
+            })
+            .collect()
+    }
+}
+
+impl EventHandler for EventCollector {
+    fn update(&mut self) {}
+
+    fn draw(&mut self) {}
+
+    fn mouse_motion_event(&mut self, x: f32, y: f32) {
+        self.deliver();
+        self.push(InputKind::MouseMove(Vec2::new(x, y)));
+    }
+
+    fn mouse_button_down_event(&mut self, button: MouseButton, x: f32, y: f32) {
+        self.deliver();
+        self.push(InputKind::MouseMove(Vec2::new(x, y)));
+        self.push(InputKind::MouseDown(button));
+    }
+
+    fn mouse_button_up_event(&mut self, button: MouseButton, _x: f32, _y: f32) {
+        self.deliver();
+        self.push(InputKind::MouseUp(button));
+    }
+
+    fn key_down_event(&mut self, keycode: KeyCode, _keymods: KeyMods, repeat: bool) {
+        self.deliver();
+        // Holding a key down sends repeats, which are not new presses
+        if !repeat {
+            self.push(InputKind::KeyDown(keycode));
+        }
+    }
+
+    fn key_up_event(&mut self, keycode: KeyCode, _keymods: KeyMods) {
+        self.deliver();
+        self.push(InputKind::KeyUp(keycode));
+    }
+}
+
+/// Place events on the song clock by how long before `now` they were received, when
+/// the song was at `song_time`
+pub fn song_times(events: &[InputEvent], now: Instant, song_time: f64) -> Vec<(f64, InputKind)> {
+    events
+        .iter()
+        .map(|event| {
+            let age = now.saturating_duration_since(event.received).as_secs_f64();
+            (song_time - age, event.kind)
+        })
+        .collect()
+}
+
+/// Whether an event is a press of one of `keys` or a click
+pub fn is_tap(kind: InputKind, keys: &[KeyCode]) -> bool {
+    match kind {
+        InputKind::KeyDown(key) => keys.contains(&key),
+        InputKind::MouseDown(button) => button == MouseButton::Left,
+        _ => false,
+    }
+}
+
+impl HitInput {
+    /// Follow the hit keys of a play, starting with nothing held and the cursor at `cursor`
+    pub fn new(hit_keys: [KeyCode; 2], cursor: Vec2) -> Self {
+        HitInput {
+            hit_keys,
+            keys_held: [false; 2],
+            buttons_held: [false; 2],
+            cursor,
+            last_time: 0.0,
+        }
+    }
+
+    /// Replay frames for the events of one rendered frame at song position `time`,
+    /// each event given with the song position it was received at.
+    ///
+    /// Every press of a hit key or mouse button gets a frame of its own at its time,
+    /// followed by one frame with the keys held and the cursor at `time`.
+    pub fn frames(
+        &mut self,
+        events: &[(f64, InputKind)],
+        time: f64,
+        center: Vec2,
+        spawn_radius: f32
+    ) -> Vec<ReplayFrame> {
+        let mut frames = Vec::new();
+        for &(event_time, kind) in events {
+            // The song clock is smoothed, so keep the frames in order
+            let event_time = event_time.clamp(self.last_time, time.max(self.last_time));
+            self.last_time = event_time;
+
+            let pressed = match kind {
+                InputKind::MouseMove(position) => {
+                    self.cursor = position;
+                    None
+                }
+                InputKind::KeyDown(key) => self.set_key(key, true),
+                InputKind::KeyUp(key) => {
+                    self.set_key(key, false);
+                    None
+                }
+                InputKind::MouseDown(button) => self.set_button(button, true),
+                InputKind::MouseUp(button) => {
+                    self.set_button(button, false);
+                    None
+                }
+            };
+            if let Some(slot) = pressed {
+                let mut presses = [false; 2];
+                presses[slot] = true;
+                let held = self.held();
+                frames.push(
+                    ReplayFrame::new(event_time, self.cursor, center, spawn_radius, held, presses)
+                );
+            }
+        }
+
+        self.last_time = time.max(self.last_time);
+        let held = self.held();
+        frames.push(
+            ReplayFrame::new(self.last_time, self.cursor, center, spawn_radius, held, [false; 2])
+        );
+        frames
+    }
+
+    /// Which of the two hit slots are held by a key or a mouse button
+    fn held(&self) -> [bool; 2] {
+        [0, 1].map(|slot| self.keys_held[slot] || self.buttons_held[slot])
+    }
+
+    /// Update a hit key and return its slot if it is one and was newly pressed
+    fn set_key(&mut self, key: KeyCode, down: bool) -> Option<usize> {
+        let slot = self.hit_keys.iter().position(|&hit_key| hit_key == key)?;
+        let pressed = down && !self.keys_held[slot];
+        self.keys_held[slot] = down;
+        pressed.then_some(slot)
+    }
+
+    /// Update a mouse button and return its slot if it hits and was newly pressed
+    fn set_button(&mut self, button: MouseButton, down: bool) -> Option<usize> {
+        let slot = match button {
+            MouseButton::Left => 0,
+            MouseButton::Right => 1,
+            _ => {
+                return None;
+            }
+        };
+        let pressed = down && !self.buttons_held[slot];
+        self.buttons_held[slot] = down;
+        pressed.then_some(slot)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::structs::{ Band, BeatmapCircle, BeatmapDifficulty, Simulation };
+    use std::time::Duration;
+
+    const CENTER: Vec2 = Vec2::new(400.0, 300.0);
+    const SPAWN_RADIUS: f32 = 200.0;
+
+    fn hit_input() -> HitInput {
+        HitInput::new([KeyCode::A, KeyCode::S], CENTER)
+    }
+
+    #[test]
+    fn every_press_in_a_frame_gets_its_own_frame() {
+        let mut input = hit_input();
+        let left = CENTER - Vec2::new(100.0, 0.0);
+        let frames = input.frames(
+            &[
+                (1.001, InputKind::MouseMove(left)),
+                (1.002, InputKind::KeyDown(KeyCode::A)),
+                (1.005, InputKind::KeyDown(KeyCode::S)),
+                (1.008, InputKind::KeyUp(KeyCode::A)),
+                (1.010, InputKind::KeyDown(KeyCode::A)),
+                (1.012, InputKind::KeyDown(KeyCode::D)),
+            ],
+            1.016,
+            CENTER,
+            SPAWN_RADIUS
+        );
+
+        let times: Vec<f64> = frames.iter().map(|frame| frame.time).collect();
+        assert_eq!(times, vec![1.002, 1.005, 1.010, 1.016]);
+        assert!(frames[..3].iter().all(ReplayFrame::key_pressed));
+        assert!(!frames[3].key_pressed() && frames[3].key_held());
+        assert_eq!(frames[0].cursor(CENTER, SPAWN_RADIUS), left);
+    }
+
+    #[test]
+    fn events_stay_in_order_when_the_clock_is_corrected() {
+        let mut input = hit_input();
+        input.frames(&[], 2.0, CENTER, SPAWN_RADIUS);
+        let frames = input.frames(
+            &[(1.99, InputKind::MouseDown(MouseButton::Right))],
+            2.016,
+            CENTER,
+            SPAWN_RADIUS
+        );
+
+        assert_eq!(frames[0].time, 2.0);
+        assert!(frames[0].key_pressed());
+    }
+
+    #[test]
+    fn a_batch_of_events_is_spread_over_the_frame() {
+        let since = Instant::now();
+        let now = since + Duration::from_millis(16);
+        let mut collector = EventCollector { deliveries: 0, events: Vec::new() };
+        collector.key_down_event(KeyCode::A, KeyMods::default(), false);
+        collector.mouse_button_down_event(MouseButton::Left, 10.0, 20.0);
+        collector.key_down_event(KeyCode::A, KeyMods::default(), true);
+        collector.key_up_event(KeyCode::A, KeyMods::default());
+
+        let received: Vec<Duration> = collector
+            .stamp(since, now)
+            .iter()
+            .map(|event| event.received - since)
+            .collect();
+        // Four deliveries, the click moves the cursor and presses at once
+        let millis = |ms: f64| Duration::from_secs_f64(ms / 1000.0);
+        assert_eq!(received, vec![millis(3.2), millis(6.4), millis(6.4), millis(12.8)]);
+    }
+
+    #[test]
+    fn events_are_placed_on_the_song_clock_by_their_age() {
+        let now = Instant::now();
+        let received = |ms: u64| now - Duration::from_millis(ms);
+        let events = [
+            InputEvent { received: received(12), kind: InputKind::KeyDown(KeyCode::A) },
+            InputEvent { received: received(8), kind: InputKind::KeyDown(KeyCode::S) },
+            InputEvent { received: received(4), kind: InputKind::KeyUp(KeyCode::A) },
+        ];
+
+        let mut input = hit_input();
+        let frames = input.frames(&song_times(&events, now, 2.0), 2.0, CENTER, SPAWN_RADIUS);
+
+        let times: Vec<f64> = frames.iter().map(|frame| frame.time).collect();
+        let expected = [1.988, 1.992, 2.0];
+        assert_eq!(times.len(), expected.len());
+        for (time, expected) in times.iter().zip(expected) {
+            assert!((time - expected).abs() < 1e-9, "{} != {}", time, expected);
+        }
+        assert!(frames[..2].iter().all(ReplayFrame::key_pressed));
+    }
+
+    #[test]
+    fn two_presses_in_one_frame_hit_two_circles() {
+        let beatmap_circles = [0.0, 0.5].map(|x| BeatmapCircle {
+            time: 2.0 + (x as f64) * 0.05,
+            x,
+            y: 0.0,
+            band: Band::Kick,
+            strength: 1.0,
+            slider: None,
+            spinner_duration: None,
+        });
+        let mut simulation = Simulation::new(
+            &beatmap_circles,
+            SPAWN_RADIUS,
+            CENTER,
+            &BeatmapDifficulty::default(),
+            0.0
+        );
+
+        // A fast double tap on both keys while the cursor moves between the circles
+        let mut input = hit_input();
+        let second = CENTER + Vec2::new(100.0, 0.0);
+        let frames = input.frames(
+            &[
+                (2.0, InputKind::KeyDown(KeyCode::A)),
+                (2.01, InputKind::MouseMove(second)),
+                (2.012, InputKind::KeyDown(KeyCode::S)),
+            ],
+            2.016,
+            CENTER,
+            SPAWN_RADIUS
+        );
+        for frame in &frames {
+            simulation.step(frame, CENTER, SPAWN_RADIUS);
+        }
+
+        assert!(simulation.circles.iter().all(|circle| circle.hit));
+        assert_eq!(simulation.stats.count_300, 2);
+    }
+}
@@ -3,18 +3,44 @@ mod structs;
 mod audio;
 mod ui;
 mod game;
+mod beatmap;
+mod osu;
+mod settings;
+mod calibration;
+mod replay;
+mod patterns;
+mod rating;
+mod profile;
+mod simulation;
+mod slider;
+mod spinner;
+mod input;
 
 use crate::structs::*;
 use crate::constants::*;
-use crate::audio::*;
 use crate::ui::*;
 use crate::game::*;
+use crate::beatmap::*;
+use crate::osu::*;
+use crate::audio::open_audio_source;
+use crate::settings::{ load_settings, save_settings };
+use crate::calibration::{ calibrate, start_calibration };
+use crate::replay::{ list_replays, read_replay, save_replay };
+use crate::rating::{ play_performance_points, play_star_rating };
+use crate::profile::{ load_profile, save_profile };
+use crate::simulation::auto_frame;
+use crate::input::{ is_tap, song_times };
 
 use macroquad::prelude::*;
 use rodio::{ Decoder, OutputStream, Sink };
-use std::{ sync::mpsc, thread, time::Instant };
-
-fn handle_menu_state(assets: &Assets, songs: &mut Vec<String>) -> GameState {
+use std::{
+    sync::{ atomic::{ AtomicBool, Ordering }, mpsc::{ self, TryRecvError }, Arc },
+    path::PathBuf,
+    thread,
+    time::Instant,
+};
+
+fn handle_menu_state(assets: &Assets, songs: &mut Vec<SongEntry>) -> GameState {
     if let Some(selected) = draw_menu(assets) {
         match selected.as_str() {
             "Start Game" => {
@@ -22,8 +48,14 @@ fn handle_menu_state(assets: &Assets, songs: &mut Vec<String>) -> GameState {
                 GameState::SongSelection // Proceed to song selection
             }
             "Settings" => {
-                GameState::Settings // Navigate to the settings screen (assuming you have this state)
+                GameState::Settings(SettingsScreenState::default()) // Navigate to the settings screen
             }
+            "Profile" => GameState::Profile,
+            "Replays" =>
+                GameState::Replays(ReplayBrowserState {
+                    replays: list_replays(),
+                    selected: 0,
+                }),
             "Exit" => {
                 GameState::Exit // Assuming you have an exit state that handles closing the game
             }
@@ -36,12 +68,29 @@ fn handle_menu_state(assets: &Assets, songs: &mut Vec<String>) -> GameState {
 
 fn handle_song_selection_state(
     selected_song: &mut String,
-    songs: &Vec<String>,
+    songs: &[SongEntry],
+    mods: &mut Mods,
+    level: &mut DifficultyLevel,
     assets: &Assets
 ) -> GameState {
     let mut selection_state = SongSelectionState::new();
 
-    if let Some(song) = draw_choose_audio(&mut selection_state, songs, assets) {
+    // Tab toggles the autoplay bot for the next plays
+    if is_key_pressed(KeyCode::Tab) {
+        *mods = mods.toggled(Mods::AUTO);
+    }
+    // Left and right pick the difficulty generated maps are played at
+    if is_key_pressed(KeyCode::Left) {
+        *level = level.stepped(-1);
+    }
+    if is_key_pressed(KeyCode::Right) {
+        *level = level.stepped(1);
+    }
+
+    let selected = draw_choose_audio(&mut selection_state, songs, *level, assets);
+    draw_mods(*mods, assets);
+    draw_level(*level, assets);
+    if let Some(song) = selected {
         *selected_song = song;
         GameState::Playing
     } else {
@@ -49,59 +98,131 @@ fn handle_song_selection_state(
     }
 }
 
-fn handle_playing_state(selected_song: &String) -> GameState {
-    // Start the beat detection in a new thread
+fn handle_playing_state(
+    selected_song: &str,
+    level: DifficultyLevel,
+    settings: &Settings
+) -> GameState {
+    // Load the chart or cached beatmap, or run the beat detection in a new thread
     let (tx, rx) = mpsc::channel();
-    let song_path = selected_song.clone();
+    let cancel = Arc::new(AtomicBool::new(false));
+    let progress = AnalysisProgress {
+        tx,
+        cancel: cancel.clone(),
+    };
+    let song_path = selected_song.to_string();
+    let seed_override = settings.placement_seed;
+    let params = settings.analysis_params();
     thread::spawn(move || {
-        let beats = gather_beats(&song_path);
-        tx.send(beats).unwrap();
+        let beatmap = if is_osu_chart(&song_path) {
+            load_osu_beatmap(&song_path)
+        } else {
+            load_or_generate_beatmap(&song_path, level, &params, seed_override, &progress)
+        };
+        let message = match beatmap {
+            Ok(beatmap) => LoadingMessage::Done(Box::new(beatmap)),
+            Err(LoadError::Cancelled) => LoadingMessage::Cancelled,
+            Err(err) => LoadingMessage::Failed(err),
+        };
+        // The loading screen may already be gone, so a failed send is fine
+        let _ = progress.tx.send(message);
     });
 
     // Switch to the loading state
     GameState::Loading {
         rx,
-        start_time: Instant::now(),
+        cancel,
+        stage: LoadingStage::Decoding,
+        progress: 0.0,
     }
 }
 
 fn handle_loading_state(
-    rx: mpsc::Receiver<Vec<f64>>,
-    start_time: Instant,
-    selected_song: &String,
+    selected_song: &str,
+    rx: mpsc::Receiver<LoadingMessage>,
+    cancel: Arc<AtomicBool>,
+    mut stage: LoadingStage,
+    mut progress: f32,
     assets: &Assets
 ) -> GameState {
-    // Display the loading bar
-    let loading_time = start_time.elapsed().as_secs_f32();
-    draw_loading_bar(loading_time, assets);
+    // Escape asks the analysis thread to stop, it acknowledges with `Cancelled`
+    if is_key_pressed(KeyCode::Escape) {
+        cancel.store(true, Ordering::Relaxed);
+    }
+    let cancelling = cancel.load(Ordering::Relaxed);
 
-    // Check if the beats are received
-    if let Ok(beats) = rx.try_recv() {
-        // Load the audio file but don't play it yet
-        let file = std::fs::File::open(selected_song).expect("Failed to open audio file");
-        let reader = std::io::BufReader::new(file);
-        let source = Decoder::new(reader).expect("Failed to decode audio");
+    // Handle every message received since the last frame
+    loop {
+        let message = match rx.try_recv() {
+            Ok(message) => message,
+            Err(TryRecvError::Empty) => break,
+            Err(TryRecvError::Disconnected) => {
+                // The thread ended without an answer, most likely because it panicked
+                return GameState::Error(
+                    Box::new(LoadError::Analysis {
+                        path: selected_song.to_string(),
+                        message: "the analysis thread stopped unexpectedly".to_string(),
+                    })
+                );
+            }
+        };
 
-        // Switch to the ready to play state
-        GameState::ReadyToPlay {
-            beats,
-            ready_time: Instant::now(),
-            source: Some(source),
-        }
-    } else {
-        // Stay in the loading state
-        GameState::Loading {
-            rx,
-            start_time,
+        match message {
+            LoadingMessage::Progress { stage: new_stage, fraction } => {
+                stage = new_stage;
+                progress = fraction;
+            }
+            LoadingMessage::Cancelled => {
+                return GameState::SongSelection;
+            }
+            LoadingMessage::Failed(err) => {
+                println!("{}", err);
+                return GameState::Error(Box::new(err));
+            }
+            LoadingMessage::Done(beatmap) => {
+                if cancelling {
+                    return GameState::SongSelection;
+                }
+
+                // Load the audio file but don't play it yet
+                let source = match open_audio_source(&beatmap.metadata.audio_path) {
+                    Ok(source) => source,
+                    Err(err) => {
+                        println!("{}", err);
+                        return GameState::Error(Box::new(err));
+                    }
+                };
+
+                // Switch to the ready to play state
+                return GameState::ReadyToPlay {
+                    beatmap,
+                    ready_time: Instant::now(),
+                    source: Some(Box::new(source)),
+                };
+            }
         }
     }
+
+    // Display the loading bar
+    let label = if cancelling { "Cancelling" } else { stage.label() };
+    draw_loading_bar(label, stage.overall_progress(progress), assets);
+
+    // Stay in the loading state
+    GameState::Loading {
+        rx,
+        cancel,
+        stage,
+        progress,
+    }
 }
 
 fn handle_ready_to_play_state(
-    beats: Vec<f64>,
+    beatmap: Box<Beatmap>,
     ready_time: Instant,
-    mut source: Option<Decoder<std::io::BufReader<std::fs::File>>>,
+    mut source: Option<Box<Decoder<std::io::BufReader<std::fs::File>>>>,
     sink: &mut Sink,
+    settings: &Settings,
+    mods: Mods,
     assets: &Assets
 ) -> GameState {
     // Display the countdown
@@ -118,7 +239,7 @@ fn handle_ready_to_play_state(
         let text_dimensions = measure_text(
             &countdown_text,
             Some(&assets.cyberpunk_font),
-            FONT_SIZE as u16,
+            FONT_SIZE,
             1.0
         );
         let text_x = (scr_width - text_dimensions.width) / 2.0; // Center horizontally
@@ -133,103 +254,408 @@ fn handle_ready_to_play_state(
         });
 
         GameState::ReadyToPlay {
-            beats,
+            beatmap,
             ready_time,
             source,
         }
     } else {
-        // Start the audio playback
-        if let Some(source) = source.take() {
-            sink.append(source);
-            sink.play();
-        }
-
-        // Initialize the visualization state
-        let (width, height) = (screen_width(), screen_height());
-        let mut rng = ::rand::thread_rng();
-
-        let spawn_radius = calculate_spawn_radius(width, height);
-        let center = Vec2::new(width / 2.0, height / 2.0);
+        let Some(source) = source.take() else {
+            return GameState::SongSelection;
+        };
 
-        let circles = initialize_circles(
-            &beats,
-            &mut rng,
-            spawn_radius,
-            center,
-            SHRINK_TIME,
-            COUNTDOWN_DURATION // Pass the delay here
+        // Record the play against the difficulty and offset it is judged with
+        let difficulty = settings.play_difficulty(&beatmap);
+        let metadata = &beatmap.metadata;
+        let replay = Replay::new(
+            metadata.chart_path.as_ref().unwrap_or(&metadata.audio_path),
+            metadata.level,
+            hash_beatmap(&beatmap),
+            mods,
+            settings.audio_offset(),
+            difficulty.approach_time,
+            difficulty.circle_radius
         );
-        let score = 0;
-        let floating_texts = Vec::new();
-
-        GameState::Visualizing(
-            Box::new(VisualizingState {
-                beats: beats.clone(),
-                start_time: Instant::now(),
-                circles,
-                score,
-                floating_texts,
-            })
-        )
+        let vis_state = start_play(beatmap, *source, sink, replay, settings.hit_keys(), None);
+        GameState::Visualizing(Box::new(vis_state))
+    }
+}
+
+/// Start the audio and build the play state.
+///
+/// Circles are timed and sized from the replay, which is recorded into while
+/// playing or re-driven from `playback` when watching it.
+fn start_play(
+    beatmap: Box<Beatmap>,
+    source: Decoder<std::io::BufReader<std::fs::File>>,
+    sink: &mut Sink,
+    replay: Replay,
+    hit_keys: [KeyCode; 2],
+    playback: Option<usize>
+) -> VisualizingState {
+    // Start the audio playback, the clock follows the samples the output consumes
+    let (source, clock) = PlaybackClock::for_source(source);
+    sink.append(source);
+    sink.play();
+
+    // Initialize the visualization state
+    let (center, spawn_radius) = playfield();
+    let difficulty = BeatmapDifficulty {
+        approach_time: replay.approach_time,
+        circle_radius: replay.circle_radius,
+        ..beatmap.difficulty.clone()
+    };
+    let simulation = Simulation::new(
+        &beatmap.circles,
+        spawn_radius,
+        center,
+        &difficulty,
+        replay.audio_offset // Circles are timed against the song position
+    );
+    let rating = play_star_rating(&beatmap, &difficulty);
+
+    VisualizingState {
+        clock,
+        simulation,
+        floating_texts: Vec::new(),
+        hit_input: HitInput::new(hit_keys, mouse_position().into()),
+        beatmap,
+        replay,
+        playback,
+        replay_path: None,
+        rating,
     }
 }
 
+/// Centre and spawn radius of the playfield in the current window
+fn playfield() -> (Vec2, f32) {
+    let (width, height) = (screen_width(), screen_height());
+    (Vec2::new(width / 2.0, height / 2.0), calculate_spawn_radius(width, height))
+}
+
 fn handle_visualizing_state(
     mut vis_state: Box<VisualizingState>,
     sink: &mut Sink,
+    profile: &mut Profile,
+    input_events: &[InputEvent],
     assets: &Assets
 ) -> GameState {
     // Visualization code
-    let elapsed = vis_state.start_time.elapsed().as_secs_f64();
+    let now = Instant::now();
+    let elapsed = vis_state.clock.update(now);
+    let (center, spawn_radius) = playfield();
 
     clear_background(DARK_BACKGROUND);
 
-    // Handle inputs, update circles, draw circles, etc.
-    handle_key_hits(&mut vis_state.circles, elapsed, &mut vis_state.score, SHRINK_TIME);
-    handle_missed_circles(
-        &mut vis_state.circles,
-        elapsed,
-        &mut vis_state.floating_texts,
-        SHRINK_TIME
-    );
-    draw_circles(&vis_state.circles, elapsed, SHRINK_TIME);
+    // Take this frame's input from the player or the autoplay bot,
+    // or every recorded frame that is now due
+    let frames = match vis_state.playback {
+        Some(next) => {
+            let due = vis_state.replay.frames[next..]
+                .iter()
+                .take_while(|frame| frame.time <= elapsed)
+                .count();
+            vis_state.playback = Some(next + due);
+            vis_state.replay.frames[next..next + due].to_vec()
+        }
+        None if vis_state.replay.mods.auto() => {
+            let frame = auto_frame(&vis_state.simulation.circles, elapsed, center, spawn_radius);
+            vis_state.replay.frames.push(frame);
+            vec![frame]
+        }
+        None => {
+            // Place each event on the song clock by how long ago it was received
+            let events = song_times(input_events, now, elapsed);
+            let frames = vis_state.hit_input.frames(&events, elapsed, center, spawn_radius);
+            vis_state.replay.frames.extend_from_slice(&frames);
+            frames
+        }
+    };
+
+    // Judge every frame at its own time, in the same order as when it was recorded
+    for frame in &frames {
+        for position in vis_state.simulation.step(frame, center, spawn_radius) {
+            vis_state.floating_texts.push(FloatingText {
+                text: "Miss".to_string(),
+                position,
+                spawn_time: frame.time,
+                duration: 1.0,
+            });
+        }
+    }
+    let simulation = &vis_state.simulation;
+    draw_circles(&simulation.circles, elapsed, simulation.approach_time);
+    if vis_state.playback.is_some() || vis_state.replay.mods.auto() {
+        if let Some(frame) = frames.last() {
+            draw_ghost_cursor(frame.cursor(center, spawn_radius));
+        }
+    }
     draw_floating_texts(&mut vis_state.floating_texts, elapsed, assets);
-    draw_score(vis_state.score, assets);
+    draw_score(vis_state.simulation.stats.score, assets);
+    draw_play_stats(&vis_state.simulation.stats, assets);
+    draw_spinner_rate(&vis_state.simulation.circles, elapsed, assets);
 
     if is_key_pressed(KeyCode::Escape) {
-        // Optionally stop the music
+        // Optionally stop the music, the replay of an aborted play is kept up to here
         sink.stop();
+        if vis_state.playback.is_none() {
+            keep_replay(&mut vis_state);
+        }
         GameState::Menu
     } else if sink.empty() {
-        // Music has ended
-        GameState::End
+        // The smoothed clock can stop short of the last recorded frames, judge them too
+        if let Some(next) = vis_state.playback {
+            for frame in &vis_state.replay.frames[next..] {
+                vis_state.simulation.step(frame, center, spawn_radius);
+            }
+            vis_state.playback = Some(vis_state.replay.frames.len());
+        }
+        // Music has ended, keep the replay of a finished play
+        if vis_state.playback.is_none() {
+            keep_replay(&mut vis_state);
+        }
+        // Auto plays and watched replays do not count towards the profile
+        if vis_state.playback.is_none() && !vis_state.replay.mods.auto() {
+            record_play(&vis_state, elapsed, profile);
+        }
+        GameState::Results(vis_state)
     } else {
         GameState::Visualizing(vis_state)
     }
 }
 
-fn handle_end_state() -> GameState {
-    // Clear the screen
-    clear_background(BLACK);
-
-    // Draw the game over text
-    let scr_width = screen_width();
-    let scr_height = screen_height();
-
-    draw_text("Game Over", scr_width / 2.0 - 100.0, scr_height / 2.0 - 150.0, 40.0, WHITE);
-    draw_text(
-        "Press any key to return to the main menu.",
-        scr_width / 2.0 - 200.0,
-        scr_height / 2.0 - 100.0,
-        30.0,
-        WHITE
-    );
+/// Save the replay recorded so far with the score reached
+fn keep_replay(vis_state: &mut VisualizingState) {
+    vis_state.replay.score = vis_state.simulation.stats.score;
+    match save_replay(&vis_state.replay) {
+        Ok(path) => {
+            println!("Saved replay: {}", path.display());
+            vis_state.replay_path = Some(path);
+        }
+        Err(err) => println!("Failed to save replay: {}", err),
+    }
+}
+
+/// Add a finished play to the profile and save it
+fn record_play(vis_state: &VisualizingState, play_time: f64, profile: &mut Profile) {
+    let beatmap = &vis_state.beatmap;
+    let stats = &vis_state.simulation.stats;
+    let title = match beatmap.metadata.level {
+        Some(level) => format!("{} [{:?}]", beatmap.metadata.title, level),
+        None => beatmap.metadata.title.clone(),
+    };
+    let play = BestPlay {
+        title,
+        beatmap_hash: format!("{:016x}", hash_beatmap(beatmap)),
+        stars: vis_state.rating.map_or(0.0, |rating| rating.stars),
+        pp: play_performance_points(vis_state).unwrap_or(0.0),
+        score: stats.score,
+        accuracy: stats.accuracy(),
+        grade: stats.grade(),
+    };
+    profile.record_play(play, stats.judged() - stats.count_miss, play_time);
+    if let Err(err) = save_profile(profile) {
+        println!("Failed to save profile: {}", err);
+    }
+}
 
-    // Check if the user wants to quit
-    if is_key_pressed(KeyCode::Enter) || is_mouse_button_pressed(MouseButton::Left) {
+fn handle_profile_state(profile: &Profile, assets: &Assets) -> GameState {
+    if draw_profile(profile, assets) {
         GameState::Menu
     } else {
-        GameState::End
+        GameState::Profile
+    }
+}
+
+fn handle_results_state(
+    vis_state: Box<VisualizingState>,
+    sink: &mut Sink,
+    assets: &Assets
+) -> GameState {
+    // A watched replay is verified by judging its inputs to the same score
+    let verified = vis_state.playback.map(|_| vis_state.simulation.stats.score == vis_state.replay.score);
+    let pp = play_performance_points(&vis_state);
+
+    match draw_results(&vis_state.simulation.stats, pp, verified, assets).as_deref() {
+        Some("Retry") => GameState::Playing, // Load the same song again
+        Some("Replay") => watch_replay(*vis_state, sink),
+        Some("Back") => GameState::SongSelection,
+        _ => GameState::Results(vis_state),
+    }
+}
+
+/// Play back the replay of a finished play, read from its saved file when possible
+fn watch_replay(vis_state: VisualizingState, sink: &mut Sink) -> GameState {
+    let replay = match vis_state.replay_path.as_deref().map(read_replay) {
+        Some(Ok(replay)) => replay,
+        Some(Err(err)) => {
+            println!("Failed to read replay, using the recorded one: {}", err);
+            vis_state.replay
+        }
+        None => vis_state.replay,
+    };
+    start_watching(
+        vis_state.beatmap,
+        replay,
+        vis_state.replay_path,
+        vis_state.hit_input.hit_keys,
+        sink
+    )
+}
+
+/// Start playing back a replay on its beatmap
+fn start_watching(
+    beatmap: Box<Beatmap>,
+    replay: Replay,
+    replay_path: Option<PathBuf>,
+    hit_keys: [KeyCode; 2],
+    sink: &mut Sink
+) -> GameState {
+    if replay.beatmap_hash != hash_beatmap(&beatmap) {
+        println!("Replay was recorded on a different version of this beatmap");
+    }
+
+    let source = match open_audio_source(&beatmap.metadata.audio_path) {
+        Ok(source) => source,
+        Err(err) => {
+            println!("{}", err);
+            return GameState::Error(Box::new(err));
+        }
+    };
+    let mut watching = start_play(beatmap, source, sink, replay, hit_keys, Some(0));
+    watching.replay_path = replay_path;
+    GameState::Visualizing(Box::new(watching))
+}
+
+fn handle_replays_state(
+    mut browser: ReplayBrowserState,
+    selected_song: &mut String,
+    level: &mut DifficultyLevel,
+    settings: &Settings,
+    sink: &mut Sink,
+    assets: &Assets
+) -> GameState {
+    match draw_replays(&mut browser, assets).as_deref() {
+        Some("Watch") => {
+            let Some(entry) = browser.replays.get(browser.selected) else {
+                return GameState::Replays(browser);
+            };
+            let replay = match read_replay(&entry.path) {
+                Ok(replay) => replay,
+                Err(err) => {
+                    println!("Failed to read replay {}: {}", entry.path.display(), err);
+                    return GameState::Replays(browser);
+                }
+            };
+            let beatmap = match load_saved_beatmap(&replay.song_path, replay.level) {
+                Ok(beatmap) => beatmap,
+                Err(err) => {
+                    println!("{}", err);
+                    return GameState::Error(Box::new(err));
+                }
+            };
+            // Retrying from the results plays the replay's song at its level, imported
+            // charts have a single level of their own
+            *selected_song = replay.song_path.clone();
+            if let Some(replay_level) = replay.level {
+                *level = replay_level;
+            }
+            let replay_path = Some(entry.path.clone());
+            start_watching(Box::new(beatmap), replay, replay_path, settings.hit_keys(), sink)
+        }
+        Some("Back") => GameState::Menu,
+        _ => GameState::Replays(browser),
+    }
+}
+
+fn handle_settings_state(
+    mut screen: SettingsScreenState,
+    settings: &mut Settings,
+    sink: &mut Sink,
+    assets: &Assets
+) -> GameState {
+    let previous = settings.clone();
+    let action = draw_settings(&mut screen, settings, assets);
+
+    // Apply the changes that take effect immediately
+    sink.set_volume(settings.volume);
+    if settings.fullscreen != previous.fullscreen {
+        set_fullscreen(settings.fullscreen);
+    }
+    if
+        (settings.window_width, settings.window_height) !=
+        (previous.window_width, previous.window_height)
+    {
+        request_new_screen_size(settings.window_width as f32, settings.window_height as f32);
+    }
+
+    match action {
+        Some(SettingsRow::Back) => {
+            if let Err(err) = save_settings(settings) {
+                println!("Failed to save settings: {}", err);
+            }
+            GameState::Menu
+        }
+        Some(SettingsRow::Calibrate) => {
+            sink.stop();
+            GameState::Calibrating(Box::new(start_calibration(sink)))
+        }
+        _ => GameState::Settings(screen),
+    }
+}
+
+fn handle_calibration_state(
+    mut calibration: Box<CalibrationState>,
+    settings: &mut Settings,
+    sink: &mut Sink,
+    input_events: &[InputEvent],
+    assets: &Assets
+) -> GameState {
+    let now = Instant::now();
+    let position = calibration.clock.update(now);
+
+    if !calibration.finished {
+        // Record a tap on every hit key or click, at the song position it was received at
+        let hit_keys = settings.hit_keys();
+        let taps = song_times(input_events, now, position)
+            .into_iter()
+            .filter(|&(_, kind)| is_tap(kind, &hit_keys))
+            .map(|(time, _)| time);
+        calibration.taps.extend(taps);
+
+        if is_key_pressed(KeyCode::Escape) {
+            sink.stop();
+            return GameState::Settings(SettingsScreenState::default());
+        }
+        if sink.empty() {
+            calibration.finished = true;
+            calibration.result = calibrate(
+                &calibration.beat_times,
+                &calibration.taps,
+                60.0 / CALIBRATION_BPM
+            );
+        }
+    } else if is_key_pressed(KeyCode::Enter) {
+        // Store the measured offset, it shifts every circle's hit time from now on
+        if let Some(result) = calibration.result {
+            settings.audio_offset_ms = ((result.offset * 1000.0).round() as i32).clamp(-500, 500);
+            if let Err(err) = save_settings(settings) {
+                println!("Failed to save settings: {}", err);
+            }
+        }
+        return GameState::Settings(SettingsScreenState::default());
+    } else if is_key_pressed(KeyCode::Escape) {
+        return GameState::Settings(SettingsScreenState::default());
+    }
+
+    draw_calibration(&calibration, position, assets);
+    GameState::Calibrating(calibration)
+}
+
+fn handle_error_state(error: Box<LoadError>, assets: &Assets) -> GameState {
+    if draw_error_screen(&error, assets) {
+        GameState::SongSelection
+    } else {
+        GameState::Error(error)
     }
 }
 
@@ -239,28 +665,68 @@ async fn main() {
     let mut state = GameState::Menu;
     let mut selected_song = String::new();
     let mut songs = Vec::new();
+    let mut settings = load_settings();
+    let mut mods = Mods::default();
+    let mut level = DifficultyLevel::Normal;
+    let mut profile = load_profile();
 
     let (_stream, stream_handle) = OutputStream::try_default().unwrap();
     let mut sink = Sink::try_new(&stream_handle).unwrap();
+    sink.set_volume(settings.volume);
 
     let assets = load_ui_assets().await;
+    let mut input = InputQueue::new();
 
     loop {
+        // Drained every frame so the queue only ever holds this frame's events
+        let input_events = input.poll();
         state = match state {
             GameState::Menu => handle_menu_state(&assets, &mut songs),
             GameState::SongSelection =>
-                handle_song_selection_state(&mut selected_song, &songs, &assets),
-            GameState::Playing => handle_playing_state(&selected_song),
-            GameState::Loading { rx, start_time } => {
-                handle_loading_state(rx, start_time, &selected_song, &assets)
+                handle_song_selection_state(&mut selected_song, &songs, &mut mods, &mut level, &assets),
+            GameState::Playing => handle_playing_state(&selected_song, level, &settings),
+            GameState::Loading { rx, cancel, stage, progress } => {
+                handle_loading_state(&selected_song, rx, cancel, stage, progress, &assets)
+            }
+            GameState::ReadyToPlay { beatmap, ready_time, source } => {
+                handle_ready_to_play_state(
+                    beatmap,
+                    ready_time,
+                    source,
+                    &mut sink,
+                    &settings,
+                    mods,
+                    &assets
+                )
+            }
+            GameState::Visualizing(vis_state) => {
+                handle_visualizing_state(vis_state, &mut sink, &mut profile, &input_events, &assets)
+            }
+            GameState::Results(vis_state) => handle_results_state(vis_state, &mut sink, &assets),
+            GameState::Profile => handle_profile_state(&profile, &assets),
+            GameState::Replays(browser) => {
+                handle_replays_state(
+                    browser,
+                    &mut selected_song,
+                    &mut level,
+                    &settings,
+                    &mut sink,
+                    &assets
+                )
+            }
+            GameState::Error(error) => handle_error_state(error, &assets),
+            GameState::Calibrating(calibration) => {
+                handle_calibration_state(
+                    calibration,
+                    &mut settings,
+                    &mut sink,
+                    &input_events,
+                    &assets
+                )
             }
-            GameState::ReadyToPlay { beats, ready_time, source } => {
-                handle_ready_to_play_state(beats, ready_time, source, &mut sink, &assets)
+            GameState::Settings(screen) => {
+                handle_settings_state(screen, &mut settings, &mut sink, &assets)
             }
-            GameState::Visualizing(vis_state) =>
-                handle_visualizing_state(vis_state, &mut sink, &assets),
-            GameState::End => handle_end_state(),
-            GameState::Settings => GameState::Settings,
             GameState::Exit => {
                 break;
             }
@@ -0,0 +1,597 @@
+use crate::beatmap::{ hash_audio_file, invalid_data, parse_value, BEATMAP_VERSION };
+use crate::constants::OSU_MAPS_DIR;
+use crate::rating::beatmap_star_rating;
+use crate::structs::{
+    Band,
+    Beatmap,
+    BeatmapCircle,
+    BeatmapDifficulty,
+    BeatmapMetadata,
+    BeatGrid,
+    CurveType,
+    LoadError,
+    SliderPath,
+};
+use macroquad::math::Vec2;
+use std::fs::{ self, File };
+use std::io;
+use std::path::Path;
+
+// Hit object type bits of the .osu format
+const TYPE_CIRCLE: u32 = 1;
+const TYPE_SLIDER: u32 = 1 << 1;
+const TYPE_SPINNER: u32 = 1 << 3;
+const TYPE_HOLD: u32 = 1 << 7;
+
+// The osu! playfield is 512x384 osu! pixels, half its height maps to the spawn radius
+const OSU_PLAYFIELD_CENTER_X: f32 = 256.0;
+const OSU_PLAYFIELD_CENTER_Y: f32 = 192.0;
+const OSU_PLAYFIELD_RADIUS: f32 = 192.0;
+
+// Scale from osu! pixels to screen pixels in the default 800x600 window
+const OSU_PIXEL_SCALE: f32 = 200.0 / OSU_PLAYFIELD_RADIUS;
+
+// Slider velocity of one beat in osu! pixels, before the SliderMultiplier
+const OSU_BASE_SLIDER_VELOCITY: f64 = 100.0;
+
+pub struct OsuTimingPoint {
+    pub time: f64,
+    pub beat_length: f64,
+    pub uninherited: bool,
+}
+
+/// A hit object that was found in the .osu file but cannot be played yet
+pub struct UnsupportedObject {
+    pub time: f64,
+    pub kind: &'static str,
+}
+
+pub struct OsuChart {
+    pub beatmap: Beatmap,
+    pub unsupported: Vec<UnsupportedObject>,
+}
+
+/// Whether a song entry refers to an imported osu! chart instead of an audio file
+pub fn is_osu_chart(path: &str) -> bool {
+    Path::new(path)
+        .extension()
+        .is_some_and(|extension| extension == "osu")
+}
+
+/// Load an imported osu! chart and report the objects that had to be skipped
+pub fn load_osu_beatmap(path: &str) -> Result<Beatmap, LoadError> {
+    println!("Loading osu! chart: {}", path);
+    let mut chart = parse_osu_file(Path::new(path)).map_err(|source| LoadError::InvalidChart {
+        path: path.to_string(),
+        source,
+    })?;
+    let audio_path = &chart.beatmap.metadata.audio_path;
+    chart.beatmap.audio_hash = hash_audio_file(audio_path).map_err(|source| LoadError::Open {
+        path: audio_path.clone(),
+        source,
+    })?;
+
+    if let Some(grid) = chart.beatmap.beat_grid {
+        println!(
+            "{}: {} circles at {:.0} BPM, timed from {:.3}s",
+            chart.beatmap.metadata.title,
+            chart.beatmap.circles.len(),
+            grid.bpm,
+            grid.offset
+        );
+    }
+    for object in &chart.unsupported {
+        println!("Skipping unsupported {} at {:.3}s", object.kind, object.time);
+    }
+
+    Ok(chart.beatmap)
+}
+
+/// Read and parse a .osu file, resolving its audio file relative to the chart.
+///
+/// The audio file is not hashed here so listing many charts stays cheap.
+pub fn parse_osu_file(path: &Path) -> io::Result<OsuChart> {
+    let contents = fs::read_to_string(path)?;
+    let base_dir = path.parent().unwrap_or_else(|| Path::new("."));
+    let mut chart = parse_osu(&contents, base_dir)?;
+    chart.beatmap.metadata.chart_path = Some(path.to_string_lossy().to_string());
+    Ok(chart)
+}
+
+/// Parse the General, Metadata, Difficulty, TimingPoints and HitObjects sections
+/// of a .osu file into a playable beatmap.
+pub fn parse_osu(contents: &str, base_dir: &Path) -> io::Result<OsuChart> {
+    let mut lines = contents.lines().map(|line| line.trim_start_matches('\u{feff}').trim());
+
+    let header = lines.next().unwrap_or_default();
+    if !header.starts_with("osu file format") {
+        return Err(invalid_data("missing osu file format header"));
+    }
+
+    let mut section = String::new();
+    let mut audio_filename = String::new();
+    let (mut title, mut artist, mut version) = (String::new(), String::new(), String::new());
+    let mut circle_size = 5.0;
+    let mut overall_difficulty = 5.0;
+    let mut approach_rate = None;
+    let mut slider_multiplier = 1.4;
+    let mut slider_tick_rate = 1.0;
+    let mut timing_points = Vec::new();
+    let mut circles = Vec::new();
+    let mut unsupported = Vec::new();
+
+    for line in lines {
+        if line.is_empty() || line.starts_with("//") {
+            continue;
+        }
+        if line.starts_with('[') && line.ends_with(']') {
+            section = line[1..line.len() - 1].to_string();
+            continue;
+        }
+
+        match section.as_str() {
+            "General" | "Metadata" | "Difficulty" => {
+                let Some((key, value)) = line.split_once(':') else {
+                    continue;
+                };
+                let value = value.trim();
+
+                match key.trim() {
+                    "AudioFilename" => {
+                        audio_filename = value.to_string();
+                    }
+                    "Title" => {
+                        title = value.to_string();
+                    }
+                    "Artist" => {
+                        artist = value.to_string();
+                    }
+                    "Version" => {
+                        version = value.to_string();
+                    }
+                    "CircleSize" => {
+                        circle_size = parse_value(value)?;
+                    }
+                    "OverallDifficulty" => {
+                        overall_difficulty = parse_value(value)?;
+                    }
+                    "ApproachRate" => {
+                        approach_rate = Some(parse_value(value)?);
+                    }
+                    "SliderMultiplier" => {
+                        slider_multiplier = parse_value(value)?;
+                    }
+                    "SliderTickRate" => {
+                        slider_tick_rate = parse_value(value)?;
+                    }
+                    _ => {}
+                }
+            }
+            "TimingPoints" => {
+                timing_points.push(parse_timing_point(line)?);
+            }
+            "HitObjects" => {
+                let fields: Vec<&str> = line.split(',').map(str::trim).collect();
+                if fields.len() < 4 {
+                    return Err(invalid_data(&format!("malformed hit object: {}", line)));
+                }
+                let x: f32 = parse_value(fields[0])?;
+                let y: f32 = parse_value(fields[1])?;
+                let time = parse_value::<f64>(fields[2])? / 1000.0;
+                let object_type: u32 = parse_value(fields[3])?;
+
+                let slider = if object_type & TYPE_SLIDER != 0 {
+                    let (beat_length, velocity) = timing_at(&timing_points, time);
+                    let velocity = velocity * slider_multiplier;
+                    parse_slider(&fields, beat_length, velocity, slider_tick_rate)?
+                } else {
+                    None
+                };
+                // Spinners are at the playfield centre whatever their position says
+                let spinner_duration = if object_type & TYPE_SPINNER != 0 {
+                    let end_time = fields
+                        .get(5)
+                        .ok_or_else(|| invalid_data(&format!("spinner has no end: {}", line)))?;
+                    Some(parse_value::<f64>(end_time)? / 1000.0 - time)
+                } else {
+                    None
+                };
+
+                if object_type & TYPE_CIRCLE != 0 || slider.is_some() || spinner_duration.is_some()
+                {
+                    let position = if spinner_duration.is_some() {
+                        Vec2::ZERO
+                    } else {
+                        normalize(x, y)
+                    };
+                    circles.push(BeatmapCircle {
+                        time,
+                        x: position.x,
+                        y: position.y,
+                        band: Band::Kick, // Charts carry no band information
+                        strength: 1.0,
+                        slider,
+                        spinner_duration,
+                    });
+                } else {
+                    let kind = if object_type & TYPE_HOLD != 0 {
+                        "hold note"
+                    } else if object_type & TYPE_SLIDER != 0 {
+                        "Catmull slider"
+                    } else {
+                        "hit object"
+                    };
+                    unsupported.push(UnsupportedObject { time, kind });
+                }
+            }
+            _ => {} // Editor, Colours, Events and unknown sections are not needed
+        }
+    }
+
+    if audio_filename.is_empty() {
+        return Err(invalid_data("chart has no AudioFilename"));
+    }
+    let audio_path = base_dir.join(&audio_filename).to_string_lossy().to_string();
+
+    // The first uninherited timing point gives the tempo and phase of the chart
+    let beat_grid = timing_points
+        .iter()
+        .find(|point| point.uninherited && point.beat_length > 0.0)
+        .map(|point| BeatGrid {
+            bpm: 60_000.0 / point.beat_length,
+            offset: point.time,
+        });
+
+    // Old charts have no ApproachRate and use the OverallDifficulty instead
+    let approach_rate = approach_rate.unwrap_or(overall_difficulty);
+
+    let mut beatmap = Beatmap {
+        version: BEATMAP_VERSION,
+        metadata: BeatmapMetadata {
+            title: format!("{} - {} [{}]", artist, title, version),
+            audio_path,
+            level: None,
+            chart_path: None,
+        },
+        difficulty: BeatmapDifficulty {
+            approach_time: approach_time_from_ar(approach_rate),
+            circle_radius: circle_radius_from_cs(circle_size),
+            overall_difficulty,
+        },
+        beat_grid,
+        analysis: None,
+        audio_hash: 0,
+        seed: None,
+        star_rating: None,
+        circles,
+    };
+    beatmap.star_rating = Some(beatmap_star_rating(&beatmap));
+
+    Ok(OsuChart { beatmap, unsupported })
+}
+
+/// Move a point of the osu! playfield to the unit disk around the playfield centre
+fn normalize(x: f32, y: f32) -> Vec2 {
+    Vec2::new(x - OSU_PLAYFIELD_CENTER_X, y - OSU_PLAYFIELD_CENTER_Y) / OSU_PLAYFIELD_RADIUS
+}
+
+/// Beat length in seconds and slider velocity multiplier in effect at a time.
+///
+/// Uninherited points set the beat length, the inherited points after them scale
+/// the slider velocity by -100 / their beat length, like osu!.
+fn timing_at(timing_points: &[OsuTimingPoint], time: f64) -> (f64, f64) {
+    let mut beat_length = timing_points
+        .iter()
+        .find(|point| point.uninherited && point.beat_length > 0.0)
+        .map_or(500.0, |point| point.beat_length);
+    let mut velocity = 1.0;
+    for point in timing_points.iter().take_while(|point| point.time <= time + 1e-6) {
+        if point.uninherited {
+            if point.beat_length > 0.0 {
+                beat_length = point.beat_length;
+            }
+            velocity = 1.0;
+        } else if point.beat_length < 0.0 {
+            velocity = (-100.0 / point.beat_length).clamp(0.1, 10.0);
+        }
+    }
+    (beat_length / 1000.0, velocity)
+}
+
+/// Parse the `curveType|x:y|...,slides,length` fields of a slider.
+///
+/// A span lasts as long as the ball takes to travel the length at the slider
+/// velocity, and there are `SliderTickRate` ticks per beat. Catmull curves, which
+/// are deprecated in osu!, are not supported and give None.
+fn parse_slider(
+    fields: &[&str],
+    beat_length: f64,
+    velocity: f64,
+    tick_rate: f64
+) -> io::Result<Option<SliderPath>> {
+    if fields.len() < 8 {
+        return Err(invalid_data(&format!("malformed slider: {}", fields.join(","))));
+    }
+    let mut path = fields[5].split('|');
+    let curve = match path.next().unwrap_or_default() {
+        "L" => CurveType::Linear,
+        "P" => CurveType::PerfectCircle,
+        "B" => CurveType::Bezier,
+        "C" => {
+            return Ok(None);
+        }
+        other => {
+            return Err(invalid_data(&format!("unknown slider curve: {}", other)));
+        }
+    };
+    let control_points = path
+        .map(|point| {
+            let (x, y) = point
+                .split_once(':')
+                .ok_or_else(|| invalid_data(&format!("malformed slider point: {}", point)))?;
+            Ok(normalize(parse_value(x)?, parse_value(y)?))
+        })
+        .collect::<io::Result<Vec<Vec2>>>()?;
+    let spans: u32 = parse_value(fields[6])?;
+    let length: f64 = parse_value(fields[7])?;
+
+    Ok(
+        Some(SliderPath {
+            curve,
+            control_points,
+            length: (length as f32) / OSU_PLAYFIELD_RADIUS,
+            spans: spans.max(1),
+            span_duration: (length / (OSU_BASE_SLIDER_VELOCITY * velocity)) * beat_length,
+            tick_interval: beat_length / tick_rate.max(0.1),
+        })
+    )
+}
+
+/// Parse a `time,beatLength,meter,sampleSet,sampleIndex,volume,uninherited,effects` line
+fn parse_timing_point(line: &str) -> io::Result<OsuTimingPoint> {
+    let fields: Vec<&str> = line.split(',').map(str::trim).collect();
+    if fields.len() < 2 {
+        return Err(invalid_data(&format!("malformed timing point: {}", line)));
+    }
+
+    Ok(OsuTimingPoint {
+        time: parse_value::<f64>(fields[0])? / 1000.0,
+        beat_length: parse_value(fields[1])?,
+        // Old formats omit the field, where every timing point is uninherited
+        uninherited: fields.get(6).is_none_or(|&field| field != "0"),
+    })
+}
+
+/// Seconds a circle is visible before its hit time for an osu! approach rate
+pub fn approach_time_from_ar(approach_rate: f32) -> f64 {
+    let approach_rate = approach_rate as f64;
+    if approach_rate < 5.0 {
+        1.2 + (0.6 * (5.0 - approach_rate)) / 5.0
+    } else {
+        1.2 - (0.75 * (approach_rate - 5.0)) / 5.0
+    }
+}
+
+/// Circle radius in screen pixels for an osu! circle size
+pub fn circle_radius_from_cs(circle_size: f32) -> f32 {
+    (54.4 - 4.48 * circle_size) * OSU_PIXEL_SCALE
+}
+
+/// Unpack every .osz archive in the maps directory and list all playable charts.
+///
+/// Archives are extracted into a folder named after the archive, once.
+pub fn import_osu_maps() -> Vec<String> {
+    let maps_dir = Path::new(OSU_MAPS_DIR);
+    let mut charts = Vec::new();
+
+    let Ok(entries) = fs::read_dir(maps_dir) else {
+        return charts;
+    };
+
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if path.extension().is_some_and(|extension| extension == "osz") {
+            let target = path.with_extension("");
+            if !target.exists() {
+                match unpack_osz(&path, &target) {
+                    Ok(()) => println!("Imported osu! archive: {}", path.display()),
+                    Err(err) => println!("Failed to import {}: {}", path.display(), err),
+                }
+            }
+        }
+    }
+
+    collect_charts(maps_dir, &mut charts);
+    if let Ok(entries) = fs::read_dir(maps_dir) {
+        for entry in entries.flatten() {
+            if entry.path().is_dir() {
+                collect_charts(&entry.path(), &mut charts);
+            }
+        }
+    }
+
+    charts.sort();
+    charts
+}
+
+/// Extract an .osz archive (a zip file) into the target directory
+fn unpack_osz(archive_path: &Path, target: &Path) -> io::Result<()> {
+    let mut archive = zip::ZipArchive::new(File::open(archive_path)?)?;
+    archive.extract(target)?;
+    Ok(())
+}
+
+/// Add every .osu file directly inside a directory to the chart list
+fn collect_charts(dir: &Path, charts: &mut Vec<String>) {
+    if let Ok(entries) = fs::read_dir(dir) {
+        for entry in entries.flatten() {
+            let path = entry.path().to_string_lossy().to_string();
+            if is_osu_chart(&path) {
+                println!("Loaded chart: {}", path);
+                charts.push(path);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CHART: &str = "\u{feff}osu file format v14
+
+[General]
+AudioFilename: audio.mp3
+
+[Metadata]
+Title:Test Song
+Artist:Someone
+Version:Hard
+
+[Difficulty]
+CircleSize:4
+OverallDifficulty:8
+ApproachRate:9
+SliderMultiplier:1.4
+SliderTickRate:2
+
+[TimingPoints]
+1000,500,4,2,0,50,1,0
+3000,-50,4,2,0,50,0,0
+
+[HitObjects]
+256,192,1000,1,0,0:0:0:0:
+448,192,1500,5,0,0:0:0:0:
+256,192,2000,2,0,L|356:192,1,140
+256,192,3000,2,0,B|306:142|356:192,2,140
+100,100,4000,12,0,6000,0:0:0:0:
+256,192,7000,128,0,7500:0:0:0:0:
+256,192,8000,2,0,C|306:142|356:192,1,140
+";
+
+    fn chart() -> OsuChart {
+        parse_osu(CHART, Path::new("maps/test")).unwrap()
+    }
+
+    #[test]
+    fn hit_objects_become_circles_sliders_and_spinners() {
+        let chart = chart();
+        let circles = &chart.beatmap.circles;
+
+        assert_eq!(chart.beatmap.metadata.title, "Someone - Test Song [Hard]");
+        assert_eq!(
+            Path::new(&chart.beatmap.metadata.audio_path),
+            Path::new("maps/test/audio.mp3")
+        );
+        let times: Vec<f64> = circles.iter().map(|circle| circle.time).collect();
+        assert_eq!(times, vec![1.0, 1.5, 2.0, 3.0, 4.0]);
+        assert_eq!((circles[0].x, circles[0].y), (0.0, 0.0));
+        assert_eq!((circles[1].x, circles[1].y), (1.0, 0.0));
+
+        // Spinners are centred and last until their end time
+        assert_eq!((circles[4].x, circles[4].y), (0.0, 0.0));
+        assert_eq!(circles[4].spinner_duration, Some(2.0));
+
+        let grid = chart.beatmap.beat_grid.unwrap();
+        assert_eq!((grid.bpm, grid.offset), (120.0, 1.0));
+
+        let unsupported: Vec<(f64, &str)> = chart.unsupported
+            .iter()
+            .map(|object| (object.time, object.kind))
+            .collect();
+        assert_eq!(unsupported, vec![(7.0, "hold note"), (8.0, "Catmull slider")]);
+    }
+
+    #[test]
+    fn sliders_follow_the_inherited_slider_velocity() {
+        let chart = chart();
+        let first = chart.beatmap.circles[2].slider.as_ref().unwrap();
+        let second = chart.beatmap.circles[3].slider.as_ref().unwrap();
+
+        // 140 osu! pixels at 1.4 × 100 pixels per 0.5 s beat
+        assert_eq!(first.curve, CurveType::Linear);
+        assert_eq!(first.control_points, vec![Vec2::new(100.0 / 192.0, 0.0)]);
+        assert_eq!(first.spans, 1);
+        assert!((first.span_duration - 0.5).abs() < 1e-9);
+        assert!((first.tick_interval - 0.25).abs() < 1e-9);
+
+        // The -50 inherited point doubles the velocity
+        assert_eq!(second.curve, CurveType::Bezier);
+        assert_eq!(second.spans, 2);
+        assert!((second.span_duration - 0.25).abs() < 1e-9);
+    }
+
+    #[test]
+    fn difficulty_settings_map_to_approach_time_size_and_od() {
+        let difficulty = chart().beatmap.difficulty;
+        assert!((difficulty.approach_time - 0.6).abs() < 1e-9);
+        assert!((difficulty.circle_radius - 38.0).abs() < 1e-4);
+        assert_eq!(difficulty.overall_difficulty, 8.0);
+
+        // Old charts without an ApproachRate approach at their OverallDifficulty
+        let old_chart = CHART.replace("ApproachRate:9\n", "");
+        let difficulty = parse_osu(&old_chart, Path::new(".")).unwrap().beatmap.difficulty;
+        assert!((difficulty.approach_time - approach_time_from_ar(8.0)).abs() < 1e-9);
+
+        assert!((approach_time_from_ar(0.0) - 1.8).abs() < 1e-9);
+        assert!((approach_time_from_ar(5.0) - 1.2).abs() < 1e-9);
+        assert!((approach_time_from_ar(10.0) - 0.45).abs() < 1e-9);
+        assert!(circle_radius_from_cs(2.0) > circle_radius_from_cs(7.0));
+    }
+
+    #[test]
+    fn timing_points_set_the_beat_length_and_reset_the_velocity() {
+        let point = |line| parse_timing_point(line).unwrap();
+        let points = [
+            point("0,500,4,2,0,50,1,0"),
+            point("1000,-200,4,2,0,50,0,0"),
+            point("2000,250,4,2,0,50,1,0"),
+            point("3000,-25"),
+        ];
+
+        assert_eq!(timing_at(&[], 1.0), (0.5, 1.0));
+        assert_eq!(timing_at(&points, 0.5), (0.5, 1.0));
+        assert_eq!(timing_at(&points, 1.5), (0.5, 0.5));
+        assert_eq!(timing_at(&points, 2.5), (0.25, 1.0));
+        // Old charts omit the uninherited field, so every point sets the beat length
+        assert_eq!(timing_at(&points, 3.5).0, 0.25);
+    }
+
+    #[test]
+    fn malformed_charts_are_rejected() {
+        let with = |from: &str, to: &str| parse_osu(&CHART.replacen(from, to, 1), Path::new("."));
+
+        assert!(with("osu file format v14", "not a chart").is_err());
+        assert!(with("AudioFilename: audio.mp3", "").is_err());
+        assert!(with("CircleSize:4", "CircleSize:big").is_err());
+        assert!(with("1000,500,4,2,0,50,1,0", "1000").is_err());
+        assert!(with("256,192,1000,1,0,0:0:0:0:", "256,192").is_err());
+        assert!(with("L|356:192", "X|356:192").is_err());
+        assert!(with("L|356:192", "L|356").is_err());
+        assert!(with("100,100,4000,12,0,6000,0:0:0:0:", "100,100,4000,12").is_err());
+    }
+
+    #[test]
+    fn osz_archives_are_unpacked_into_a_folder() {
+        use std::io::Write;
+
+        let dir = std::env::temp_dir().join(format!("yum-osu-osz-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let archive_path = dir.join("set.osz");
+        let mut archive = zip::ZipWriter::new(File::create(&archive_path).unwrap());
+        archive.start_file("chart.osu", zip::write::SimpleFileOptions::default()).unwrap();
+        archive.write_all(CHART.as_bytes()).unwrap();
+        archive.finish().unwrap();
+
+        let target = dir.join("set");
+        let unpacked = unpack_osz(&archive_path, &target);
+        let mut charts = Vec::new();
+        collect_charts(&target, &mut charts);
+        let contents = fs::read_to_string(target.join("chart.osu"));
+        fs::remove_dir_all(&dir).unwrap();
+
+        unpacked.unwrap();
+        assert_eq!(charts.len(), 1);
+        assert_eq!(contents.unwrap(), CHART);
+    }
+}
@@ -0,0 +1,554 @@
+//! Circle placement for generated beatmaps.
+//!
+//! Consecutive circles are spaced by the time between their beats (distance snapping)
+//! and follow short recognisable shapes, so the cursor moves with the music. Onsets
+//! that start a sustained section become sliders lasting until it ends, and long gaps
+//! over a sustain or a build-up become spinners.
+
+use crate::constants::*;
+use crate::slider::{ curve_points, path_length };
+use crate::structs::{ Band, BeatmapCircle, CurveType, Onset, SliderPath, Sustain };
+use macroquad::math::Vec2;
+use rand::Rng;
+use std::f32::consts::{ PI, TAU };
+
+// How much streams bend per circle, in radians
+const STREAM_CURVE_MIN: f32 = 0.05;
+const STREAM_CURVE_MAX: f32 = 0.3;
+
+// Back-and-forth jumps turn back by this much less than a half turn, so they drift sideways
+const BACK_AND_FORTH_SPREAD: f32 = PI / 4.0;
+
+// Steps used to turn a circle away from the edge or from the circles before it
+const RESOLVE_ANGLE: f32 = PI / 9.0;
+const RESOLVE_STEPS: usize = 9;
+
+// Spacings tried, relative to the snapped distance, when no direction is clear
+const SPACING_STRETCH: [f32; 5] = [1.0, 0.85, 1.15, 0.7, 1.3];
+
+// A sustain may be detected this many seconds after the onset that starts it
+const SUSTAIN_TOLERANCE: f64 = 0.1;
+
+// How far generated arcs turn, in radians
+const ARC_ANGLE_MIN: f32 = 0.6;
+const ARC_ANGLE_MAX: f32 = 1.6;
+
+// How far the ends of generated Bézier sliders bend away from the straight line
+const BEZIER_BEND: f32 = 0.6;
+
+// Onsets per second a gap needs to be a build-up, even if none are mapped
+const BUILD_UP_MIN_RATE: f64 = 1.0;
+
+const SLIDER_CURVES: [CurveType; 3] = [
+    CurveType::Linear,
+    CurveType::PerfectCircle,
+    CurveType::Bezier,
+];
+
+/// A shape consecutive circles follow
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Pattern {
+    Stream, // Closely spaced circles along a gentle curve
+    Triangle,
+    Square,
+    BackAndForth, // Jumps that zigzag back and forth
+    Star, // Corners of a five-pointed star
+}
+
+const JUMP_PATTERNS: [Pattern; 4] = [
+    Pattern::Triangle,
+    Pattern::Square,
+    Pattern::BackAndForth,
+    Pattern::Star,
+];
+
+impl Pattern {
+    /// Moves before the next pattern is picked.
+    ///
+    /// Shapes stop one corner short of closing, so they never land on their first circle.
+    fn length(self) -> usize {
+        match self {
+            Pattern::Stream => usize::MAX,
+            Pattern::Triangle => 2,
+            Pattern::Square => 3,
+            Pattern::BackAndForth => 4,
+            Pattern::Star => 4,
+        }
+    }
+
+    /// Angle the direction of movement turns by at a step of the pattern
+    fn turn(self, step: usize, curve: f32) -> f32 {
+        match self {
+            Pattern::Stream => curve,
+            Pattern::Triangle => TAU / 3.0,
+            Pattern::Square => PI / 2.0,
+            Pattern::BackAndForth if step.is_multiple_of(2) => PI - BACK_AND_FORTH_SPREAD,
+            Pattern::BackAndForth => -(PI - BACK_AND_FORTH_SPREAD),
+            Pattern::Star => 0.8 * PI,
+        }
+    }
+}
+
+/// Place a circle for every onset inside the unit disk.
+///
+/// Beats closer than `STREAM_GAP` become streams, slower ones jumps that follow a
+/// randomly picked shape. Stronger onsets get bigger jumps. Onsets at the start of a
+/// sustain become sliders with ticks every `tick_interval`, and the next circle is
+/// spaced from where the slider ends. A spinner in the centre is placed for every
+/// start and end time in `spinners`, which must fall between the onsets.
+pub fn place_circles(
+    onsets: &[Onset],
+    sustains: &[Sustain],
+    spinners: &[(f64, f64)],
+    tick_interval: f64,
+    rng: &mut impl Rng
+) -> Vec<BeatmapCircle> {
+    let mut circles: Vec<BeatmapCircle> = Vec::with_capacity(onsets.len() + spinners.len());
+    // Where the circle of each onset was placed
+    let mut heads: Vec<Vec2> = Vec::with_capacity(onsets.len());
+    let mut spinners = spinners.iter().copied().peekable();
+    let mut direction = Vec2::from_angle(rng.gen_range(0.0..TAU));
+    let mut pattern = Pattern::Stream;
+    let mut step = 0;
+    let mut winding = 1.0;
+    let mut curve = 0.0;
+    // Where and when the previous circle or slider ended
+    let mut previous: Option<(Vec2, f64)> = None;
+
+    for (index, onset) in onsets.iter().enumerate() {
+        while let Some((start, end)) = spinners.next_if(|&(start, _)| start < onset.time) {
+            circles.push(spinner(start, end));
+            previous = Some((Vec2::ZERO, end));
+        }
+
+        let position = match previous {
+            None => Vec2::from_angle(rng.gen_range(0.0..TAU)) * rng.gen_range(0.0..0.5),
+            Some((previous_end, previous_time)) => {
+                let gap = onset.time - previous_time;
+                let streaming = gap < STREAM_GAP;
+                if streaming != (pattern == Pattern::Stream) || step >= pattern.length() {
+                    pattern = if streaming {
+                        Pattern::Stream
+                    } else {
+                        JUMP_PATTERNS[rng.gen_range(0..JUMP_PATTERNS.len())]
+                    };
+                    step = 0;
+                    winding = if rng.gen_bool(0.5) { 1.0 } else { -1.0 };
+                    curve = rng.gen_range(STREAM_CURVE_MIN..STREAM_CURVE_MAX);
+                }
+
+                // Spaced from the previous end by distance snapping, clear of the circles before
+                let avoid: Vec<Vec2> = (index.saturating_sub(OVERLAP_HISTORY)..index - 1)
+                    .filter(|&earlier| onset.time - onsets[earlier].time >= OVERLAP_MIN_GAP)
+                    .map(|earlier| heads[earlier])
+                    .collect();
+                let turn = winding * pattern.turn(step, curve);
+                let heading = Vec2::from_angle(turn).rotate(direction);
+                let spacing = jump_spacing(gap, onset.strength);
+                let position = next_position(previous_end, heading, spacing, &avoid);
+
+                direction = (position - previous_end).try_normalize().unwrap_or(heading);
+                step += 1;
+                position
+            }
+        };
+
+        let next_time = onsets
+            .get(index + 1)
+            .map(|next| next.time)
+            .into_iter()
+            .chain(spinners.peek().map(|&(start, _)| start))
+            .reduce(f64::min);
+        let slider = slide_duration(onset.time, next_time, sustains)
+            .map(|duration| place_slider(position, direction, duration, tick_interval, rng));
+        previous = Some(match &slider {
+            Some(slider) => {
+                let points = slider.points(position);
+                let (end, before_end) = if slider.spans.is_multiple_of(2) {
+                    (points[0], points[1])
+                } else {
+                    (points[points.len() - 1], points[points.len() - 2])
+                };
+                direction = (end - before_end).try_normalize().unwrap_or(direction);
+                (end, onset.time + slider.duration())
+            }
+            None => (position, onset.time),
+        });
+
+        circles.push(BeatmapCircle {
+            time: onset.time,
+            x: position.x,
+            y: position.y,
+            band: onset.band,
+            strength: onset.strength,
+            slider,
+            spinner_duration: None,
+        });
+        heads.push(position);
+    }
+    circles.extend(spinners.map(|(start, end)| spinner(start, end)));
+
+    circles
+}
+
+/// A spinner in the playfield centre. It has no onset of its own, so it is given
+/// the band sustains are detected in.
+fn spinner(start: f64, end: f64) -> BeatmapCircle {
+    BeatmapCircle {
+        time: start,
+        x: 0.0,
+        y: 0.0,
+        band: Band::Kick,
+        strength: 1.0,
+        slider: None,
+        spinner_duration: Some(end - start),
+    }
+}
+
+/// Start and end times of the spinners in the gaps between the `onsets` a level
+/// keeps.
+///
+/// A gap gets a spinner when, after a lead-in and before time to recover, at least
+/// `SPINNER_MIN_DURATION` is left and that stretch is mostly sustained, or is a
+/// build-up: enough of `all_onsets` the level left out, getting stronger toward the
+/// end. Silent breaks stay empty.
+pub fn spinner_sections(
+    onsets: &[Onset],
+    all_onsets: &[Onset],
+    sustains: &[Sustain]
+) -> Vec<(f64, f64)> {
+    onsets
+        .windows(2)
+        .filter_map(|pair| {
+            let start = pair[0].time + SPINNER_LEAD_IN;
+            let end = pair[1].time - SPINNER_RECOVERY;
+            let duration = end - start;
+            if duration < SPINNER_MIN_DURATION {
+                return None;
+            }
+
+            let sustained: f64 = sustains
+                .iter()
+                .map(|sustain| (sustain.end.min(end) - sustain.start.max(start)).max(0.0))
+                .sum();
+            let inside: Vec<&Onset> = all_onsets
+                .iter()
+                .filter(|onset| onset.time > start && onset.time < end)
+                .collect();
+            let middle = start + duration / 2.0;
+            let mean_strength = |half: Vec<&&Onset>| {
+                half.iter().map(|onset| onset.strength).sum::<f32>() / (half.len().max(1) as f32)
+            };
+            let (first, second): (Vec<&&Onset>, Vec<&&Onset>) = inside
+                .iter()
+                .partition(|onset| onset.time < middle);
+            let builds_up =
+                (inside.len() as f64) >= duration * BUILD_UP_MIN_RATE &&
+                mean_strength(second) > mean_strength(first);
+
+            (sustained >= duration * SPINNER_MIN_SUSTAIN || builds_up).then_some((start, end))
+        })
+        .collect()
+}
+
+/// How long a slider starting at an onset lasts: until its sustain ends, leaving
+/// room before the next onset. None when that is too short for a slider.
+fn slide_duration(time: f64, next_time: Option<f64>, sustains: &[Sustain]) -> Option<f64> {
+    let sustain = sustains
+        .iter()
+        .find(|sustain| sustain.start <= time + SUSTAIN_TOLERANCE && sustain.end > time)?;
+    let end = next_time.map_or(sustain.end, |next| sustain.end.min(next - SLIDER_END_GAP));
+    let duration = end - time;
+    (duration >= SLIDER_MIN_DURATION).then_some(duration)
+}
+
+/// A slider from `head` lasting `duration` seconds with a random curve that stays on
+/// the playfield, leaving in the direction of movement when there is room.
+///
+/// Long slides turn back and forth so the ball keeps a steady speed.
+fn place_slider(
+    head: Vec2,
+    direction: Vec2,
+    duration: f64,
+    tick_interval: f64,
+    rng: &mut impl Rng
+) -> SliderPath {
+    let spans = (duration / SLIDER_MAX_SPAN).ceil().max(1.0) as u32;
+    let span_duration = duration / (spans as f64);
+    let length = (SLIDER_VELOCITY * (span_duration as f32)).min(SLIDER_MAX_LENGTH);
+    let curve = SLIDER_CURVES[rng.gen_range(0..SLIDER_CURVES.len())];
+    let side = if rng.gen_bool(0.5) { 1.0 } else { -1.0 };
+    let arc_angle = rng.gen_range(ARC_ANGLE_MIN..ARC_ANGLE_MAX);
+
+    let fits = |control_points: &[Vec2]| {
+        let mut points = vec![head];
+        points.extend(control_points);
+        curve_points(curve, &points)
+            .iter()
+            .all(|point| point.length() <= PLAYFIELD_LIMIT)
+    };
+    let turns = (0..=RESOLVE_STEPS).flat_map(|turn| [1.0, -1.0].map(|sign| sign * (turn as f32)));
+    let control_points = turns
+        .map(|turn| {
+            let heading = Vec2::from_angle(turn * RESOLVE_ANGLE).rotate(direction);
+            slider_control_points(curve, head, heading, length, side, arc_angle)
+        })
+        .find(|control_points| fits(control_points));
+
+    match control_points {
+        Some(control_points) => {
+            let mut points = vec![head];
+            points.extend(&control_points);
+            SliderPath {
+                curve,
+                length: path_length(&curve_points(curve, &points)).min(length),
+                control_points,
+                spans,
+                span_duration,
+                tick_interval,
+            }
+        }
+        // Nothing fits, so slide straight toward the centre, which always has room
+        None => {
+            let toward_center = (-head).try_normalize().unwrap_or(direction);
+            SliderPath {
+                curve: CurveType::Linear,
+                control_points: vec![head + toward_center * length],
+                length,
+                spans,
+                span_duration,
+                tick_interval,
+            }
+        }
+    }
+}
+
+/// Control points after the head of a slider curve about `length` long, leaving
+/// along `heading` and bending to one `side`
+fn slider_control_points(
+    curve: CurveType,
+    head: Vec2,
+    heading: Vec2,
+    length: f32,
+    side: f32,
+    arc_angle: f32
+) -> Vec<Vec2> {
+    match curve {
+        CurveType::Linear => vec![head + heading * length],
+        CurveType::PerfectCircle => {
+            // The arc leaves along the heading around a centre to one side of the head
+            let radius = length / arc_angle;
+            let center = head + heading.perp() * side * radius;
+            [0.5, 1.0]
+                .map(|share| {
+                    center + Vec2::from_angle(side * arc_angle * share).rotate(head - center)
+                })
+                .to_vec()
+        }
+        CurveType::Bezier => {
+            // A bow: leave bent to one side and come back in bent to the other
+            let end = head + heading * length * 0.85;
+            vec![
+                head + Vec2::from_angle(side * BEZIER_BEND).rotate(heading) * length * 0.4,
+                end - Vec2::from_angle(-side * BEZIER_BEND).rotate(heading) * length * 0.4,
+                end
+            ]
+        }
+    }
+}
+
+/// Distance from the previous circle, growing with the time since its beat and the onset strength
+fn jump_spacing(gap: f64, strength: f32) -> f32 {
+    let scale = JUMP_SCALE_MIN + (JUMP_SCALE_MAX - JUMP_SCALE_MIN) * strength.clamp(0.0, 1.0);
+    ((gap as f32) * DISTANCE_SNAP * scale).clamp(MIN_SPACING, MAX_SPACING)
+}
+
+/// The point `spacing` away from `previous`, turned as little as possible away from
+/// `heading` to stay on the playfield and clear of the `avoid` circles.
+///
+/// When no direction is clear, the one with the most room is used.
+fn next_position(previous: Vec2, heading: Vec2, spacing: f32, avoid: &[Vec2]) -> Vec2 {
+    let clearance = |position: Vec2| {
+        avoid
+            .iter()
+            .map(|other| other.distance(position))
+            .fold(f32::INFINITY, f32::min)
+    };
+
+    let mut best: Option<(Vec2, f32)> = None;
+    for stretch in SPACING_STRETCH {
+        for turn in 0..=RESOLVE_STEPS {
+            for side in [1.0, -1.0] {
+                if turn == 0 && side < 0.0 {
+                    continue;
+                }
+                let angle = side * (turn as f32) * RESOLVE_ANGLE;
+                let candidate = previous + Vec2::from_angle(angle).rotate(heading) * spacing * stretch;
+                if candidate.length() > PLAYFIELD_LIMIT {
+                    continue;
+                }
+                let room = clearance(candidate);
+                if room >= OVERLAP_DISTANCE {
+                    return candidate;
+                }
+                if stretch == 1.0 && best.is_none_or(|(_, best_room)| room > best_room) {
+                    best = Some((candidate, room));
+                }
+            }
+        }
+    }
+
+    // A jump longer than the playfield allows from here is shortened at the edge
+    best.map_or_else(
+        || (previous + heading * spacing).clamp_length_max(PLAYFIELD_LIMIT),
+        |(position, _)| position
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::beatmap::placement_rng;
+    use crate::structs::Band;
+
+    fn onsets(gaps: &[f64], strength: f32) -> Vec<Onset> {
+        let mut time = 1.0;
+        gaps.iter()
+            .map(|gap| {
+                time += gap;
+                Onset { time, band: Band::Kick, strength }
+            })
+            .collect()
+    }
+
+    fn position(circle: &BeatmapCircle) -> Vec2 {
+        Vec2::new(circle.x, circle.y)
+    }
+
+    fn mean_spacing(circles: &[BeatmapCircle]) -> f32 {
+        let distances: Vec<f32> = circles
+            .windows(2)
+            .map(|pair| position(&pair[0]).distance(position(&pair[1])))
+            .collect();
+        distances.iter().sum::<f32>() / (distances.len() as f32)
+    }
+
+    #[test]
+    fn circles_stay_on_the_playfield() {
+        let gaps: Vec<f64> = (0..200).map(|i| [0.125, 0.5, 1.0, 0.25, 2.0][i % 5]).collect();
+        for seed in 0..20 {
+            let onsets = onsets(&gaps, 1.0);
+            let circles = place_circles(&onsets, &[], &[], 0.5, &mut placement_rng(seed));
+
+            assert_eq!(circles.len(), gaps.len());
+            for circle in &circles {
+                assert!(position(circle).length() <= PLAYFIELD_LIMIT + 1e-4, "seed {}", seed);
+            }
+        }
+    }
+
+    /// Circles for onsets at `gaps`, with no sustains or spinners
+    fn place(gaps: &[f64], strength: f32, seed: u64) -> Vec<BeatmapCircle> {
+        place_circles(&onsets(gaps, strength), &[], &[], 0.5, &mut placement_rng(seed))
+    }
+
+    #[test]
+    fn spacing_follows_the_time_between_beats() {
+        let stream = place(&[0.125; 32], 0.5, 1);
+        let jumps = place(&[0.5; 32], 0.5, 1);
+
+        assert!((mean_spacing(&stream) - jump_spacing(0.125, 0.5)).abs() < 1e-4);
+        assert!((mean_spacing(&jumps) - jump_spacing(0.5, 0.5)).abs() < 1e-4);
+        assert!(mean_spacing(&jumps) > 3.0 * mean_spacing(&stream));
+    }
+
+    #[test]
+    fn stronger_onsets_jump_further() {
+        let weak = place(&[0.5; 32], 0.1, 2);
+        let strong = place(&[0.5; 32], 1.0, 2);
+
+        assert!(mean_spacing(&strong) > 2.0 * mean_spacing(&weak));
+    }
+
+    #[test]
+    fn jumps_rarely_overlap_the_circles_before_them() {
+        // The playfield is small for jumps this big, so a few circles may be boxed in
+        let mut placed = 0;
+        let mut overlapping = 0;
+        for seed in 0..20 {
+            let onsets = onsets(&[0.5; 64], 0.6);
+            let circles = place_circles(&onsets, &[], &[], 0.5, &mut placement_rng(seed));
+
+            for (index, circle) in circles.iter().enumerate().skip(2) {
+                placed += 1;
+                let overlaps = circles[index.saturating_sub(OVERLAP_HISTORY)..index - 1]
+                    .iter()
+                    .any(|earlier| position(earlier).distance(position(circle)) < OVERLAP_DISTANCE);
+                overlapping += overlaps as usize;
+            }
+        }
+
+        assert!(overlapping * 100 <= placed, "{} of {} circles overlap", overlapping, placed);
+    }
+
+    #[test]
+    fn sustained_onsets_become_sliders_on_the_playfield() {
+        let onsets = onsets(&[1.0; 40], 0.6);
+        // Every other onset starts a sustain lasting most of the way to the next one
+        let sustains: Vec<Sustain> = onsets
+            .iter()
+            .step_by(2)
+            .map(|onset| Sustain { start: onset.time + 0.05, end: onset.time + 0.9 })
+            .collect();
+
+        for seed in 0..20 {
+            let circles = place_circles(&onsets, &sustains, &[], 0.25, &mut placement_rng(seed));
+
+            assert_eq!(circles.iter().filter(|circle| circle.slider.is_some()).count(), 20);
+            for (index, circle) in circles.iter().enumerate() {
+                let Some(slider) = &circle.slider else {
+                    continue;
+                };
+                let end = circle.time + slider.duration();
+                assert!(end <= circles[index + 1].time - SLIDER_END_GAP + 1e-9);
+                assert_eq!(slider.spans, 2);
+                for point in slider.points(position(circle)) {
+                    assert!(point.length() <= PLAYFIELD_LIMIT + 1e-4, "seed {}", seed);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn spinners_fill_sustained_gaps_and_build_ups_but_not_silence() {
+        // Gaps of four seconds after the first, third and fifth onset, the last one silent
+        let level = onsets(&[0.5, 4.0, 0.5, 4.0, 0.5, 4.0, 0.5], 1.0);
+        let sustains = [Sustain { start: 2.0, end: 5.0 }];
+        // Hi-hats getting louder through the second gap
+        let build_up: Vec<Onset> = (0..12)
+            .map(|i| Onset {
+                time: 6.25 + (i as f64) * 0.25,
+                band: Band::HiHat,
+                strength: 0.1 + (i as f32) * 0.05,
+            })
+            .collect();
+        let mut all_onsets = level.clone();
+        all_onsets.extend(&build_up);
+
+        let spinners = spinner_sections(&level, &all_onsets, &sustains);
+        assert_eq!(spinners, vec![(2.0, 4.75), (6.5, 9.25)]);
+
+        // Spinners sit in the centre between the circles, which keep their order
+        let circles = place_circles(&level, &[], &spinners, 0.5, &mut placement_rng(4));
+        assert_eq!(circles.len(), level.len() + 2);
+        assert!(circles.windows(2).all(|pair| pair[0].time < pair[1].time));
+        let placed: Vec<(f64, f64)> = circles
+            .iter()
+            .filter_map(|circle| Some((circle.time, circle.time + circle.spinner_duration?)))
+            .collect();
+        assert_eq!(placed, spinners);
+        assert!(circles.iter().filter(|circle| circle.spinner_duration.is_some()).all(|circle| {
+            position(circle) == Vec2::ZERO
+        }));
+    }
+}
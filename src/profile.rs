@@ -0,0 +1,118 @@
+use crate::constants::*;
+use crate::structs::{ BestPlay, Profile };
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+impl Profile {
+    /// Count a finished play and keep it if it is the best on its beatmap
+    pub fn record_play(&mut self, play: BestPlay, hits: u32, play_time: f64) {
+        self.play_count += 1;
+        self.total_hits += hits as u64;
+        self.play_time += play_time;
+
+        match self.best_plays.iter().position(|best| best.beatmap_hash == play.beatmap_hash) {
+            Some(index) if self.best_plays[index].pp >= play.pp => {}
+            Some(index) => {
+                self.best_plays[index] = play;
+            }
+            None => self.best_plays.push(play),
+        }
+        self.best_plays.sort_by(|a, b| b.pp.total_cmp(&a.pp));
+        self.best_plays.truncate(PROFILE_BEST_PLAYS);
+    }
+
+    /// The best plays summed with decreasing weights, so the top plays count the most
+    pub fn total_pp(&self) -> f64 {
+        self.best_plays
+            .iter()
+            .enumerate()
+            .map(|(i, play)| play.pp * PROFILE_WEIGHT_DECAY.powi(i as i32))
+            .sum()
+    }
+}
+
+/// Location of the profile file, next to the settings
+pub fn profile_path() -> Option<PathBuf> {
+    dirs::config_dir().map(|dir| dir.join(SETTINGS_DIR_NAME).join(PROFILE_FILE_NAME))
+}
+
+/// Load the profile, starting a fresh one when it is missing or unreadable
+pub fn load_profile() -> Profile {
+    let Some(path) = profile_path() else {
+        return Profile::default();
+    };
+
+    match fs::read_to_string(&path) {
+        Ok(contents) =>
+            match toml::from_str(&contents) {
+                Ok(profile) => profile,
+                Err(err) => {
+                    println!("Ignoring invalid profile {}: {}", path.display(), err);
+                    Profile::default()
+                }
+            }
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Profile::default(),
+        Err(err) => {
+            println!("Ignoring unreadable profile {}: {}", path.display(), err);
+            Profile::default()
+        }
+    }
+}
+
+/// Write the profile file, creating its directory if needed
+pub fn save_profile(profile: &Profile) -> io::Result<()> {
+    let path = profile_path().ok_or_else(||
+        io::Error::new(io::ErrorKind::NotFound, "no user config directory")
+    )?;
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let contents = toml
+        ::to_string_pretty(profile)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+    fs::write(path, contents)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::structs::Grade;
+
+    fn play(beatmap_hash: &str, pp: f64) -> BestPlay {
+        BestPlay {
+            title: format!("Song {}", beatmap_hash),
+            beatmap_hash: beatmap_hash.to_string(),
+            stars: 3.0,
+            pp,
+            score: 100_000,
+            accuracy: 0.95,
+            grade: Grade::A,
+        }
+    }
+
+    #[test]
+    fn only_the_best_play_of_a_beatmap_counts() {
+        let mut profile = Profile::default();
+        profile.record_play(play("a", 100.0), 200, 90.0);
+        profile.record_play(play("b", 50.0), 100, 60.0);
+        profile.record_play(play("a", 80.0), 180, 90.0);
+        profile.record_play(play("b", 120.0), 150, 60.0);
+
+        let pps: Vec<f64> = profile.best_plays.iter().map(|play| play.pp).collect();
+        assert_eq!(pps, vec![120.0, 100.0]);
+        assert_eq!(profile.play_count, 4);
+        assert_eq!(profile.total_hits, 630);
+        assert_eq!(profile.play_time, 300.0);
+        assert!((profile.total_pp() - (120.0 + 100.0 * PROFILE_WEIGHT_DECAY)).abs() < 1e-9);
+    }
+
+    #[test]
+    fn profiles_survive_a_round_trip() {
+        let mut profile = Profile::default();
+        profile.record_play(play("ffffffffffffffff", 123.5), 300, 120.5);
+
+        let contents = toml::to_string_pretty(&profile).unwrap();
+        assert_eq!(toml::from_str::<Profile>(&contents).unwrap(), profile);
+    }
+}
@@ -0,0 +1,408 @@
+//! Star rating of beatmaps, from aim and speed strain like osu!, and the performance
+//! points a play on them is worth.
+//!
+//! Every circle adds to two strains that decay over time: aim grows with the distance
+//! from where the previous circle or slider ended in circle diameters, speed with how
+//! soon the circle follows it. Spinners add to neither. The peaks of each strain per
+//! section of the map are summed with decreasing weights, so the hardest parts count
+//! the most.
+
+use crate::constants::*;
+use crate::game::calculate_spawn_radius;
+use crate::simulation::initialize_circles;
+use crate::structs::{ Beatmap, BeatmapDifficulty, Circle, PlayStats, StarRating, VisualizingState };
+use macroquad::math::Vec2;
+
+// Shortest time between circles the strains consider, in seconds
+const MIN_DELTA_TIME: f64 = 0.025;
+
+// Length of the sections whose peak strains are summed, in seconds
+const SECTION_LENGTH: f64 = 0.4;
+
+// Weight of each next hardest section
+const SECTION_WEIGHT_DECAY: f64 = 0.9;
+
+// Share of the strain left after one second
+const AIM_DECAY_BASE: f64 = 0.15;
+const SPEED_DECAY_BASE: f64 = 0.3;
+
+// Scale of each strain and of the stars they are worth
+const AIM_MULTIPLIER: f64 = 2.0;
+const SPEED_MULTIPLIER: f64 = 0.4;
+const STAR_SCALING: f64 = 0.17;
+
+// Extra stars per second the approach time is shorter than the default
+const READING_BONUS: f64 = 0.1;
+
+/// Star rating of a beatmap at its own difficulty.
+///
+/// The circles are laid out on the playfield of the default window, so the rating
+/// does not depend on the window size.
+pub fn beatmap_star_rating(beatmap: &Beatmap) -> StarRating {
+    star_rating_at(beatmap, &beatmap.difficulty)
+}
+
+/// Star rating of a rated beatmap played at `difficulty`, which differs from its own
+/// when the approach rate or circle size is overridden
+pub fn play_star_rating(beatmap: &Beatmap, difficulty: &BeatmapDifficulty) -> Option<StarRating> {
+    let rating = beatmap.star_rating?;
+    if *difficulty == beatmap.difficulty {
+        return Some(rating);
+    }
+    Some(star_rating_at(beatmap, difficulty))
+}
+
+/// Star rating of the circles of a beatmap at a difficulty
+fn star_rating_at(beatmap: &Beatmap, difficulty: &BeatmapDifficulty) -> StarRating {
+    let spawn_radius = calculate_spawn_radius(
+        DEFAULT_WINDOW_WIDTH as f32,
+        DEFAULT_WINDOW_HEIGHT as f32
+    );
+    let circles = initialize_circles(
+        &beatmap.circles,
+        spawn_radius,
+        Vec2::ZERO,
+        difficulty,
+        0.0
+    );
+    star_rating(&circles, difficulty.approach_time)
+}
+
+/// Aim and speed strain of a list of circles and the star rating they add up to
+pub fn star_rating(circles: &[Circle], approach_time: f64) -> StarRating {
+    let mut aim = StrainSkill::new(AIM_DECAY_BASE, AIM_MULTIPLIER);
+    let mut speed = StrainSkill::new(SPEED_DECAY_BASE, SPEED_MULTIPLIER);
+    let circles: Vec<&Circle> = circles
+        .iter()
+        .filter(|circle| circle.spinner.is_none())
+        .collect();
+
+    for pair in circles.windows(2) {
+        let (previous, current) = (pair[0], pair[1]);
+        let delta_time = (current.hit_time - previous.hit_time).max(MIN_DELTA_TIME);
+        let travel_time = (current.hit_time - previous.end_time()).max(MIN_DELTA_TIME);
+        let diameter = (2.0 * current.max_radius).max(1.0) as f64;
+        let jump = (current.position.distance(previous.end_position()) as f64) / diameter;
+
+        aim.add(current.hit_time, delta_time, jump / travel_time);
+        speed.add(current.hit_time, delta_time, 1.0 / delta_time);
+    }
+
+    let aim = aim.difficulty().sqrt() * STAR_SCALING;
+    let speed = speed.difficulty().sqrt() * STAR_SCALING;
+    let reading = 1.0 + READING_BONUS * (APPROACH_TIME - approach_time).max(0.0);
+    let stars = if circles.len() < 2 {
+        0.0
+    } else {
+        (aim + speed + (aim - speed).abs() / 2.0) * reading
+    };
+
+    StarRating { aim, speed, stars }
+}
+
+/// Performance points of a play, following the osu! performance formula.
+///
+/// Aim and speed are worth more on harder and longer maps and less with misses, a
+/// broken combo or low accuracy. Accuracy is worth more at a higher OD. The combo is
+/// compared to `max_combo`, the combo of a play that hits everything.
+pub fn performance_points(
+    rating: &StarRating,
+    stats: &PlayStats,
+    circle_count: usize,
+    max_combo: u32,
+    overall_difficulty: f32
+) -> f64 {
+    if circle_count == 0 || max_combo == 0 {
+        return 0.0;
+    }
+    let circles = circle_count as f64;
+    let od = overall_difficulty as f64;
+    let accuracy = stats.accuracy();
+    let miss_penalty = (0.97f64).powi(stats.count_miss as i32);
+    let combo_scaling = ((stats.max_combo as f64) / (max_combo as f64)).powf(0.8).min(1.0);
+    let length_bonus =
+        0.95 +
+        0.4 * (circles / 2000.0).min(1.0) +
+        (if circles > 2000.0 { (circles / 2000.0).log10() * 0.5 } else { 0.0 });
+    let skill_value = |stars: f64| {
+        (5.0 * (stars / 0.0675).max(1.0) - 4.0).powi(3) / 100_000.0 *
+            length_bonus *
+            miss_penalty *
+            combo_scaling
+    };
+
+    let aim = skill_value(rating.aim) * accuracy * (0.98 + od.powi(2) / 2500.0);
+    let speed =
+        skill_value(rating.speed) *
+        (0.95 + od.powi(2) / 750.0) *
+        accuracy.powf((14.5 - od.max(8.0)) / 2.0);
+    let accuracy_value =
+        (1.52163f64).powf(od) * accuracy.powi(24) * 2.83 * (circles / 1000.0).powf(0.3).min(1.15);
+
+    (aim.powf(1.1) + speed.powf(1.1) + accuracy_value.powf(1.1)).powf(1.0 / 1.1) * 1.12
+}
+
+/// Performance points of a play, at the difficulty it was played with, if the beatmap
+/// is rated
+pub fn play_performance_points(vis_state: &VisualizingState) -> Option<f64> {
+    let beatmap = &vis_state.beatmap;
+    let simulation = &vis_state.simulation;
+    vis_state.rating.as_ref().map(|rating| {
+        performance_points(
+            rating,
+            &simulation.stats,
+            beatmap.circles.len(),
+            max_combo(&simulation.circles),
+            beatmap.difficulty.overall_difficulty
+        )
+    })
+}
+
+/// Combo of a play that hits everything: one for every circle and spinner, and one for
+/// the head and every checkpoint of a slider
+pub fn max_combo(circles: &[Circle]) -> u32 {
+    circles
+        .iter()
+        .map(|circle| {
+            1 + circle.slider.as_ref().map_or(0, |slider| slider.checkpoints.len() as u32)
+        })
+        .sum()
+}
+
+/// A strain that decays over time, with its peak in every section of the map
+struct StrainSkill {
+    decay_base: f64,
+    multiplier: f64,
+    strain: f64,
+    section_end: Option<f64>,
+    section_peaks: Vec<f64>,
+}
+
+impl StrainSkill {
+    fn new(decay_base: f64, multiplier: f64) -> Self {
+        StrainSkill {
+            decay_base,
+            multiplier,
+            strain: 0.0,
+            section_end: None,
+            section_peaks: Vec::new(),
+        }
+    }
+
+    /// Add the strain of a circle hit at `time`, `delta_time` after the previous one
+    fn add(&mut self, time: f64, delta_time: f64, value: f64) {
+        let section_end = *self.section_end.get_or_insert(time + SECTION_LENGTH);
+        if time > section_end {
+            // Start a new section, its peak is at least the strain decayed until its start
+            let sections = ((time - section_end) / SECTION_LENGTH).ceil();
+            let new_end = section_end + sections * SECTION_LENGTH;
+            let start = new_end - SECTION_LENGTH;
+            self.section_peaks.push(
+                self.strain * self.decay_base.powf(start - (time - delta_time))
+            );
+            self.section_end = Some(new_end);
+        }
+        if self.section_peaks.is_empty() {
+            self.section_peaks.push(0.0);
+        }
+
+        self.strain = self.strain * self.decay_base.powf(delta_time) + value * self.multiplier;
+        let peak = self.section_peaks.last_mut().expect("a section is open");
+        *peak = peak.max(self.strain);
+    }
+
+    /// Peak strains of every section, hardest first, summed with decreasing weights
+    fn difficulty(&self) -> f64 {
+        let mut peaks = self.section_peaks.clone();
+        peaks.sort_by(|a, b| b.total_cmp(a));
+        peaks
+            .iter()
+            .zip(std::iter::successors(Some(1.0), |weight| Some(weight * SECTION_WEIGHT_DECAY)))
+            .map(|(peak, weight)| peak * weight)
+            .sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::beatmap::{ circles_from_analysis, placement_rng, BEATMAP_VERSION };
+    use crate::structs::{
+        AnalysisParams,
+        Band,
+        BeatmapMetadata,
+        BeatmapCircle,
+        BeatmapDifficulty,
+        CurveType,
+        DifficultyLevel,
+        Onset,
+        SliderPath,
+        SongAnalysis,
+    };
+
+    /// Circles on a line, `spacing` pixels and `gap` seconds apart
+    fn line(count: usize, gap: f64, spacing: f32) -> Vec<Circle> {
+        (0..count)
+            .map(|i| Circle {
+                position: Vec2::new(((i % 8) as f32) * spacing, 0.0),
+                spawn_time: (i as f64) * gap,
+                hit_time: 1.0 + (i as f64) * gap,
+                max_radius: CIRCLE_MAX_RADIUS,
+                hit: false,
+                missed: false,
+                slider: None,
+                spinner: None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn an_empty_map_has_no_stars() {
+        assert_eq!(star_rating(&[], APPROACH_TIME).stars, 0.0);
+        assert_eq!(star_rating(&line(1, 0.5, 100.0), APPROACH_TIME).stars, 0.0);
+    }
+
+    #[test]
+    fn bigger_jumps_need_more_aim() {
+        let small = star_rating(&line(64, 0.5, 50.0), APPROACH_TIME);
+        let big = star_rating(&line(64, 0.5, 200.0), APPROACH_TIME);
+
+        assert!(big.aim > small.aim);
+        assert!((big.speed - small.speed).abs() < 1e-9);
+        assert!(big.stars > small.stars);
+    }
+
+    #[test]
+    fn faster_circles_need_more_speed() {
+        let slow = star_rating(&line(64, 0.5, 50.0), APPROACH_TIME);
+        let fast = star_rating(&line(64, 0.125, 50.0), APPROACH_TIME);
+
+        assert!(fast.speed > slow.speed);
+        assert!(fast.stars > slow.stars);
+    }
+
+    #[test]
+    fn harder_levels_have_more_stars() {
+        // Two minutes at 120 BPM with kicks, snares and hi-hats
+        let onsets: Vec<Onset> = (0..960)
+            .map(|i| {
+                let band = [Band::Kick, Band::HiHat, Band::Snare, Band::HiHat][i % 4];
+                let strength = if i % 8 == 0 { 1.0 } else { 0.2 };
+                Onset { time: 1.0 + (i as f64) * 0.125, band, strength }
+            })
+            .collect();
+        let analysis = SongAnalysis { onsets, sustains: Vec::new(), beat_grid: None };
+
+        let stars: Vec<f64> = DifficultyLevel::ALL.iter()
+            .map(|&level| {
+                let circles = initialize_circles(
+                    &circles_from_analysis(&analysis, level, &mut placement_rng(5)),
+                    200.0,
+                    Vec2::ZERO,
+                    &level.difficulty(),
+                    0.0
+                );
+                star_rating(&circles, level.difficulty().approach_time).stars
+            })
+            .collect();
+
+        assert!(stars.windows(2).all(|pair| pair[0] < pair[1]), "stars: {:?}", stars);
+    }
+
+    #[test]
+    fn overridden_difficulty_is_rated() {
+        let onsets: Vec<Onset> = (0..256)
+            .map(|i| Onset { time: 1.0 + (i as f64) * 0.25, band: Band::Kick, strength: 1.0 })
+            .collect();
+        let analysis = SongAnalysis { onsets, sustains: Vec::new(), beat_grid: None };
+        let level = DifficultyLevel::Normal;
+        let mut beatmap = Beatmap {
+            version: BEATMAP_VERSION,
+            metadata: BeatmapMetadata {
+                title: "Rated".to_string(),
+                audio_path: String::new(),
+                level: Some(level),
+                chart_path: None,
+            },
+            difficulty: level.difficulty(),
+            beat_grid: None,
+            analysis: Some(AnalysisParams::default()),
+            audio_hash: 0,
+            seed: Some(5),
+            star_rating: None,
+            circles: circles_from_analysis(&analysis, level, &mut placement_rng(5)),
+        };
+        let own = beatmap.difficulty.clone();
+        assert_eq!(play_star_rating(&beatmap, &own), None);
+
+        let rating = beatmap_star_rating(&beatmap);
+        beatmap.star_rating = Some(rating);
+        let harder = BeatmapDifficulty {
+            approach_time: 0.6,
+            circle_radius: own.circle_radius / 2.0,
+            ..own.clone()
+        };
+        assert_eq!(play_star_rating(&beatmap, &own), Some(rating));
+        assert!(play_star_rating(&beatmap, &harder).unwrap().stars > rating.stars);
+    }
+
+    fn full_combo(circles: u32) -> PlayStats {
+        PlayStats { count_300: circles, max_combo: circles, combo: circles, ..Default::default() }
+    }
+
+    #[test]
+    fn better_plays_on_harder_maps_are_worth_more() {
+        let rating = StarRating { aim: 2.0, speed: 1.5, stars: 4.0 };
+        let harder = StarRating { aim: 2.5, speed: 2.0, stars: 5.0 };
+        let perfect = full_combo(500);
+        let inaccurate = PlayStats { count_300: 450, count_100: 50, ..full_combo(500) };
+        let missed = PlayStats { count_300: 490, count_miss: 10, max_combo: 250, ..full_combo(500) };
+
+        let pp = performance_points(&rating, &perfect, 500, 500, 5.0);
+        assert!(pp > 0.0);
+        assert!(performance_points(&harder, &perfect, 500, 500, 5.0) > pp);
+        assert!(performance_points(&rating, &perfect, 500, 500, 8.0) > pp);
+        assert!(performance_points(&rating, &inaccurate, 500, 500, 5.0) < pp);
+        assert!(performance_points(&rating, &missed, 500, 500, 5.0) < pp);
+        assert_eq!(performance_points(&rating, &PlayStats::default(), 0, 0, 5.0), 0.0);
+    }
+
+    #[test]
+    fn a_full_combo_on_sliders_is_not_penalised() {
+        // 100 sliders with a tick and an end each
+        let beatmap_circles: Vec<BeatmapCircle> = (0..100)
+            .map(|i| BeatmapCircle {
+                time: 1.0 + (i as f64) * 0.5,
+                x: 0.0,
+                y: 0.0,
+                band: Band::Kick,
+                strength: 1.0,
+                slider: Some(SliderPath {
+                    curve: CurveType::Linear,
+                    control_points: vec![Vec2::new(0.3, 0.0)],
+                    length: 0.3,
+                    spans: 1,
+                    span_duration: 0.3,
+                    tick_interval: 0.2,
+                }),
+                spinner_duration: None,
+            })
+            .collect();
+        let circles = initialize_circles(
+            &beatmap_circles,
+            200.0,
+            Vec2::ZERO,
+            &BeatmapDifficulty::default(),
+            0.0
+        );
+        let combo = max_combo(&circles);
+        assert_eq!(combo, 300);
+
+        let rating = StarRating { aim: 2.0, speed: 1.5, stars: 4.0 };
+        let full = PlayStats { count_300: 100, max_combo: combo, combo, ..Default::default() };
+        let broken = PlayStats { max_combo: combo / 2, ..full.clone() };
+        let pp = performance_points(&rating, &full, 100, combo, 5.0);
+        assert!(pp > performance_points(&rating, &broken, 100, combo, 5.0));
+        assert_eq!(pp, performance_points(&rating, &full_combo(100), 100, 100, 5.0));
+    }
+}
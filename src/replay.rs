@@ -0,0 +1,351 @@
+use crate::beatmap::invalid_data;
+use crate::constants::REPLAY_DIR;
+use crate::structs::{ DifficultyLevel, Mods, Replay, ReplayEntry, ReplayFrame };
+use macroquad::prelude::Vec2;
+use std::fs;
+use std::io;
+use std::path::{ Path, PathBuf };
+use std::time::{ SystemTime, UNIX_EPOCH };
+
+/// First bytes of every replay file
+const REPLAY_MAGIC: &[u8; 4] = b"YRPL";
+
+/// Version of the binary replay layout.
+///
+/// Version 2: the song path and difficulty level are stored, so saved replays can be
+/// opened without playing first. Version 1 replays are read without them.
+const REPLAY_VERSION: u8 = 2;
+
+// Bytes of the fixed header fields after the magic and of one frame
+const HEADER_SIZE: usize = 1 + 8 + 4 + 8 + 8 + 4 + 8 + 1 + 2 + 4;
+const FRAME_SIZE: usize = 8 + 4 + 4 + 1;
+
+// Frame key bits: which hit keys are held, and which were pressed during the frame
+const HELD_BITS: u8 = 0b0000_0011;
+const PRESSED_SHIFT: u8 = 4;
+
+impl ReplayFrame {
+    /// Capture the input of one frame
+    pub fn new(
+        time: f64,
+        cursor: Vec2,
+        center: Vec2,
+        spawn_radius: f32,
+        held: [bool; 2],
+        pressed: [bool; 2]
+    ) -> Self {
+        let mut keys = 0;
+        for (i, (&held, &pressed)) in held.iter().zip(&pressed).enumerate() {
+            keys |= (held as u8) << i;
+            keys |= (pressed as u8) << (PRESSED_SHIFT + (i as u8));
+        }
+        let position = (cursor - center) / spawn_radius;
+
+        ReplayFrame {
+            time,
+            x: position.x,
+            y: position.y,
+            keys,
+        }
+    }
+
+    /// The cursor position in screen pixels for the current playfield
+    pub fn cursor(&self, center: Vec2, spawn_radius: f32) -> Vec2 {
+        center + Vec2::new(self.x, self.y) * spawn_radius
+    }
+
+    /// Whether a hit key was pressed during this frame
+    pub fn key_pressed(&self) -> bool {
+        (self.keys >> PRESSED_SHIFT) & HELD_BITS != 0
+    }
+
+    /// Whether a hit key was held down at this frame
+    pub fn key_held(&self) -> bool {
+        self.keys & HELD_BITS != 0
+    }
+}
+
+impl Mods {
+    pub const AUTO: u32 = 1; // The autoplay bot played, not the player
+
+    pub fn auto(self) -> bool {
+        self.bits & Mods::AUTO != 0
+    }
+
+    /// The mods with a flag switched on or off
+    pub fn toggled(self, flag: u32) -> Mods {
+        Mods { bits: self.bits ^ flag }
+    }
+}
+
+impl Replay {
+    /// An empty replay to record a play of a beatmap into
+    pub fn new(
+        song_path: &str,
+        level: Option<DifficultyLevel>,
+        beatmap_hash: u64,
+        mods: Mods,
+        audio_offset: f64,
+        approach_time: f64,
+        circle_radius: f32
+    ) -> Self {
+        Replay {
+            song_path: song_path.to_string(),
+            level,
+            beatmap_hash,
+            mods,
+            audio_offset,
+            approach_time,
+            circle_radius,
+            score: 0,
+            frames: Vec::new(),
+        }
+    }
+}
+
+/// Encode a replay in the compact little-endian binary layout: magic, version, header
+/// fields, level, song path length and bytes, frame count, then `time,x,y,keys` per frame.
+pub fn encode_replay(replay: &Replay) -> Vec<u8> {
+    let song_path = replay.song_path.as_bytes();
+    let song_path = &song_path[..song_path.len().min(u16::MAX as usize)];
+    let mut bytes = Vec::with_capacity(
+        REPLAY_MAGIC.len() + HEADER_SIZE + song_path.len() + replay.frames.len() * FRAME_SIZE
+    );
+    bytes.extend_from_slice(REPLAY_MAGIC);
+    bytes.push(REPLAY_VERSION);
+    bytes.extend_from_slice(&replay.beatmap_hash.to_le_bytes());
+    bytes.extend_from_slice(&replay.mods.bits.to_le_bytes());
+    bytes.extend_from_slice(&replay.audio_offset.to_le_bytes());
+    bytes.extend_from_slice(&replay.approach_time.to_le_bytes());
+    bytes.extend_from_slice(&replay.circle_radius.to_le_bytes());
+    bytes.extend_from_slice(&replay.score.to_le_bytes());
+    bytes.push(encode_level(replay.level));
+    bytes.extend_from_slice(&(song_path.len() as u16).to_le_bytes());
+    bytes.extend_from_slice(song_path);
+    bytes.extend_from_slice(&(replay.frames.len() as u32).to_le_bytes());
+
+    for frame in &replay.frames {
+        bytes.extend_from_slice(&frame.time.to_le_bytes());
+        bytes.extend_from_slice(&frame.x.to_le_bytes());
+        bytes.extend_from_slice(&frame.y.to_le_bytes());
+        bytes.push(frame.keys);
+    }
+    bytes
+}
+
+/// Decode a replay written by `encode_replay`
+pub fn decode_replay(bytes: &[u8]) -> io::Result<Replay> {
+    let header = bytes
+        .strip_prefix(REPLAY_MAGIC.as_slice())
+        .ok_or_else(|| invalid_data("not a replay file"))?;
+    let mut reader = ByteReader { bytes: header };
+
+    let version = reader.take::<1>()?[0];
+    if version == 0 || version > REPLAY_VERSION {
+        return Err(invalid_data(&format!("unsupported replay version {}", version)));
+    }
+
+    let beatmap_hash = u64::from_le_bytes(reader.take()?);
+    let mods = Mods { bits: u32::from_le_bytes(reader.take()?) };
+    let audio_offset = f64::from_le_bytes(reader.take()?);
+    let approach_time = f64::from_le_bytes(reader.take()?);
+    let circle_radius = f32::from_le_bytes(reader.take()?);
+    let score = u64::from_le_bytes(reader.take()?);
+    let (level, song_path) = if version >= 2 {
+        let level = decode_level(reader.take::<1>()?[0])?;
+        let length = u16::from_le_bytes(reader.take()?) as usize;
+        let song_path = String::from_utf8(reader.take_slice(length)?.to_vec())
+            .map_err(|_| invalid_data("replay song path is not UTF-8"))?;
+        (level, song_path)
+    } else {
+        (None, String::new())
+    };
+    let frame_count = u32::from_le_bytes(reader.take()?) as usize;
+
+    if reader.bytes.len() != frame_count * FRAME_SIZE {
+        return Err(invalid_data("replay frames are truncated"));
+    }
+    let mut frames = Vec::with_capacity(frame_count);
+    for _ in 0..frame_count {
+        frames.push(ReplayFrame {
+            time: f64::from_le_bytes(reader.take()?),
+            x: f32::from_le_bytes(reader.take()?),
+            y: f32::from_le_bytes(reader.take()?),
+            keys: reader.take::<1>()?[0],
+        });
+    }
+
+    Ok(Replay {
+        song_path,
+        level,
+        beatmap_hash,
+        mods,
+        audio_offset,
+        approach_time,
+        circle_radius,
+        score,
+        frames,
+    })
+}
+
+/// Reads fixed-size fields from the front of a byte slice
+struct ByteReader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> ByteReader<'a> {
+    fn take<const N: usize>(&mut self) -> io::Result<[u8; N]> {
+        Ok(self.take_slice(N)?.try_into().expect("take_slice returned N bytes"))
+    }
+
+    fn take_slice(&mut self, length: usize) -> io::Result<&'a [u8]> {
+        if self.bytes.len() < length {
+            return Err(invalid_data("replay ends unexpectedly"));
+        }
+        let (field, rest) = self.bytes.split_at(length);
+        self.bytes = rest;
+        Ok(field)
+    }
+}
+
+/// The level as one byte: 0 for imported charts, then the levels from Easy up
+fn encode_level(level: Option<DifficultyLevel>) -> u8 {
+    level.map_or(0, |level| {
+        let index = DifficultyLevel::ALL.iter().position(|&other| other == level);
+        (index.expect("every level is listed") as u8) + 1
+    })
+}
+
+fn decode_level(byte: u8) -> io::Result<Option<DifficultyLevel>> {
+    match byte {
+        0 => Ok(None),
+        _ =>
+            DifficultyLevel::ALL.get((byte as usize) - 1)
+                .map(|&level| Some(level))
+                .ok_or_else(|| invalid_data(&format!("unknown replay level {}", byte))),
+    }
+}
+
+/// Save a replay in the replay directory, named after its beatmap and the current time
+pub fn save_replay(replay: &Replay) -> io::Result<PathBuf> {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_secs());
+    let path = Path::new(REPLAY_DIR).join(
+        format!("{:016x}-{}.yrp", replay.beatmap_hash, timestamp)
+    );
+    fs::create_dir_all(REPLAY_DIR)?;
+    fs::write(&path, encode_replay(replay))?;
+    Ok(path)
+}
+
+/// Read a replay file
+pub fn read_replay(path: &Path) -> io::Result<Replay> {
+    decode_replay(&fs::read(path)?)
+}
+
+/// The saved replays that know their song, newest first
+pub fn list_replays() -> Vec<ReplayEntry> {
+    let Ok(entries) = fs::read_dir(REPLAY_DIR) else {
+        return Vec::new();
+    };
+
+    let mut replays: Vec<(SystemTime, ReplayEntry)> = entries
+        .flatten()
+        .map(|entry| entry.path())
+        .filter(|path| path.extension().is_some_and(|extension| extension == "yrp"))
+        .filter_map(|path| {
+            let replay = match read_replay(&path) {
+                Ok(replay) => replay,
+                Err(err) => {
+                    println!("Ignoring unreadable replay {}: {}", path.display(), err);
+                    return None;
+                }
+            };
+            // Replays from before the song was stored cannot be matched to a beatmap
+            if replay.song_path.is_empty() {
+                return None;
+            }
+            let saved = fs::metadata(&path)
+                .and_then(|metadata| metadata.modified())
+                .unwrap_or(UNIX_EPOCH);
+            Some((saved, ReplayEntry {
+                path,
+                song_path: replay.song_path,
+                level: replay.level,
+                mods: replay.mods,
+                score: replay.score,
+            }))
+        })
+        .collect();
+    replays.sort_by(|(first, _), (second, _)| second.cmp(first));
+    replays
+        .into_iter()
+        .map(|(_, entry)| entry)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample_replay() -> Replay {
+        let mut replay = Replay::new(
+            "src/assets/music/song.mp3",
+            Some(DifficultyLevel::Insane),
+            0x1234_5678_9abc_def0,
+            Mods::default(),
+            0.025,
+            1.2,
+            64.0
+        );
+        replay.score = 123_456;
+        let center = Vec2::new(400.0, 300.0);
+        replay.frames = (0..50)
+            .map(|i| {
+                let cursor = center + Vec2::new(i as f32, -(i as f32) * 2.0);
+                ReplayFrame::new((i as f64) / 60.0, cursor, center, 200.0, [i % 3 == 0, false], [
+                    i % 6 == 0,
+                    i == 7,
+                ])
+            })
+            .collect();
+        replay
+    }
+
+    #[test]
+    fn replays_survive_a_round_trip() {
+        let replay = sample_replay();
+        let decoded = decode_replay(&encode_replay(&replay)).unwrap();
+
+        assert_eq!(decoded, replay);
+        assert!(decoded.frames[6].key_pressed());
+        assert!(decoded.frames[7].key_pressed());
+        assert!(!decoded.frames[8].key_pressed());
+    }
+
+    #[test]
+    fn version_1_replays_are_read_without_their_song() {
+        let replay = sample_replay();
+        let mut bytes = encode_replay(&replay);
+        // Drop the level and the song path length and bytes of version 2
+        let song_start = REPLAY_MAGIC.len() + 1 + 8 + 4 + 8 + 8 + 4 + 8;
+        bytes.drain(song_start..song_start + 1 + 2 + replay.song_path.len());
+        bytes[REPLAY_MAGIC.len()] = 1;
+
+        let decoded = decode_replay(&bytes).unwrap();
+        assert_eq!(decoded.song_path, "");
+        assert_eq!(decoded.level, None);
+        assert_eq!(decoded.frames, replay.frames);
+        assert_eq!(decoded.score, replay.score);
+    }
+
+    #[test]
+    fn truncated_replays_are_rejected() {
+        let bytes = encode_replay(&sample_replay());
+
+        assert!(decode_replay(&bytes[..bytes.len() - 1]).is_err());
+        assert!(decode_replay(&bytes[..10]).is_err());
+        assert!(decode_replay(b"nope").is_err());
+    }
+}
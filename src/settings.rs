@@ -0,0 +1,422 @@
+use crate::constants::*;
+use crate::osu::approach_time_from_ar;
+use crate::structs::{
+    AnalysisParams,
+    Beatmap,
+    BeatmapDifficulty,
+    ChannelMode,
+    Settings,
+    SettingsRow,
+    SnapDivision,
+};
+use macroquad::input::KeyCode;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+// Keys that can be bound to hitting circles
+const BINDABLE_KEYS: [KeyCode; 40] = [
+    KeyCode::A,
+    KeyCode::B,
+    KeyCode::C,
+    KeyCode::D,
+    KeyCode::E,
+    KeyCode::F,
+    KeyCode::G,
+    KeyCode::H,
+    KeyCode::I,
+    KeyCode::J,
+    KeyCode::K,
+    KeyCode::L,
+    KeyCode::M,
+    KeyCode::N,
+    KeyCode::O,
+    KeyCode::P,
+    KeyCode::Q,
+    KeyCode::R,
+    KeyCode::S,
+    KeyCode::T,
+    KeyCode::U,
+    KeyCode::V,
+    KeyCode::W,
+    KeyCode::X,
+    KeyCode::Y,
+    KeyCode::Z,
+    KeyCode::Key0,
+    KeyCode::Key1,
+    KeyCode::Key2,
+    KeyCode::Key3,
+    KeyCode::Key4,
+    KeyCode::Key5,
+    KeyCode::Key6,
+    KeyCode::Key7,
+    KeyCode::Key8,
+    KeyCode::Key9,
+    KeyCode::Space,
+    KeyCode::LeftShift,
+    KeyCode::LeftControl,
+    KeyCode::Semicolon,
+];
+
+// Beat snap choices, in the order the settings screen steps through them
+const SNAP_DIVISIONS: [Option<SnapDivision>; 5] = [
+    None,
+    Some(SnapDivision::Whole),
+    Some(SnapDivision::Half),
+    Some(SnapDivision::Third),
+    Some(SnapDivision::Quarter),
+];
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            volume: DEFAULT_VOLUME,
+            audio_offset_ms: 0,
+            key_bindings: DEFAULT_HIT_KEYS.map(str::to_string),
+            approach_rate: None,
+            circle_radius: None,
+            placement_seed: None,
+            channel_mode: ChannelMode::Downmix,
+            snap_division: None,
+            fullscreen: false,
+            window_width: DEFAULT_WINDOW_WIDTH,
+            window_height: DEFAULT_WINDOW_HEIGHT,
+        }
+    }
+}
+
+impl Settings {
+    /// The keys that hit circles, falling back to the default for unknown names.
+    ///
+    /// Both default keys are used when the two keys would be the same, like after the
+    /// file was edited by hand.
+    pub fn hit_keys(&self) -> [KeyCode; 2] {
+        let defaults = [KeyCode::A, KeyCode::S];
+        let mut keys = defaults;
+        for (key, (name, default)) in keys
+            .iter_mut()
+            .zip(self.key_bindings.iter().zip(DEFAULT_HIT_KEYS)) {
+            *key = key_from_name(name)
+                .or_else(|| key_from_name(default))
+                .unwrap_or(*key);
+        }
+        if keys[0] == keys[1] {
+            return defaults;
+        }
+        keys
+    }
+
+    /// Bind a key to one of the two hit slots.
+    ///
+    /// A key already bound to the other slot swaps places with this slot's key, so the
+    /// two hit keys always stay different.
+    pub fn bind_key(&mut self, slot: usize, key: KeyCode) {
+        let name = key_name(key);
+        let other = 1 - slot;
+        if self.key_bindings[other] == name {
+            self.key_bindings[other] = self.key_bindings[slot].clone();
+        }
+        self.key_bindings[slot] = name;
+    }
+
+    /// Global audio offset in seconds
+    pub fn audio_offset(&self) -> f64 {
+        (self.audio_offset_ms as f64) / 1000.0
+    }
+
+    /// Parameters to analyse songs with, so changing them re-analyses cached maps
+    pub fn analysis_params(&self) -> AnalysisParams {
+        AnalysisParams {
+            channel_mode: self.channel_mode,
+            snap_division: self.snap_division,
+            ..AnalysisParams::default()
+        }
+    }
+
+    /// Difficulty to play a beatmap with.
+    ///
+    /// Generated maps follow the approach rate and circle size settings when they
+    /// are set, imported charts keep the values chosen by their mapper.
+    pub fn play_difficulty(&self, beatmap: &Beatmap) -> BeatmapDifficulty {
+        let difficulty = beatmap.difficulty.clone();
+        if beatmap.analysis.is_none() {
+            return difficulty;
+        }
+        BeatmapDifficulty {
+            approach_time: self.approach_rate
+                .map_or(difficulty.approach_time, approach_time_from_ar),
+            circle_radius: self.circle_radius.unwrap_or(difficulty.circle_radius),
+            ..difficulty
+        }
+    }
+}
+
+/// Step a setting that can be left to the map.
+///
+/// Stepping up an unset value starts from `start`, and stepping below `min` unsets it again.
+fn step_override(value: Option<f64>, step: f64, start: f64, min: f64, max: f64) -> Option<f64> {
+    let Some(value) = value else {
+        return (step > 0.0).then_some(start);
+    };
+    let stepped = value + step;
+    // Compare with some slack so repeated float steps still reach the minimum
+    (stepped >= min - 1e-9).then(|| stepped.clamp(min, max))
+}
+
+/// Location of the settings file, if the platform has a user config directory
+pub fn settings_path() -> Option<PathBuf> {
+    dirs::config_dir().map(|dir| dir.join(SETTINGS_DIR_NAME).join(SETTINGS_FILE_NAME))
+}
+
+/// Load the settings file, using the defaults when it is missing or unreadable
+pub fn load_settings() -> Settings {
+    let Some(path) = settings_path() else {
+        return Settings::default();
+    };
+
+    match fs::read_to_string(&path) {
+        Ok(contents) =>
+            match toml::from_str(&contents) {
+                Ok(settings) => settings,
+                Err(err) => {
+                    println!("Ignoring invalid settings {}: {}", path.display(), err);
+                    Settings::default()
+                }
+            }
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Settings::default(),
+        Err(err) => {
+            println!("Ignoring unreadable settings {}: {}", path.display(), err);
+            Settings::default()
+        }
+    }
+}
+
+/// Write the settings file, creating its directory if needed
+pub fn save_settings(settings: &Settings) -> io::Result<()> {
+    let path = settings_path().ok_or_else(||
+        io::Error::new(io::ErrorKind::NotFound, "no user config directory")
+    )?;
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let contents = toml
+        ::to_string_pretty(settings)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+    fs::write(path, contents)
+}
+
+/// Look up a bindable key by its macroquad name
+pub fn key_from_name(name: &str) -> Option<KeyCode> {
+    BINDABLE_KEYS.into_iter().find(|&key| key_name(key) == name)
+}
+
+/// Name of a key as stored in the settings file
+pub fn key_name(key: KeyCode) -> String {
+    format!("{:?}", key)
+}
+
+/// Whether a key can be bound to hitting circles
+pub fn is_bindable(key: KeyCode) -> bool {
+    BINDABLE_KEYS.contains(&key)
+}
+
+impl SettingsRow {
+    pub const ALL: [SettingsRow; 13] = [
+        SettingsRow::Volume,
+        SettingsRow::AudioOffset,
+        SettingsRow::PrimaryKey,
+        SettingsRow::SecondaryKey,
+        SettingsRow::ApproachRate,
+        SettingsRow::CircleSize,
+        SettingsRow::PlacementSeed,
+        SettingsRow::ChannelMode,
+        SettingsRow::SnapDivision,
+        SettingsRow::Fullscreen,
+        SettingsRow::Resolution,
+        SettingsRow::Calibrate,
+        SettingsRow::Back,
+    ];
+
+    pub fn label(self) -> &'static str {
+        match self {
+            SettingsRow::Volume => "Volume",
+            SettingsRow::AudioOffset => "Audio offset",
+            SettingsRow::PrimaryKey => "Key 1",
+            SettingsRow::SecondaryKey => "Key 2",
+            SettingsRow::ApproachRate => "Approach rate",
+            SettingsRow::CircleSize => "Circle size",
+            SettingsRow::PlacementSeed => "Map seed",
+            SettingsRow::ChannelMode => "Channels",
+            SettingsRow::SnapDivision => "Beat snap",
+            SettingsRow::Fullscreen => "Fullscreen",
+            SettingsRow::Resolution => "Resolution",
+            SettingsRow::Calibrate => "Calibrate offset",
+            SettingsRow::Back => "Back",
+        }
+    }
+
+    /// The current value of the row, as shown on the settings screen
+    pub fn value(self, settings: &Settings) -> String {
+        match self {
+            SettingsRow::Volume => format!("{:.0}%", settings.volume * 100.0),
+            SettingsRow::AudioOffset => format!("{:+} ms", settings.audio_offset_ms),
+            SettingsRow::PrimaryKey => settings.key_bindings[0].clone(),
+            SettingsRow::SecondaryKey => settings.key_bindings[1].clone(),
+            SettingsRow::ApproachRate =>
+                match settings.approach_rate {
+                    Some(approach_rate) => format!("AR {:.1}", approach_rate),
+                    None => "From map".to_string(),
+                }
+            SettingsRow::CircleSize =>
+                match settings.circle_radius {
+                    Some(circle_radius) => format!("{:.0} px", circle_radius),
+                    None => "From map".to_string(),
+                }
+            SettingsRow::PlacementSeed =>
+                match settings.placement_seed {
+                    Some(seed) => seed.to_string(),
+                    None => "From audio".to_string(),
+                }
+            SettingsRow::ChannelMode =>
+                match settings.channel_mode {
+                    ChannelMode::Downmix => "Downmix".to_string(),
+                    ChannelMode::PerChannel => "Per channel".to_string(),
+                }
+            SettingsRow::SnapDivision =>
+                match settings.snap_division {
+                    Some(division) => format!("1/{}", division.divisions_per_beat()),
+                    None => "Off".to_string(),
+                }
+            SettingsRow::Fullscreen => (if settings.fullscreen { "On" } else { "Off" }).to_string(),
+            SettingsRow::Resolution => {
+                format!("{}x{}", settings.window_width, settings.window_height)
+            }
+            SettingsRow::Calibrate | SettingsRow::Back => String::new(),
+        }
+    }
+
+    /// Index of the key binding this row edits
+    pub fn key_slot(self) -> Option<usize> {
+        match self {
+            SettingsRow::PrimaryKey => Some(0),
+            SettingsRow::SecondaryKey => Some(1),
+            _ => None,
+        }
+    }
+
+    /// Step the value of the row up or down
+    pub fn adjust(self, settings: &mut Settings, step: i32) {
+        match self {
+            SettingsRow::Volume => {
+                settings.volume = (settings.volume + 0.05 * (step as f32)).clamp(0.0, 1.0);
+            }
+            SettingsRow::AudioOffset => {
+                settings.audio_offset_ms = (settings.audio_offset_ms + 5 * step).clamp(-500, 500);
+            }
+            // Stepping below the smallest value goes back to the map's own value
+            SettingsRow::ApproachRate => {
+                settings.approach_rate = step_override(
+                    settings.approach_rate.map(f64::from),
+                    0.5 * (step as f64),
+                    5.0,
+                    0.0,
+                    10.0
+                ).map(|approach_rate| approach_rate as f32);
+            }
+            SettingsRow::CircleSize => {
+                settings.circle_radius = step_override(
+                    settings.circle_radius.map(f64::from),
+                    5.0 * (step as f64),
+                    CIRCLE_MAX_RADIUS as f64,
+                    20.0,
+                    150.0
+                ).map(|radius| radius as f32);
+            }
+            SettingsRow::PlacementSeed => {
+                // Stepping below the first seed goes back to seeding from the audio
+                let seed = settings.placement_seed.map_or(-1, |seed| seed as i64) + (step as i64);
+                settings.placement_seed = (seed >= 0).then_some(seed as u64);
+            }
+            SettingsRow::ChannelMode => {
+                settings.channel_mode = match settings.channel_mode {
+                    ChannelMode::Downmix => ChannelMode::PerChannel,
+                    ChannelMode::PerChannel => ChannelMode::Downmix,
+                };
+            }
+            SettingsRow::SnapDivision => {
+                let current = SNAP_DIVISIONS.iter()
+                    .position(|&division| division == settings.snap_division)
+                    .unwrap_or(0) as i32;
+                let next = (current + step).rem_euclid(SNAP_DIVISIONS.len() as i32) as usize;
+                settings.snap_division = SNAP_DIVISIONS[next];
+            }
+            SettingsRow::Fullscreen => {
+                settings.fullscreen = !settings.fullscreen;
+            }
+            SettingsRow::Resolution => {
+                let current = RESOLUTIONS.iter()
+                    .position(|&size| size == (settings.window_width, settings.window_height))
+                    .unwrap_or(0) as i32;
+                let next = (current + step).rem_euclid(RESOLUTIONS.len() as i32) as usize;
+                (settings.window_width, settings.window_height) = RESOLUTIONS[next];
+            }
+            SettingsRow::PrimaryKey |
+            SettingsRow::SecondaryKey |
+            SettingsRow::Calibrate |
+            SettingsRow::Back => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn settings_survive_a_toml_round_trip() {
+        let settings = Settings {
+            volume: 0.35,
+            audio_offset_ms: -25,
+            key_bindings: ["Z".to_string(), "X".to_string()],
+            approach_rate: Some(8.5),
+            circle_radius: None,
+            placement_seed: Some(7),
+            channel_mode: ChannelMode::PerChannel,
+            snap_division: Some(SnapDivision::Half),
+            fullscreen: true,
+            window_width: 1280,
+            window_height: 720,
+        };
+        let contents = toml::to_string_pretty(&settings).unwrap();
+        assert_eq!(toml::from_str::<Settings>(&contents).unwrap(), settings);
+
+        // Settings missing from an older file keep their defaults
+        let partial: Settings = toml::from_str("volume = 0.5").unwrap();
+        assert_eq!(partial, Settings { volume: 0.5, ..Settings::default() });
+    }
+
+    #[test]
+    fn unknown_key_names_fall_back_to_the_defaults() {
+        let mut settings = Settings::default();
+        assert_eq!(settings.hit_keys(), [KeyCode::A, KeyCode::S]);
+
+        settings.key_bindings = ["Escape".to_string(), "K".to_string()];
+        assert_eq!(settings.hit_keys(), [KeyCode::A, KeyCode::K]);
+
+        // A fallback must not bind one key to both slots
+        settings.key_bindings = ["S".to_string(), "bogus".to_string()];
+        assert_eq!(settings.hit_keys(), [KeyCode::A, KeyCode::S]);
+        settings.key_bindings = ["K".to_string(), "K".to_string()];
+        assert_eq!(settings.hit_keys(), [KeyCode::A, KeyCode::S]);
+    }
+
+    #[test]
+    fn binding_the_other_hit_key_swaps_them() {
+        let mut settings = Settings::default();
+        settings.bind_key(0, KeyCode::Z);
+        assert_eq!(settings.hit_keys(), [KeyCode::Z, KeyCode::S]);
+
+        settings.bind_key(1, KeyCode::Z);
+        assert_eq!(settings.hit_keys(), [KeyCode::S, KeyCode::Z]);
+    }
+}
@@ -0,0 +1,1014 @@
+//! The gameplay rules: circles, judgements, score and combo.
+//!
+//! Nothing here polls input or draws, so whole plays can be simulated without a window.
+
+use crate::structs::{
+    BeatmapCircle,
+    BeatmapDifficulty,
+    CheckpointKind,
+    Circle,
+    Grade,
+    HitWindows,
+    Judgement,
+    PlayStats,
+    ReplayFrame,
+    Simulation,
+    Slider,
+    Spinner,
+};
+use crate::constants::*;
+use macroquad::math::Vec2;
+use std::f64::consts::TAU;
+
+impl Simulation {
+    /// Lay out the circles of a beatmap on the playfield, timed with the audio offset
+    pub fn new(
+        beatmap_circles: &[BeatmapCircle],
+        spawn_radius: f32,
+        center: Vec2,
+        difficulty: &BeatmapDifficulty,
+        audio_offset: f64
+    ) -> Self {
+        Simulation {
+            circles: initialize_circles(beatmap_circles, spawn_radius, center, difficulty, audio_offset),
+            stats: PlayStats::default(),
+            approach_time: difficulty.approach_time,
+            hit_windows: HitWindows::new(difficulty.overall_difficulty),
+        }
+    }
+
+    /// Judge one frame of input at its own time.
+    ///
+    /// Returns the positions of the circles, sliders and spinners that were missed by
+    /// this frame.
+    pub fn step(&mut self, frame: &ReplayFrame, center: Vec2, spawn_radius: f32) -> Vec<Vec2> {
+        let cursor = frame.cursor(center, spawn_radius);
+        let mut missed = Vec::new();
+        let hit = handle_key_hits(
+            &mut self.circles,
+            frame.time,
+            &mut self.stats,
+            cursor,
+            frame.key_pressed(),
+            &self.hit_windows
+        );
+        if let Some((Judgement::Miss, position)) = hit {
+            missed.push(position);
+        }
+        missed.extend(
+            handle_missed_circles(&mut self.circles, frame.time, &mut self.stats, &self.hit_windows)
+        );
+        missed.extend(
+            handle_sliders(&mut self.circles, frame.time, &mut self.stats, cursor, frame.key_held())
+        );
+        missed.extend(
+            handle_spinners(
+                &mut self.circles,
+                frame.time,
+                &mut self.stats,
+                cursor,
+                frame.key_held()
+            )
+        );
+        missed
+    }
+}
+
+/// Initialize circles for a game with animations
+pub fn initialize_circles(
+    beatmap_circles: &[BeatmapCircle],
+    spawn_radius: f32,
+    center: Vec2,
+    difficulty: &BeatmapDifficulty,
+    delay: f64
+) -> Vec<Circle> {
+    beatmap_circles
+        .iter()
+        .map(|beatmap_circle| {
+            let position = Vec2::new(
+                center.x + beatmap_circle.x * spawn_radius,
+                center.y + beatmap_circle.y * spawn_radius
+            );
+
+            let hit_time = beatmap_circle.time + delay;
+            let head = Vec2::new(beatmap_circle.x, beatmap_circle.y);
+
+            Circle {
+                position,
+                spawn_time: hit_time - difficulty.approach_time,
+                hit_time,
+                max_radius: difficulty.circle_radius,
+                hit: false,
+                missed: false,
+                slider: beatmap_circle.slider
+                    .as_ref()
+                    .map(|path| Slider::new(path, head, hit_time, center, spawn_radius)),
+                spinner: beatmap_circle.spinner_duration.map(|duration| {
+                    Spinner::new(hit_time, duration, difficulty.overall_difficulty)
+                }),
+            }
+        })
+        .collect()
+}
+
+impl Circle {
+    /// Whether the circle, the whole slider it is the head of or the spinner has been judged
+    pub fn is_judged(&self) -> bool {
+        match (&self.slider, &self.spinner) {
+            (Some(slider), _) => slider.finished,
+            (_, Some(spinner)) => spinner.finished,
+            _ => self.hit || self.missed,
+        }
+    }
+
+    /// When the object ends: its hit time, when the ball of its slider stops or when
+    /// the spinner ends
+    pub fn end_time(&self) -> f64 {
+        match (&self.slider, &self.spinner) {
+            (Some(slider), _) => slider.end_time(),
+            (_, Some(spinner)) => spinner.end_time,
+            _ => self.hit_time,
+        }
+    }
+
+    /// Where the object ends: the circle, or where the ball of its slider stops.
+    /// Spinners are at the centre of the playfield.
+    pub fn end_position(&self) -> Vec2 {
+        self.slider.as_ref().map_or(self.position, Slider::end_position)
+    }
+}
+
+impl PlayStats {
+    /// Count a hit worth 300, 100 or 50 and extend the combo.
+    ///
+    /// Like osu!, the hit is worth more the longer the combo before it.
+    pub fn register_hit(&mut self, judgement: Judgement, hit_error: f64) {
+        self.hit_errors.push(hit_error);
+        self.count_judgement(judgement);
+        self.combo += 1;
+        self.max_combo = self.max_combo.max(self.combo);
+    }
+
+    /// Count a miss and break the combo
+    pub fn register_miss(&mut self) {
+        self.count_miss += 1;
+        self.combo = 0;
+    }
+
+    /// Score a slider head, tick, repeat or end that was followed and extend the combo
+    pub fn register_slider_check(&mut self, value: u64) {
+        self.score += value;
+        self.combo += 1;
+        self.max_combo = self.max_combo.max(self.combo);
+    }
+
+    /// Count the judgement of a finished slider.
+    ///
+    /// The combo was already given by the head and checkpoints along the slider.
+    pub fn register_slider(&mut self, judgement: Judgement, hit_error: Option<f64>) {
+        if judgement != Judgement::Miss {
+            self.hit_errors.extend(hit_error);
+        }
+        self.count_judgement(judgement);
+    }
+
+    /// Count the judgement of a finished spinner and extend the combo, unless it was missed
+    pub fn register_spinner(&mut self, judgement: Judgement) {
+        if judgement == Judgement::Miss {
+            self.register_miss();
+            return;
+        }
+        self.count_judgement(judgement);
+        self.combo += 1;
+        self.max_combo = self.max_combo.max(self.combo);
+    }
+
+    /// Score a rotation of a spinner, without adding to the combo
+    pub fn register_spin(&mut self, value: u64) {
+        self.score += value;
+    }
+
+    /// Count a judgement and add its score, worth more the longer the combo before it
+    fn count_judgement(&mut self, judgement: Judgement) {
+        match judgement {
+            Judgement::Hit300 => self.count_300 += 1,
+            Judgement::Hit100 => self.count_100 += 1,
+            Judgement::Hit50 => self.count_50 += 1,
+            Judgement::Miss => self.count_miss += 1,
+        }
+        let value = judgement.value();
+        self.score += value + (value * (self.combo as u64)) / COMBO_SCORE_DIVISOR;
+    }
+
+    /// Break the combo without counting a miss, for slider heads and ticks that were missed
+    pub fn break_combo(&mut self) {
+        self.combo = 0;
+    }
+
+    /// Number of circles judged so far
+    pub fn judged(&self) -> u32 {
+        self.count_300 + self.count_100 + self.count_50 + self.count_miss
+    }
+
+    /// osu! accuracy from 0 to 1, weighting each judgement by its hit value
+    pub fn accuracy(&self) -> f64 {
+        let judged = self.judged();
+        if judged == 0 {
+            return 1.0;
+        }
+        let points = 300 * self.count_300 + 100 * self.count_100 + 50 * self.count_50;
+        (points as f64) / ((300 * judged) as f64)
+    }
+
+    /// osu! grade from the share of 300s, 50s and misses.
+    ///
+    /// Like the accuracy, a play with nothing judged counts as perfect.
+    pub fn grade(&self) -> Grade {
+        let judged = self.judged();
+        if judged == 0 {
+            return Grade::SS;
+        }
+        let judged = judged as f64;
+        let ratio_300 = (self.count_300 as f64) / judged;
+        let ratio_50 = (self.count_50 as f64) / judged;
+        let no_misses = self.count_miss == 0;
+
+        if ratio_300 >= 1.0 {
+            Grade::SS
+        } else if ratio_300 > 0.9 && ratio_50 < 0.01 && no_misses {
+            Grade::S
+        } else if (ratio_300 > 0.8 && no_misses) || ratio_300 > 0.9 {
+            Grade::A
+        } else if (ratio_300 > 0.7 && no_misses) || ratio_300 > 0.8 {
+            Grade::B
+        } else if ratio_300 > 0.6 {
+            Grade::C
+        } else {
+            Grade::D
+        }
+    }
+
+    /// Mean hit error in seconds, negative when the player tends to hit early
+    pub fn average_offset(&self) -> f64 {
+        if self.hit_errors.is_empty() {
+            return 0.0;
+        }
+        self.hit_errors.iter().sum::<f64>() / (self.hit_errors.len() as f64)
+    }
+
+    /// Ten times the standard deviation of the hit errors in milliseconds, as in osu!
+    pub fn unstable_rate(&self) -> f64 {
+        if self.hit_errors.is_empty() {
+            return 0.0;
+        }
+        let mean = self.average_offset();
+        let variance =
+            self.hit_errors
+                .iter()
+                .map(|error| (error - mean).powi(2))
+                .sum::<f64>() / (self.hit_errors.len() as f64);
+        variance.sqrt() * 1000.0 * 10.0
+    }
+}
+
+impl Judgement {
+    /// Hit value the judgement scores
+    pub fn value(self) -> u64 {
+        match self {
+            Judgement::Hit300 => 300,
+            Judgement::Hit100 => 100,
+            Judgement::Hit50 => 50,
+            Judgement::Miss => 0,
+        }
+    }
+}
+
+impl HitWindows {
+    /// osu! hit windows, narrower at a higher overall difficulty
+    pub fn new(overall_difficulty: f32) -> Self {
+        let od = overall_difficulty as f64;
+        HitWindows {
+            hit_300: (80.0 - 6.0 * od) / 1000.0,
+            hit_100: (140.0 - 8.0 * od) / 1000.0,
+            hit_50: (200.0 - 10.0 * od) / 1000.0,
+            miss: TOO_EARLY_WINDOW,
+        }
+    }
+
+    /// Judgement of a press `hit_error` seconds from a hit time, negative when early.
+    ///
+    /// Presses earlier than the 50 window are misses, those later than it or earlier
+    /// than the miss window do not count.
+    pub fn judge(&self, hit_error: f64) -> Option<Judgement> {
+        let error = hit_error.abs();
+        if error <= self.hit_300 {
+            Some(Judgement::Hit300)
+        } else if error <= self.hit_100 {
+            Some(Judgement::Hit100)
+        } else if error <= self.hit_50 {
+            Some(Judgement::Hit50)
+        } else if hit_error < 0.0 && error <= self.miss {
+            Some(Judgement::Miss)
+        } else {
+            None
+        }
+    }
+}
+
+impl Grade {
+    pub fn label(self) -> &'static str {
+        match self {
+            Grade::SS => "SS",
+            Grade::S => "S",
+            Grade::A => "A",
+            Grade::B => "B",
+            Grade::C => "C",
+            Grade::D => "D",
+        }
+    }
+}
+
+/// Input of the autoplay bot for one frame.
+///
+/// The cursor glides along a smoothstep path from where the previous object ended to
+/// the next unjudged one, arriving just before its hit time, and presses once it is
+/// there. Sliders are held with the cursor on the ball until they end, and spinners
+/// are spun around their centre at `AUTO_SPIN_RPS`.
+pub fn auto_frame(circles: &[Circle], time: f64, center: Vec2, spawn_radius: f32) -> ReplayFrame {
+    let next = circles.iter().position(|circle| !circle.is_judged());
+
+    let (cursor, held, pressed) = match next {
+        Some(index) if circles[index].spinner.is_some() && time >= circles[index].hit_time => {
+            let spun = (time - circles[index].hit_time) * AUTO_SPIN_RPS * TAU;
+            let offset = Vec2::from_angle(spun as f32) * AUTO_SPIN_RADIUS;
+            (circles[index].position + offset, true, false)
+        }
+        // Once the head is judged, hold the key with the cursor on the ball
+        Some(index) if circles[index].hit || circles[index].missed => {
+            let ball = circles[index].slider.as_ref().map_or(circles[index].position, |slider| {
+                slider.ball_position(time)
+            });
+            (ball, true, false)
+        }
+        Some(index) => {
+            let target = &circles[index];
+            let (from, depart) = match index.checked_sub(1) {
+                Some(previous) => (circles[previous].end_position(), circles[previous].end_time()),
+                None => (center, target.spawn_time),
+            };
+            let arrive = target.hit_time - AUTO_LEAD;
+
+            let progress = if arrive > depart {
+                (((time - depart) / (arrive - depart)) as f32).clamp(0.0, 1.0)
+            } else {
+                1.0
+            };
+            let eased = progress * progress * (3.0 - 2.0 * progress);
+            let pressed = time >= target.spawn_time && target.hit_time - time <= AUTO_LEAD;
+            (from.lerp(target.position, eased), pressed, pressed)
+        }
+        None => (circles.last().map_or(center, Circle::end_position), false, false),
+    };
+
+    ReplayFrame::new(time, cursor, center, spawn_radius, [held, false], [pressed, false])
+}
+
+/// Judge a key press on the first circle or slider head under the cursor that is
+/// inside its hit windows, and return the judgement and where the circle is.
+///
+/// A press too early for a 50 is a miss. The input is passed in rather than polled
+/// so recorded replays judge the same way.
+pub fn handle_key_hits(
+    circles: &mut [Circle],
+    elapsed: f64,
+    stats: &mut PlayStats,
+    mouse_pos: Vec2,
+    key_pressed: bool,
+    hit_windows: &HitWindows
+) -> Option<(Judgement, Vec2)> {
+    if !key_pressed {
+        return None;
+    }
+    let pending = circles
+        .iter_mut()
+        .filter(|c| !c.hit && !c.missed && c.spinner.is_none() && elapsed >= c.spawn_time);
+    for circle in pending {
+        if mouse_pos.distance(circle.position) >= circle.max_radius {
+            continue;
+        }
+        let hit_error = elapsed - circle.hit_time;
+        let Some(judgement) = hit_windows.judge(hit_error) else {
+            continue;
+        };
+
+        match (&mut circle.slider, judgement) {
+            (Some(_), Judgement::Miss) => {
+                circle.missed = true;
+                stats.break_combo();
+            }
+            // A slider head only counts as one of its checks, judged when it ends
+            (Some(slider), _) => {
+                circle.hit = true;
+                slider.head_error = Some(hit_error);
+                slider.checkpoints_hit += 1;
+                stats.register_slider_check(SLIDER_EDGE_SCORE);
+            }
+            (None, Judgement::Miss) => {
+                circle.missed = true;
+                stats.register_miss();
+            }
+            (None, _) => {
+                circle.hit = true;
+                stats.register_hit(judgement, hit_error);
+            }
+        }
+        return Some((judgement, circle.position));
+    }
+    None
+}
+
+/// Mark the circles whose 50 window closed without a hit as missed and return their
+/// positions.
+///
+/// A missed slider head only breaks the combo, the slider is judged when it ends.
+/// Spinners have no head and are judged when they end.
+pub fn handle_missed_circles(
+    circles: &mut [Circle],
+    elapsed: f64,
+    stats: &mut PlayStats,
+    hit_windows: &HitWindows
+) -> Vec<Vec2> {
+    let mut missed = Vec::new();
+    for circle in circles.iter_mut().filter(|c| !c.hit && !c.missed && c.spinner.is_none()) {
+        if elapsed - circle.hit_time > hit_windows.hit_50 {
+            circle.missed = true;
+            if circle.slider.is_some() {
+                stats.break_combo();
+            } else {
+                stats.register_miss();
+                missed.push(circle.position);
+            }
+        }
+    }
+    missed
+}
+
+/// Follow the ball of every slider in progress and judge the checkpoints it has
+/// passed. A checkpoint is hit when a key is held with the cursor in the follow
+/// circle, which grows once the ball is being followed.
+///
+/// Finished sliders are judged by the share of checks that were hit. Returns the end
+/// positions of the sliders that were missed entirely.
+pub fn handle_sliders(
+    circles: &mut [Circle],
+    elapsed: f64,
+    stats: &mut PlayStats,
+    mouse_pos: Vec2,
+    key_held: bool
+) -> Vec<Vec2> {
+    let mut missed = Vec::new();
+    for circle in circles.iter_mut() {
+        let max_radius = circle.max_radius;
+        let Some(slider) = circle.slider.as_mut() else {
+            continue;
+        };
+        if slider.finished || elapsed < slider.start_time {
+            continue;
+        }
+
+        let ball = slider.ball_position(elapsed);
+        let follow_radius = if slider.tracking {
+            max_radius * SLIDER_FOLLOW_SCALE
+        } else {
+            max_radius
+        };
+        slider.tracking = key_held && mouse_pos.distance(ball) < follow_radius;
+
+        while let Some(checkpoint) = slider.checkpoints.get(slider.judged_checkpoints).copied() {
+            if checkpoint.time > elapsed {
+                break;
+            }
+            slider.judged_checkpoints += 1;
+            if slider.tracking {
+                slider.checkpoints_hit += 1;
+                stats.register_slider_check(checkpoint.kind.score());
+            } else if checkpoint.kind != CheckpointKind::End {
+                // Like osu!, letting go just before the end does not break the combo
+                stats.break_combo();
+            }
+        }
+
+        if slider.judged_checkpoints == slider.checkpoints.len() {
+            slider.finished = true;
+            let judgement = slider.judgement();
+            stats.register_slider(judgement, slider.head_error);
+            if judgement == Judgement::Miss {
+                missed.push(slider.end_position());
+            }
+        }
+    }
+    missed
+}
+
+/// Spin every spinner in progress with the cursor angle around its centre while a key
+/// is held. Rotations up to the required ones score `SPINNER_SPIN_SCORE`, rotations
+/// beyond them `SPINNER_BONUS_SCORE`.
+///
+/// Spinners that ended are judged by the share of the required rotations spun.
+/// Returns the positions of the spinners that were missed.
+pub fn handle_spinners(
+    circles: &mut [Circle],
+    elapsed: f64,
+    stats: &mut PlayStats,
+    mouse_pos: Vec2,
+    key_held: bool
+) -> Vec<Vec2> {
+    let mut missed = Vec::new();
+    for circle in circles.iter_mut() {
+        let center = circle.position;
+        let Some(spinner) = circle.spinner.as_mut() else {
+            continue;
+        };
+        if spinner.finished || elapsed < spinner.start_time {
+            continue;
+        }
+
+        let offset = mouse_pos - center;
+        let spun_before = spinner.rotations.floor() as u64;
+        spinner.spin(elapsed.min(spinner.end_time), offset.y.atan2(offset.x), key_held);
+        for rotation in spun_before + 1..=(spinner.rotations.floor() as u64) {
+            if (rotation as f64) <= spinner.required_rotations {
+                stats.register_spin(SPINNER_SPIN_SCORE);
+            } else {
+                stats.register_spin(SPINNER_BONUS_SCORE);
+            }
+        }
+
+        if elapsed >= spinner.end_time {
+            spinner.finished = true;
+            let judgement = spinner.judgement();
+            stats.register_spinner(judgement);
+            if judgement == Judgement::Miss {
+                missed.push(center);
+            }
+        }
+    }
+    missed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio::analyse_samples;
+    use crate::beatmap::{ circles_from_analysis, placement_rng };
+    use crate::structs::{
+        AnalysisParams,
+        AnalysisProgress,
+        Band,
+        CurveType,
+        DifficultyLevel,
+        SliderPath,
+    };
+    use std::sync::{ atomic::AtomicBool, mpsc, Arc };
+
+    const SAMPLE_RATE: u32 = 44_100;
+    const CENTER: Vec2 = Vec2::new(400.0, 300.0);
+    const SPAWN_RADIUS: f32 = 200.0;
+
+    /// A 60 Hz tone burst at every beat time, heard by the kick band
+    fn kick_track(beat_times: &[f64], duration: f64) -> Vec<f32> {
+        let mut samples = vec![0.0; (duration * (SAMPLE_RATE as f64)) as usize];
+        for &time in beat_times {
+            let start = (time * (SAMPLE_RATE as f64)) as usize;
+            for (i, sample) in samples.iter_mut().skip(start).take(4_410).enumerate() {
+                let t = (i as f32) / (SAMPLE_RATE as f32);
+                *sample = (std::f32::consts::TAU * 60.0 * t).sin() * (-20.0 * t).exp();
+            }
+        }
+        samples
+    }
+
+    fn beatmap_circle(time: f64, x: f32, y: f32) -> BeatmapCircle {
+        BeatmapCircle {
+            time,
+            x,
+            y,
+            band: Band::Kick,
+            strength: 1.0,
+            slider: None,
+            spinner_duration: None,
+        }
+    }
+
+    /// A spinner from `time` lasting `duration` seconds, needing 2.5 rotations a second
+    fn beatmap_spinner(time: f64, duration: f64) -> BeatmapCircle {
+        BeatmapCircle { spinner_duration: Some(duration), ..beatmap_circle(time, 0.0, 0.0) }
+    }
+
+    /// Frames at 60 fps holding the first hit key while circling the centre at `rps`
+    fn spin(start: f64, end: f64, rps: f64) -> Vec<ReplayFrame> {
+        let count = ((end - start) * 60.0) as usize;
+        (0..=count)
+            .map(|i| {
+                let time = start + (i as f64) / 60.0;
+                let angle = ((time - start) * rps * TAU) as f32;
+                hold(time, CENTER + Vec2::from_angle(angle) * 80.0)
+            })
+            .collect()
+    }
+
+    /// A slider with ticks every quarter second and spans of 0.6 seconds
+    fn beatmap_slider(
+        time: f64,
+        x: f32,
+        y: f32,
+        curve: CurveType,
+        control_points: &[Vec2],
+        spans: u32
+    ) -> BeatmapCircle {
+        BeatmapCircle {
+            slider: Some(SliderPath {
+                curve,
+                control_points: control_points.to_vec(),
+                length: 0.5,
+                spans,
+                span_duration: 0.6,
+                tick_interval: 0.25,
+            }),
+            ..beatmap_circle(time, x, y)
+        }
+    }
+
+    fn simulation(beatmap_circles: &[BeatmapCircle]) -> Simulation {
+        Simulation::new(beatmap_circles, SPAWN_RADIUS, CENTER, &BeatmapDifficulty::default(), 0.0)
+    }
+
+    /// Judge a whole stream of input frames, in order
+    fn run(simulation: &mut Simulation, frames: &[ReplayFrame]) {
+        for frame in frames {
+            simulation.step(frame, CENTER, SPAWN_RADIUS);
+        }
+    }
+
+    fn is_finished(simulation: &Simulation) -> bool {
+        simulation.circles.iter().all(Circle::is_judged)
+    }
+
+    /// A frame pressing the first hit key with the cursor at a screen position
+    fn press(time: f64, cursor: Vec2) -> ReplayFrame {
+        ReplayFrame::new(time, cursor, CENTER, SPAWN_RADIUS, [true, false], [true, false])
+    }
+
+    /// A frame holding the first hit key with the cursor at a screen position
+    fn hold(time: f64, cursor: Vec2) -> ReplayFrame {
+        ReplayFrame::new(time, cursor, CENTER, SPAWN_RADIUS, [true, false], [false; 2])
+    }
+
+    /// Frames at 60 fps with no keys pressed, from `start` up to `end`
+    fn idle(start: f64, end: f64) -> Vec<ReplayFrame> {
+        let count = ((end - start) * 60.0) as usize;
+        (0..=count)
+            .map(|i| {
+                ReplayFrame::new(start + (i as f64) / 60.0, CENTER, CENTER, SPAWN_RADIUS, [false; 2], [
+                    false;
+                    2
+                ])
+            })
+            .collect()
+    }
+
+    #[test]
+    fn autoplay_gets_an_ss_on_a_generated_map() {
+        // Detect the beats of a synthetic song and generate a map from them
+        let beat_times: Vec<f64> = (0..24).map(|beat| 1.0 + (beat as f64) * 0.5).collect();
+        let samples = kick_track(&beat_times, 14.0);
+        let params = AnalysisParams::default();
+        let (tx, _rx) = mpsc::channel();
+        let progress = AnalysisProgress { tx, cancel: Arc::new(AtomicBool::new(false)) };
+        let analysis = analyse_samples(&samples, 1, SAMPLE_RATE, &params, &progress).unwrap();
+        let beatmap_circles = circles_from_analysis(&analysis, DifficultyLevel::Hard, &mut placement_rng(7));
+        assert_eq!(beatmap_circles.len(), beat_times.len());
+
+        // Let the bot play it at 60 frames per second through the normal judgement
+        let mut simulation = simulation(&beatmap_circles);
+        for frame_index in 0..15 * 60 {
+            let time = (frame_index as f64) / 60.0;
+            let frame = auto_frame(&simulation.circles, time, CENTER, SPAWN_RADIUS);
+            simulation.step(&frame, CENTER, SPAWN_RADIUS);
+        }
+
+        let stats = &simulation.stats;
+        assert!(is_finished(&simulation));
+        assert_eq!(stats.count_300 as usize, beat_times.len(), "stats: {:?}", stats);
+        assert_eq!(stats.grade(), Grade::SS);
+        assert_eq!(stats.max_combo as usize, beat_times.len());
+    }
+
+    #[test]
+    fn an_empty_play_is_graded_like_its_accuracy() {
+        let stats = PlayStats::default();
+        assert_eq!(stats.accuracy(), 1.0);
+        assert_eq!(stats.grade(), Grade::SS);
+    }
+
+    #[test]
+    fn an_idle_play_misses_every_circle() {
+        let mut simulation = simulation(
+            &[beatmap_circle(2.0, 0.0, 0.0), beatmap_circle(3.0, 0.5, 0.0)]
+        );
+        run(&mut simulation, &idle(0.0, 2.5));
+        assert!(!is_finished(&simulation));
+
+        let missed = simulation.step(&idle(3.6, 3.6)[0], CENTER, SPAWN_RADIUS);
+        assert_eq!(missed, vec![CENTER + Vec2::new(0.5, 0.0) * SPAWN_RADIUS]);
+        assert!(is_finished(&simulation));
+
+        let stats = &simulation.stats;
+        assert_eq!(stats.count_miss, 2);
+        assert_eq!((stats.score, stats.combo, stats.max_combo), (0, 0, 0));
+        assert_eq!(stats.accuracy(), 0.0);
+        assert_eq!(stats.grade(), Grade::D);
+    }
+
+    #[test]
+    fn hits_are_judged_by_their_hit_windows() {
+        // At OD 5 the 300, 100 and 50 windows are 50, 100 and 150 ms
+        let mut simulation = simulation(
+            &[
+                beatmap_circle(2.0, -0.5, 0.0),
+                beatmap_circle(4.0, 0.0, 0.0),
+                beatmap_circle(6.0, 0.5, 0.0),
+                beatmap_circle(8.0, 0.0, 0.5),
+            ]
+        );
+        let circle_at = |x: f32, y: f32| CENTER + Vec2::new(x, y) * SPAWN_RADIUS;
+        run(&mut simulation, &[
+            press(1.97, circle_at(-0.5, 0.0)),
+            press(4.08, circle_at(0.0, 0.0)),
+            press(5.88, circle_at(0.5, 0.0)),
+            // Too early to count at all
+            press(7.5, circle_at(0.0, 0.5)),
+        ]);
+        assert_eq!(simulation.stats.judged(), 3);
+
+        // Too early for a 50 is a miss
+        let missed = simulation.step(&press(7.7, circle_at(0.0, 0.5)), CENTER, SPAWN_RADIUS);
+        assert_eq!(missed, vec![circle_at(0.0, 0.5)]);
+
+        let stats = &simulation.stats;
+        assert_eq!((stats.count_300, stats.count_100, stats.count_50), (1, 1, 1));
+        assert_eq!((stats.count_miss, stats.combo, stats.max_combo), (1, 0, 3));
+        let errors: Vec<f64> = stats.hit_errors
+            .iter()
+            .map(|error| (error * 100.0).round() / 100.0)
+            .collect();
+        assert_eq!(errors, vec![-0.03, 0.08, -0.12]);
+    }
+
+    #[test]
+    fn late_hits_count_until_the_50_window_closes() {
+        let mut simulation = simulation(
+            &[beatmap_circle(2.0, 0.0, 0.0), beatmap_circle(3.0, 0.0, 0.0)]
+        );
+        simulation.step(&press(2.14, CENTER), CENTER, SPAWN_RADIUS);
+        let missed = simulation.step(&press(3.16, CENTER), CENTER, SPAWN_RADIUS);
+
+        assert_eq!(missed, vec![CENTER]);
+        assert_eq!((simulation.stats.count_50, simulation.stats.count_miss), (1, 1));
+        assert!(is_finished(&simulation));
+    }
+
+    #[test]
+    fn higher_od_narrows_the_hit_windows() {
+        let easy = HitWindows::new(0.0);
+        let hard = HitWindows::new(10.0);
+        assert_eq!(easy.judge(0.07), Some(Judgement::Hit300));
+        assert_eq!(hard.judge(0.07), Some(Judgement::Hit50));
+        assert_eq!(hard.judge(-0.2), Some(Judgement::Miss));
+        assert_eq!(hard.judge(0.2), None);
+        assert_eq!(easy.judge(-0.5), None);
+    }
+
+    #[test]
+    fn circles_are_timed_against_the_song_position() {
+        // Only the audio offset moves a circle, the countdown runs before the song starts
+        let difficulty = BeatmapDifficulty::default();
+        let mut simulation = Simulation::new(
+            &[beatmap_circle(2.0, 0.0, 0.0)],
+            SPAWN_RADIUS,
+            CENTER,
+            &difficulty,
+            0.03
+        );
+        let circle = &simulation.circles[0];
+        assert!((circle.hit_time - 2.03).abs() < 1e-9);
+        assert!((circle.spawn_time - (2.03 - difficulty.approach_time)).abs() < 1e-9);
+
+        // A press at that song position is right on time
+        simulation.step(&press(2.03, CENTER), CENTER, SPAWN_RADIUS);
+        assert_eq!(simulation.stats.count_300, 1);
+        assert!(simulation.stats.hit_errors[0].abs() < 1e-9);
+    }
+
+    #[test]
+    fn presses_away_from_the_circle_do_not_hit() {
+        let mut simulation = simulation(&[beatmap_circle(2.0, 0.0, 0.0)]);
+        simulation.step(&press(1.95, CENTER + Vec2::new(150.0, 0.0)), CENTER, SPAWN_RADIUS);
+
+        assert_eq!(simulation.stats.judged(), 0);
+        assert!(!simulation.circles[0].hit);
+    }
+
+    #[test]
+    fn a_miss_breaks_the_combo_but_keeps_the_max_combo() {
+        let times = [2.0, 2.5, 3.0, 3.5];
+        let mut simulation = simulation(
+            &times.map(|time| beatmap_circle(time, 0.0, 0.0))
+        );
+        let mut frames = vec![press(1.98, CENTER), press(2.48, CENTER)];
+        frames.extend(idle(2.5, 3.2));
+        frames.push(press(3.48, CENTER));
+        run(&mut simulation, &frames);
+
+        let stats = &simulation.stats;
+        assert_eq!(stats.count_miss, 1);
+        assert_eq!(stats.combo, 1);
+        assert_eq!(stats.max_combo, 2);
+        assert_eq!(stats.grade(), Grade::C);
+    }
+
+    #[test]
+    fn the_whole_hit_circle_counts_until_the_hit_time() {
+        // The approach circle has almost closed, the hit circle keeps its full size
+        let mut simulation = simulation(&[beatmap_circle(2.0, 0.0, 0.0)]);
+        let edge = CENTER + Vec2::new(CIRCLE_MAX_RADIUS - 5.0, 0.0);
+        simulation.step(&press(1.99, edge), CENTER, SPAWN_RADIUS);
+
+        assert!(simulation.circles[0].hit);
+        assert_eq!(simulation.stats.count_300, 1);
+    }
+
+    #[test]
+    fn one_press_hits_only_one_of_two_overlapping_circles() {
+        let mut simulation = simulation(
+            &[beatmap_circle(2.0, 0.0, 0.0), beatmap_circle(2.1, 0.05, 0.0)]
+        );
+        simulation.step(&press(1.98, CENTER), CENTER, SPAWN_RADIUS);
+
+        assert_eq!(simulation.stats.judged(), 1);
+        assert!(simulation.circles[0].hit);
+        assert!(!simulation.circles[1].hit);
+    }
+
+    #[test]
+    fn the_same_frames_give_the_same_play() {
+        let beatmap_circles: Vec<BeatmapCircle> = (0..16)
+            .map(|i| {
+                let angle = (i as f32) * 0.7;
+                beatmap_circle(1.0 + (i as f64) * 0.4, angle.cos() * 0.6, angle.sin() * 0.6)
+            })
+            .collect();
+        // Hit every other circle a little late, with the cursor slightly off centre
+        let frames: Vec<ReplayFrame> = beatmap_circles
+            .iter()
+            .step_by(2)
+            .enumerate()
+            .map(|(i, circle)| {
+                let cursor = CENTER + Vec2::new(circle.x, circle.y) * SPAWN_RADIUS;
+                press(circle.time - 0.3 + (i as f64) * 0.03, cursor + Vec2::splat(5.0))
+            })
+            .chain(idle(8.0, 8.5))
+            .collect();
+
+        let mut first = simulation(&beatmap_circles);
+        let mut second = simulation(&beatmap_circles);
+        run(&mut first, &frames);
+        run(&mut second, &frames);
+
+        assert!(is_finished(&first));
+        assert_eq!(first.stats, second.stats);
+        assert_eq!(first.stats.judged(), 16);
+    }
+
+    #[test]
+    fn autoplay_follows_every_kind_of_slider() {
+        let mut simulation = simulation(
+            &[
+                beatmap_circle(1.0, -0.5, 0.0),
+                beatmap_slider(2.0, -0.5, 0.3, CurveType::Linear, &[Vec2::new(0.0, 0.3)], 2),
+                beatmap_slider(4.0, 0.0, -0.5, CurveType::PerfectCircle, &[
+                    Vec2::new(0.2, -0.3),
+                    Vec2::new(0.4, -0.5),
+                ], 1),
+                beatmap_slider(5.5, 0.5, 0.0, CurveType::Bezier, &[
+                    Vec2::new(0.6, 0.3),
+                    Vec2::new(0.3, 0.3),
+                    Vec2::new(0.3, 0.5),
+                ], 3),
+                beatmap_circle(8.0, 0.0, 0.0),
+            ]
+        );
+        for frame_index in 0..9 * 60 {
+            let time = (frame_index as f64) / 60.0;
+            let frame = auto_frame(&simulation.circles, time, CENTER, SPAWN_RADIUS);
+            simulation.step(&frame, CENTER, SPAWN_RADIUS);
+        }
+
+        // Every circle, slider head and checkpoint adds to the combo
+        let checks: usize = simulation.circles
+            .iter()
+            .map(|circle| circle.slider.as_ref().map_or(1, |slider| slider.checkpoints.len() + 1))
+            .sum();
+        let stats = &simulation.stats;
+        assert!(is_finished(&simulation));
+        assert_eq!((stats.count_300, stats.count_miss), (5, 0), "stats: {:?}", stats);
+        assert_eq!(stats.max_combo as usize, checks);
+        assert_eq!(stats.combo, stats.max_combo);
+    }
+
+    #[test]
+    fn letting_go_of_a_slider_lowers_its_judgement() {
+        let mut simulation = simulation(
+            &[beatmap_slider(2.0, 0.0, 0.0, CurveType::Linear, &[Vec2::new(0.5, 0.0)], 2)]
+        );
+        // Follow the ball through the repeat, then let go
+        let mut frames = vec![press(1.97, CENTER)];
+        for i in 0..=40 {
+            let time = 2.0 + (i as f64) / 60.0;
+            let ball = simulation.circles[0].slider.as_ref().unwrap().ball_position(time);
+            frames.push(hold(time, ball));
+        }
+        frames.extend(idle(2.7, 3.5));
+        run(&mut simulation, &frames);
+
+        // The head, two ticks and the repeat of the seven checks were hit
+        let slider = simulation.circles[0].slider.as_ref().unwrap();
+        assert_eq!((slider.checkpoints.len(), slider.checkpoints_hit), (6, 4));
+        let stats = &simulation.stats;
+        assert!(is_finished(&simulation));
+        assert_eq!((stats.count_300, stats.count_100), (0, 1));
+        assert_eq!((stats.combo, stats.max_combo), (0, 4));
+        assert_eq!(stats.hit_errors.len(), 1);
+    }
+
+    #[test]
+    fn an_unplayed_slider_is_missed_at_its_end() {
+        let mut simulation = simulation(
+            &[beatmap_slider(2.0, 0.0, 0.0, CurveType::Linear, &[Vec2::new(0.5, 0.0)], 1)]
+        );
+        let missed: Vec<Vec2> = idle(0.0, 3.0)
+            .iter()
+            .flat_map(|frame| simulation.step(frame, CENTER, SPAWN_RADIUS))
+            .collect();
+
+        assert_eq!(missed.len(), 1);
+        assert!(missed[0].distance(CENTER + Vec2::new(100.0, 0.0)) < 1e-3);
+        assert!(is_finished(&simulation));
+        assert_eq!((simulation.stats.count_miss, simulation.stats.judged()), (1, 1));
+    }
+
+    #[test]
+    fn autoplay_spins_spinners_for_bonus_score() {
+        let mut simulation = simulation(
+            &[
+                beatmap_circle(1.0, -0.5, 0.0),
+                beatmap_spinner(2.0, 2.0),
+                beatmap_circle(5.0, 0.5, 0.0),
+            ]
+        );
+        for frame_index in 0..6 * 60 {
+            let time = (frame_index as f64) / 60.0;
+            let frame = auto_frame(&simulation.circles, time, CENTER, SPAWN_RADIUS);
+            simulation.step(&frame, CENTER, SPAWN_RADIUS);
+        }
+
+        let spinner = simulation.circles[1].spinner.as_ref().unwrap();
+        assert_eq!(spinner.required_rotations, 5.0);
+        assert!(spinner.rotations > 11.0, "rotations: {}", spinner.rotations);
+        let stats = &simulation.stats;
+        assert!(is_finished(&simulation));
+        assert_eq!((stats.count_300, stats.max_combo), (3, 3), "stats: {:?}", stats);
+        assert!(stats.score > 5 * SPINNER_SPIN_SCORE + 6 * SPINNER_BONUS_SCORE);
+        assert_eq!(stats.hit_errors.len(), 2);
+    }
+
+    #[test]
+    fn spinners_are_judged_by_how_much_they_were_spun() {
+        // Four of the five required rotations is not enough for a 300 or a 100
+        let mut slow = simulation(&[beatmap_spinner(2.0, 2.0)]);
+        run(&mut slow, &spin(2.0, 2.95, 4.0 / 0.95));
+        run(&mut slow, &idle(3.0, 4.1));
+        assert!(is_finished(&slow));
+        assert_eq!((slow.stats.count_50, slow.stats.combo), (1, 1));
+        assert_eq!(slow.stats.score, 4 * SPINNER_SPIN_SCORE + 50);
+
+        // Not spinning at all is a miss at the centre once the spinner ends
+        let mut idle_play = simulation(&[beatmap_spinner(2.0, 2.0)]);
+        let missed: Vec<Vec2> = idle(0.0, 4.1)
+            .iter()
+            .flat_map(|frame| idle_play.step(frame, CENTER, SPAWN_RADIUS))
+            .collect();
+        assert_eq!(missed, vec![CENTER]);
+        assert_eq!((idle_play.stats.count_miss, idle_play.stats.judged()), (1, 1));
+    }
+}
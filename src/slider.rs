@@ -0,0 +1,386 @@
+//! Slider paths and the ball that travels them.
+//!
+//! Paths are built like osu!: straight lines, an arc through three points or Bézier
+//! segments, then cut or extended to the slider's length. The ball travels the path
+//! once per span, turning back at each end, and must be followed through every tick,
+//! repeat and the end.
+
+use crate::constants::{ SLIDER_EDGE_SCORE, SLIDER_END_LENIENCY, SLIDER_TICK_SCORE };
+use crate::structs::{
+    CheckpointKind,
+    CurveType,
+    Judgement,
+    Slider,
+    SliderCheckpoint,
+    SliderPath,
+};
+use macroquad::math::Vec2;
+use std::f32::consts::TAU;
+
+// Points per Bézier segment
+const BEZIER_STEPS: usize = 32;
+
+// Length of the straight pieces an arc is drawn with, in playfield radii
+const ARC_STEP: f32 = 0.02;
+
+// Ticks closer than this to the end of a span are left out, in seconds
+const TICK_MARGIN: f64 = 0.01;
+
+impl CurveType {
+    pub fn from_name(name: &str) -> Option<CurveType> {
+        [CurveType::Linear, CurveType::PerfectCircle, CurveType::Bezier]
+            .into_iter()
+            .find(|curve| format!("{:?}", curve) == name)
+    }
+}
+
+impl SliderPath {
+    /// Points of the path from the head, cut or extended to the slider length
+    pub fn points(&self, head: Vec2) -> Vec<Vec2> {
+        let mut control_points = vec![head];
+        control_points.extend(&self.control_points);
+        cut_to_length(&curve_points(self.curve, &control_points), self.length)
+    }
+
+    /// Seconds from the head until the ball stops
+    pub fn duration(&self) -> f64 {
+        (self.spans as f64) * self.span_duration
+    }
+}
+
+impl Slider {
+    /// Lay out a slider whose head is at `head` on the playfield, starting at `start_time`
+    pub fn new(
+        path: &SliderPath,
+        head: Vec2,
+        start_time: f64,
+        center: Vec2,
+        spawn_radius: f32
+    ) -> Self {
+        let mut slider = Slider {
+            path: path
+                .points(head)
+                .into_iter()
+                .map(|point| center + point * spawn_radius)
+                .collect(),
+            start_time,
+            spans: path.spans.max(1),
+            span_duration: path.span_duration.max(0.0),
+            checkpoints: Vec::new(),
+            judged_checkpoints: 0,
+            checkpoints_hit: 0,
+            head_error: None,
+            tracking: false,
+            finished: false,
+        };
+        slider.checkpoints = slider.layout_checkpoints(path.tick_interval);
+        slider
+    }
+
+    /// Ticks every `tick_interval` along each span, then a repeat or the end
+    fn layout_checkpoints(&self, tick_interval: f64) -> Vec<SliderCheckpoint> {
+        let mut checkpoints = Vec::new();
+        for span in 0..self.spans {
+            let span_start = self.start_time + (span as f64) * self.span_duration;
+            if tick_interval > 0.0 {
+                let mut tick_time = tick_interval;
+                while tick_time < self.span_duration - TICK_MARGIN {
+                    let time = span_start + tick_time;
+                    checkpoints.push(SliderCheckpoint {
+                        time,
+                        position: self.ball_position(time),
+                        kind: CheckpointKind::Tick,
+                    });
+                    tick_time += tick_interval;
+                }
+            }
+
+            let span_end = span_start + self.span_duration;
+            let (time, kind) = if span + 1 == self.spans {
+                // Like osu!, the end is judged a little early so it can be let go on time
+                (span_end - SLIDER_END_LENIENCY.min(self.span_duration / 2.0), CheckpointKind::End)
+            } else {
+                (span_end, CheckpointKind::Repeat)
+            };
+            let position = self.ball_position(span_end);
+            checkpoints.push(SliderCheckpoint { time, position, kind });
+        }
+        checkpoints
+    }
+
+    /// When the ball stops
+    pub fn end_time(&self) -> f64 {
+        self.start_time + (self.spans as f64) * self.span_duration
+    }
+
+    /// Where the ball stops
+    pub fn end_position(&self) -> Vec2 {
+        self.ball_position(self.end_time())
+    }
+
+    /// Where the ball is at a time, resting on the head before the start and at the
+    /// end after it
+    pub fn ball_position(&self, time: f64) -> Vec2 {
+        if self.span_duration <= 0.0 {
+            return self.position_at(if self.spans.is_multiple_of(2) { 0.0 } else { 1.0 });
+        }
+        let elapsed = (time - self.start_time).clamp(0.0, self.end_time() - self.start_time);
+        let span = ((elapsed / self.span_duration) as u32).min(self.spans - 1);
+        let progress = (elapsed / self.span_duration - (span as f64)).clamp(0.0, 1.0) as f32;
+        self.position_at(if span.is_multiple_of(2) { progress } else { 1.0 - progress })
+    }
+
+    /// The point a share of the way along the path
+    fn position_at(&self, progress: f32) -> Vec2 {
+        point_at_distance(&self.path, progress * path_length(&self.path))
+    }
+
+    /// osu! judgement of a finished slider from the share of its head and checkpoints
+    /// that were hit: 300 for all of them, 100 for at least half, 50 for any, or a miss
+    pub fn judgement(&self) -> Judgement {
+        let total = self.checkpoints.len() + 1;
+        if self.checkpoints_hit >= total {
+            Judgement::Hit300
+        } else if 2 * self.checkpoints_hit >= total {
+            Judgement::Hit100
+        } else if self.checkpoints_hit > 0 {
+            Judgement::Hit50
+        } else {
+            Judgement::Miss
+        }
+    }
+}
+
+impl CheckpointKind {
+    /// Score for following the ball through the checkpoint
+    pub fn score(self) -> u64 {
+        match self {
+            CheckpointKind::Tick => SLIDER_TICK_SCORE,
+            CheckpointKind::Repeat | CheckpointKind::End => SLIDER_EDGE_SCORE,
+        }
+    }
+}
+
+/// Points along the curve through a list of control points, before it is cut to length.
+///
+/// Perfect circles need exactly three points that are not on a line, other ones are
+/// drawn as Bézier segments or straight lines like osu! does.
+pub fn curve_points(curve: CurveType, points: &[Vec2]) -> Vec<Vec2> {
+    match curve {
+        CurveType::Linear => points.to_vec(),
+        CurveType::PerfectCircle if points.len() == 3 => {
+            circular_arc(points[0], points[1], points[2]).unwrap_or_else(|| points.to_vec())
+        }
+        CurveType::PerfectCircle | CurveType::Bezier => bezier_path(points),
+    }
+}
+
+/// Bézier segments through the control points, a new segment starting wherever a
+/// point is repeated
+fn bezier_path(points: &[Vec2]) -> Vec<Vec2> {
+    let mut path: Vec<Vec2> = Vec::new();
+    let mut segment: Vec<Vec2> = Vec::new();
+    for (index, &point) in points.iter().enumerate() {
+        segment.push(point);
+        let segment_ends = index + 1 == points.len() || points[index + 1] == point;
+        if segment_ends {
+            for step in 0..=BEZIER_STEPS {
+                let point = de_casteljau(&segment, (step as f32) / (BEZIER_STEPS as f32));
+                if path.last() != Some(&point) {
+                    path.push(point);
+                }
+            }
+            segment.clear();
+        }
+    }
+    path
+}
+
+/// The point of a Bézier curve at `t` from 0 to 1
+fn de_casteljau(points: &[Vec2], t: f32) -> Vec2 {
+    let mut points = points.to_vec();
+    for level in (1..points.len()).rev() {
+        for i in 0..level {
+            points[i] = points[i].lerp(points[i + 1], t);
+        }
+    }
+    points[0]
+}
+
+/// The arc from `a` through `b` to `c`, or None when the points are on a line
+fn circular_arc(a: Vec2, b: Vec2, c: Vec2) -> Option<Vec<Vec2>> {
+    let d = 2.0 * (a.x * (b.y - c.y) + b.x * (c.y - a.y) + c.x * (a.y - b.y));
+    if d.abs() < 1e-6 {
+        return None;
+    }
+    let (a_sq, b_sq, c_sq) = (a.length_squared(), b.length_squared(), c.length_squared());
+    let center = Vec2::new(
+        (a_sq * (b.y - c.y) + b_sq * (c.y - a.y) + c_sq * (a.y - b.y)) / d,
+        (a_sq * (c.x - b.x) + b_sq * (a.x - c.x) + c_sq * (b.x - a.x)) / d
+    );
+    let radius = a.distance(center);
+    let start_angle = (a - center).to_angle();
+    let mut end_angle = (c - center).to_angle();
+    while end_angle < start_angle {
+        end_angle += TAU;
+    }
+
+    // Go the other way round when that is the way that passes through `b`
+    let mut direction = 1.0;
+    let mut range = end_angle - start_angle;
+    let ortho = Vec2::new(c.y - a.y, -(c.x - a.x));
+    if ortho.dot(b - a) < 0.0 {
+        direction = -1.0;
+        range = TAU - range;
+    }
+
+    let steps = ((range * radius) / ARC_STEP).ceil().clamp(2.0, 1000.0) as usize;
+    Some(
+        (0..=steps)
+            .map(|step| {
+                let angle = start_angle + direction * range * (step as f32) / (steps as f32);
+                center + Vec2::from_angle(angle) * radius
+            })
+            .collect()
+    )
+}
+
+/// Total length of a path of straight pieces
+pub fn path_length(path: &[Vec2]) -> f32 {
+    path.windows(2)
+        .map(|pair| pair[0].distance(pair[1]))
+        .sum()
+}
+
+/// The point `distance` along a path of straight pieces, clamped to its ends
+fn point_at_distance(path: &[Vec2], distance: f32) -> Vec2 {
+    let mut remaining = distance.max(0.0);
+    for pair in path.windows(2) {
+        let piece = pair[0].distance(pair[1]);
+        if remaining <= piece && piece > 0.0 {
+            return pair[0].lerp(pair[1], remaining / piece);
+        }
+        remaining -= piece;
+    }
+    path.last().copied().unwrap_or_default()
+}
+
+/// Cut a path to a length, or extend its last piece in a straight line to reach it
+fn cut_to_length(path: &[Vec2], length: f32) -> Vec<Vec2> {
+    let Some(&first) = path.first() else {
+        return Vec::new();
+    };
+    let mut cut = vec![first];
+    let mut travelled = 0.0;
+    for pair in path.windows(2) {
+        let piece = pair[0].distance(pair[1]);
+        if travelled + piece >= length {
+            if piece > 0.0 {
+                cut.push(pair[0].lerp(pair[1], (length - travelled) / piece));
+            }
+            return cut;
+        }
+        travelled += piece;
+        cut.push(pair[1]);
+    }
+
+    if let [.., before, last] = cut[..] {
+        if let Some(direction) = (last - before).try_normalize() {
+            let end = last + direction * (length - travelled);
+            cut.push(end);
+        }
+    }
+    cut
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn slider_path(
+        curve: CurveType,
+        control_points: &[Vec2],
+        length: f32,
+        spans: u32
+    ) -> SliderPath {
+        SliderPath {
+            curve,
+            control_points: control_points.to_vec(),
+            length,
+            spans,
+            span_duration: 1.0,
+            tick_interval: 0.25,
+        }
+    }
+
+    fn assert_near(actual: Vec2, expected: Vec2) {
+        assert!(actual.distance(expected) < 1e-3, "{} is not near {}", actual, expected);
+    }
+
+    #[test]
+    fn linear_paths_are_cut_or_extended_to_their_length() {
+        let head = Vec2::ZERO;
+        let short = slider_path(CurveType::Linear, &[Vec2::new(0.5, 0.0)], 0.3, 1).points(head);
+        let long = slider_path(CurveType::Linear, &[Vec2::new(0.5, 0.0)], 0.8, 1).points(head);
+
+        assert!((path_length(&short) - 0.3).abs() < 1e-5);
+        assert_near(*short.last().unwrap(), Vec2::new(0.3, 0.0));
+        assert_near(*long.last().unwrap(), Vec2::new(0.8, 0.0));
+    }
+
+    #[test]
+    fn perfect_circles_pass_through_their_middle_point() {
+        // A half circle of radius 0.5 around the origin, going over the top
+        let points = curve_points(CurveType::PerfectCircle, &[
+            Vec2::new(-0.5, 0.0),
+            Vec2::new(0.0, -0.5),
+            Vec2::new(0.5, 0.0),
+        ]);
+
+        assert_near(points[0], Vec2::new(-0.5, 0.0));
+        assert_near(*points.last().unwrap(), Vec2::new(0.5, 0.0));
+        assert!(points.iter().all(|point| (point.length() - 0.5).abs() < 1e-4));
+        assert!(points.iter().all(|point| point.y <= 1e-4));
+        assert!((path_length(&points) - 0.5 * std::f32::consts::PI).abs() < 0.01);
+
+        // Points on a line make a straight slider
+        let line = [Vec2::ZERO, Vec2::new(0.25, 0.0), Vec2::new(0.5, 0.0)];
+        assert_eq!(curve_points(CurveType::PerfectCircle, &line), line.to_vec());
+    }
+
+    #[test]
+    fn bezier_segments_split_at_repeated_points() {
+        let corner = Vec2::new(0.5, 0.0);
+        let control_points = [Vec2::ZERO, corner, corner, Vec2::new(0.5, 0.5)];
+        let points = curve_points(CurveType::Bezier, &control_points);
+
+        // Two straight segments meeting at a sharp corner
+        assert_near(points[0], Vec2::ZERO);
+        assert!(points.contains(&corner));
+        assert_near(*points.last().unwrap(), Vec2::new(0.5, 0.5));
+        assert!((path_length(&points) - 1.0).abs() < 1e-4);
+    }
+
+    #[test]
+    fn the_ball_turns_back_at_every_repeat() {
+        let path = slider_path(CurveType::Linear, &[Vec2::new(0.5, 0.0)], 0.5, 3);
+        let slider = Slider::new(&path, Vec2::ZERO, 2.0, Vec2::ZERO, 100.0);
+
+        assert_near(slider.ball_position(1.0), Vec2::ZERO);
+        assert_near(slider.ball_position(2.5), Vec2::new(25.0, 0.0));
+        assert_near(slider.ball_position(3.0), Vec2::new(50.0, 0.0));
+        assert_near(slider.ball_position(3.75), Vec2::new(12.5, 0.0));
+        assert_near(slider.end_position(), Vec2::new(50.0, 0.0));
+        assert_eq!(slider.end_time(), 5.0);
+
+        // Three ticks per span, two repeats and the end
+        let kinds: Vec<CheckpointKind> = slider.checkpoints
+            .iter()
+            .map(|checkpoint| checkpoint.kind)
+            .collect();
+        assert_eq!(kinds.iter().filter(|&&kind| kind == CheckpointKind::Tick).count(), 9);
+        assert_eq!(kinds.iter().filter(|&&kind| kind == CheckpointKind::Repeat).count(), 2);
+        assert_eq!(kinds.last(), Some(&CheckpointKind::End));
+        assert!(slider.checkpoints.windows(2).all(|pair| pair[0].time < pair[1].time));
+    }
+}
@@ -0,0 +1,107 @@
+//! Spinners and how fast they are spun.
+//!
+//! Like osu!, a spinner needs a number of rotations that grows with its length and the
+//! overall difficulty. Every frame a key is held, the turn of the cursor around the
+//! centre since the previous frame is added, up to `SPINNER_MAX_RPS`.
+
+use crate::constants::SPINNER_MAX_RPS;
+use crate::structs::{ Judgement, Spinner };
+use std::f32::consts::{ PI, TAU };
+
+// Rotations per second needed for a 300 at OD 0, 5 and 10
+const SPINS_PER_SECOND: (f64, f64, f64) = (1.5, 2.5, 3.75);
+
+// Share of the measured rate the displayed rate moves toward each frame
+const RATE_SMOOTHING: f64 = 0.1;
+
+impl Spinner {
+    /// A spinner from `start_time` lasting `duration` seconds
+    pub fn new(start_time: f64, duration: f64, overall_difficulty: f32) -> Self {
+        let od = overall_difficulty as f64;
+        let (low, mid, high) = SPINS_PER_SECOND;
+        let spins_per_second = if od < 5.0 {
+            low + ((mid - low) * od) / 5.0
+        } else {
+            mid + ((high - mid) * (od - 5.0)) / 5.0
+        };
+        Spinner {
+            start_time,
+            end_time: start_time + duration,
+            required_rotations: (duration * spins_per_second).floor().max(1.0),
+            rotations: 0.0,
+            rate: 0.0,
+            last_angle: None,
+            finished: false,
+        }
+    }
+
+    /// Spin to the cursor `angle` around the centre at `time`, counting the turn since
+    /// the previous frame if a key was held through both
+    pub fn spin(&mut self, time: f64, angle: f32, key_held: bool) {
+        if !key_held {
+            self.last_angle = None;
+            self.rate *= 1.0 - RATE_SMOOTHING;
+            return;
+        }
+        if let Some((last_time, last_angle)) = self.last_angle {
+            let delta_time = time - last_time;
+            // The shortest way round, so crossing the negative x axis is not a whole turn
+            let delta = (angle - last_angle + PI).rem_euclid(TAU) - PI;
+            let turn = ((delta.abs() / TAU) as f64).min(SPINNER_MAX_RPS * delta_time);
+            self.rotations += turn;
+            if delta_time > 0.0 {
+                self.rate += (turn / delta_time - self.rate) * RATE_SMOOTHING;
+            }
+        }
+        self.last_angle = Some((time, angle));
+    }
+
+    /// Share of the required rotations spun so far, from 0 to 1
+    pub fn progress(&self) -> f64 {
+        (self.rotations / self.required_rotations).min(1.0)
+    }
+
+    /// Judgement by how many of the required rotations were spun: 300, 100, 50 or a miss
+    pub fn judgement(&self) -> Judgement {
+        let progress = self.rotations / self.required_rotations;
+        if progress >= 1.0 {
+            Judgement::Hit300
+        } else if progress > 0.9 {
+            Judgement::Hit100
+        } else if progress > 0.75 {
+            Judgement::Hit50
+        } else {
+            Judgement::Miss
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn harder_spinners_need_more_rotations() {
+        assert_eq!(Spinner::new(0.0, 2.0, 0.0).required_rotations, 3.0);
+        assert_eq!(Spinner::new(0.0, 2.0, 5.0).required_rotations, 5.0);
+        assert_eq!(Spinner::new(0.0, 2.0, 10.0).required_rotations, 7.0);
+        assert_eq!(Spinner::new(0.0, 0.1, 5.0).required_rotations, 1.0);
+    }
+
+    #[test]
+    fn spinning_counts_turns_while_a_key_is_held() {
+        let mut spinner = Spinner::new(0.0, 2.0, 5.0);
+        // A quarter turn per frame, across the negative x axis and back the other way
+        for (i, angle) in [0.0, PI / 2.0, PI, -PI / 2.0, 0.0, -PI / 2.0].iter().enumerate() {
+            spinner.spin((i as f64) * 0.1, *angle, true);
+        }
+        assert!((spinner.rotations - 1.25).abs() < 1e-6);
+
+        // Turns with no key held and turns faster than the limit are not counted
+        spinner.spin(0.6, 0.0, false);
+        spinner.spin(0.7, PI / 2.0, true);
+        assert!((spinner.rotations - 1.25).abs() < 1e-6);
+        spinner.spin(0.701, PI, true);
+        assert!((spinner.rotations - 1.258).abs() < 1e-6);
+    }
+}
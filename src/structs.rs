@@ -1,39 +1,142 @@
 // src/structs.rs
 
-use macroquad::prelude::Vec2;
+use macroquad::prelude::{ KeyCode, MouseButton, Vec2 };
 use macroquad::text::Font;
 use std::time::Instant;
-use std::sync::mpsc;
+use std::sync::{ atomic::{ AtomicBool, AtomicU64 }, mpsc, Arc };
 use rodio::Decoder;
 use std::io::BufReader;
 use std::fs::File;
+use std::path::PathBuf;
+use serde::{ Deserialize, Serialize };
 
 pub struct Assets {
     pub cyberpunk_font: Font,
 }
 
+/// A playable entry of the song selection list
+pub struct SongEntry {
+    pub path: String,
+    pub bpm: Option<f64>, // Known once the song has been analysed or for imported charts
+    pub star_ratings: Vec<(Option<DifficultyLevel>, f64)>, // Stars of each analysed level, or of the chart
+}
+
 pub struct SongSelectionState {
     pub scroll_pos: f32,
-    pub selected_song: Option<String>,
 }
 
 pub enum GameState {
     Menu,
     SongSelection,
     Playing,
-    Settings,
+    Settings(SettingsScreenState),
     Exit,
     Loading {
-        rx: mpsc::Receiver<Vec<f64>>,
-        start_time: Instant,
+        rx: mpsc::Receiver<LoadingMessage>,
+        cancel: Arc<AtomicBool>,
+        stage: LoadingStage,
+        progress: f32, // Progress of the current stage, 0 to 1
     },
     ReadyToPlay {
-        beats: Vec<f64>,
+        beatmap: Box<Beatmap>,
         ready_time: Instant,
-        source: Option<Decoder<BufReader<File>>>,
+        source: Option<Box<Decoder<BufReader<File>>>>,
     },
     Visualizing(Box<VisualizingState>),
-    End,
+    Results(Box<VisualizingState>), // The finished play
+    Replays(ReplayBrowserState),
+    Calibrating(Box<CalibrationState>),
+    Profile,
+    Error(Box<LoadError>),
+}
+
+/// Player settings, stored as TOML in the user config directory
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Settings {
+    pub volume: f32, // 0 to 1
+    pub audio_offset_ms: i32, // Delay added to every hit time, positive when the audio is heard late
+    pub key_bindings: [String; 2], // macroquad key names, like "A"
+    pub approach_rate: Option<f32>, // osu! approach rate of generated maps, None to use the map's
+    pub circle_radius: Option<f32>, // Radius of generated circles in pixels, None to use the map's
+    pub placement_seed: Option<u64>, // Seed for placing generated circles, None to derive it from the audio
+    pub channel_mode: ChannelMode, // How multi-channel songs are analysed
+    pub snap_division: Option<SnapDivision>, // Beat grid division onsets snap to, None to not snap
+    pub fullscreen: bool,
+    pub window_width: i32,
+    pub window_height: i32,
+}
+
+/// One editable line of the settings screen, in display order
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SettingsRow {
+    Volume,
+    AudioOffset,
+    PrimaryKey,
+    SecondaryKey,
+    ApproachRate,
+    CircleSize,
+    PlacementSeed,
+    ChannelMode,
+    SnapDivision,
+    Fullscreen,
+    Resolution,
+    Calibrate,
+    Back,
+}
+
+#[derive(Default)]
+pub struct SettingsScreenState {
+    pub selected: usize, // Index into `SettingsRow::ALL`
+    pub rebinding: bool, // Waiting for the next key press to bind to the selected row
+}
+
+/// Steps of loading a song, in the order they run
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LoadingStage {
+    Decoding,
+    Filtering,
+    DetectingOnsets,
+    TrackingTempo,
+}
+
+/// Messages sent from the analysis thread to the loading screen
+pub enum LoadingMessage {
+    Progress {
+        stage: LoadingStage,
+        fraction: f32,
+    },
+    Done(Box<Beatmap>),
+    Failed(LoadError),
+    Cancelled, // The analysis stopped after a cancel request
+}
+
+/// Why a song or chart could not be loaded
+#[derive(Debug)]
+pub enum LoadError {
+    Open {
+        path: String,
+        source: std::io::Error,
+    },
+    Decode {
+        path: String,
+        source: rodio::decoder::DecoderError,
+    },
+    Analysis {
+        path: String,
+        message: String,
+    },
+    InvalidChart {
+        path: String,
+        source: std::io::Error,
+    },
+    Cancelled, // Not a failure: the player cancelled loading
+}
+
+/// Progress reporting and cancellation shared with the analysis thread
+pub struct AnalysisProgress {
+    pub tx: mpsc::Sender<LoadingMessage>,
+    pub cancel: Arc<AtomicBool>,
 }
 
 pub struct Circle {
@@ -43,6 +146,48 @@ pub struct Circle {
     pub max_radius: f32,
     pub hit: bool,
     pub missed: bool,
+    pub slider: Option<Slider>, // The rest of the slider this circle is the head of
+    pub spinner: Option<Spinner>, // Set when this is a spinner instead of a circle
+}
+
+/// A slider being played: its path on screen and the checks judged along it
+pub struct Slider {
+    pub path: Vec<Vec2>, // Points of the path in screen pixels, cut to the slider length
+    pub start_time: f64, // When the ball leaves the head
+    pub spans: u32,
+    pub span_duration: f64,
+    pub checkpoints: Vec<SliderCheckpoint>, // Ticks, repeats and the end, in time order
+    pub judged_checkpoints: usize,
+    pub checkpoints_hit: usize, // Including the head
+    pub head_error: Option<f64>, // Hit error of the head, None until it is hit
+    pub tracking: bool, // The ball was followed at the last judged frame
+    pub finished: bool, // The slider has been given its judgement
+}
+
+/// A spinner being played: a key is held while the cursor spins around the centre
+pub struct Spinner {
+    pub start_time: f64,
+    pub end_time: f64,
+    pub required_rotations: f64, // Rotations needed for a 300, from the spinner length and OD
+    pub rotations: f64, // Turns spun so far, in either direction
+    pub rate: f64, // Smoothed rotations per second, for display
+    pub last_angle: Option<(f64, f32)>, // Time and cursor angle of the last frame a key was held
+    pub finished: bool,
+}
+
+/// A point of a slider the ball must be followed through
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SliderCheckpoint {
+    pub time: f64,
+    pub position: Vec2,
+    pub kind: CheckpointKind,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CheckpointKind {
+    Tick,
+    Repeat, // The ball turns back
+    End,
 }
 
 pub struct FloatingText {
@@ -52,10 +197,345 @@ pub struct FloatingText {
     pub duration: f64,
 }
 
-pub struct VisualizingState {
-    pub beats: Vec<f64>,
-    pub start_time: Instant,
+/// The judged state of a play, advanced one input frame at a time
+pub struct Simulation {
     pub circles: Vec<Circle>,
-    pub score: i32,
+    pub stats: PlayStats,
+    pub approach_time: f64,
+    pub hit_windows: HitWindows,
+}
+
+/// How well a circle, slider or spinner was played
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Judgement {
+    Hit300,
+    Hit100,
+    Hit50,
+    Miss,
+}
+
+/// Largest hit errors in seconds, either side of the hit time, for each judgement
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct HitWindows {
+    pub hit_300: f64,
+    pub hit_100: f64,
+    pub hit_50: f64,
+    pub miss: f64, // Earlier presses than the 50 window up to this one are misses
+}
+
+pub struct VisualizingState {
+    pub clock: PlaybackClock,
+    pub simulation: Simulation,
     pub floating_texts: Vec<FloatingText>,
+    pub hit_input: HitInput,
+    pub beatmap: Box<Beatmap>,
+    pub replay: Replay, // Recorded while playing, or the replay being watched
+    pub playback: Option<usize>, // Next frame of the replay being watched, None while playing
+    pub replay_path: Option<PathBuf>, // Where the recorded replay was saved
+    pub rating: Option<StarRating>, // Of the beatmap at the difficulty it is played with
+}
+
+/// Gameplay modifiers of a play, stored as bit flags in replays
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Mods {
+    pub bits: u32,
+}
+
+/// Receives the window's key and mouse events in the order they arrived, instead of
+/// the state once per rendered frame
+pub struct InputQueue {
+    pub subscriber: usize, // macroquad input subscriber the events are taken from
+    pub last_poll: Instant, // When the events were last taken
+}
+
+/// A key or mouse event and when the game received it
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct InputEvent {
+    pub received: Instant,
+    pub kind: InputKind,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum InputKind {
+    KeyDown(KeyCode),
+    KeyUp(KeyCode),
+    MouseDown(MouseButton),
+    MouseUp(MouseButton),
+    MouseMove(Vec2), // Screen position in pixels
+}
+
+/// The hit keys and cursor of a play, followed through its input events
+pub struct HitInput {
+    pub hit_keys: [KeyCode; 2],
+    pub keys_held: [bool; 2],
+    pub buttons_held: [bool; 2], // The left and right mouse buttons hit like the two keys
+    pub cursor: Vec2,
+    pub last_time: f64, // Song position of the last frame, event times never go back before it
+}
+
+/// Input of one frame. The cursor is normalised like `BeatmapCircle` positions
+/// so replays do not depend on the window size.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ReplayFrame {
+    pub time: f64, // Song position in seconds
+    pub x: f32,
+    pub y: f32,
+    pub keys: u8, // Held keys in the low bits, keys pressed during this frame in the high bits
+}
+
+/// The inputs of a play and everything needed to judge them again
+#[derive(Clone, Debug, PartialEq)]
+pub struct Replay {
+    pub song_path: String, // Song or osu! chart the beatmap was loaded from, empty when unknown
+    pub level: Option<DifficultyLevel>, // Level of a generated beatmap, None for imported charts
+    pub beatmap_hash: u64,
+    pub mods: Mods,
+    pub audio_offset: f64, // Offset the circles were timed with, in seconds
+    pub approach_time: f64,
+    pub circle_radius: f32,
+    pub score: u64, // Final score of the recorded play, to verify playback against
+    pub frames: Vec<ReplayFrame>,
+}
+
+/// A saved replay as listed in the replay browser
+pub struct ReplayEntry {
+    pub path: PathBuf,
+    pub song_path: String,
+    pub level: Option<DifficultyLevel>,
+    pub mods: Mods,
+    pub score: u64,
+}
+
+#[derive(Default)]
+pub struct ReplayBrowserState {
+    pub replays: Vec<ReplayEntry>, // Newest first
+    pub selected: usize, // Index into `replays`
+}
+
+/// Song position of the playing audio, derived from the samples the output has consumed
+/// and smoothed against the wall clock between the output's buffer pulls
+pub struct PlaybackClock {
+    pub samples_played: Arc<AtomicU64>, // Shared with the `ClockedSource` feeding the sink
+    pub sample_rate: u32,
+    pub channels: u16,
+    pub position: f64, // Smoothed song position in seconds
+    pub last_update: Instant,
+    pub last_samples: u64,
+    pub last_advance: Instant, // When the sample counter last moved
+}
+
+/// A running offset calibration: a metronome plays and the player taps along
+pub struct CalibrationState {
+    pub clock: PlaybackClock,
+    pub beat_times: Vec<f64>, // Song positions of the clicks
+    pub taps: Vec<f64>, // Song positions of the player's taps
+    pub finished: bool, // The click track has ended
+    pub result: Option<CalibrationResult>, // None until finished, or without usable taps
+}
+
+/// How the player's taps lined up with the metronome
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CalibrationResult {
+    pub offset: f64, // Mean tap error in seconds, positive when tapping after the click
+    pub jitter: f64, // Standard deviation of the tap errors in seconds
+    pub taps: usize, // Taps that counted towards the result
+}
+
+/// Score, combo and judgement counts of a play
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PlayStats {
+    pub score: u64,
+    pub combo: u32,
+    pub max_combo: u32,
+    pub count_300: u32,
+    pub count_100: u32,
+    pub count_50: u32,
+    pub count_miss: u32,
+    pub hit_errors: Vec<f64>, // Seconds between each hit and its circle's hit time, negative when early
+}
+
+/// The local player's progression, stored as TOML next to the settings
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Profile {
+    pub play_count: u32, // Finished plays, without Auto
+    pub total_hits: u64, // 300s, 100s and 50s of every finished play
+    pub play_time: f64, // Seconds spent in finished plays
+    pub best_plays: Vec<BestPlay>, // Best play of each beatmap, highest pp first
+}
+
+/// The best play of a beatmap
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct BestPlay {
+    pub title: String,
+    pub beatmap_hash: String, // Hex, like replay file names, as TOML integers are signed
+    pub stars: f64,
+    pub pp: f64,
+    pub score: u64,
+    pub accuracy: f64,
+    pub grade: Grade,
+}
+
+/// Letter grade of a finished play, from best to worst
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Grade {
+    SS,
+    S,
+    A,
+    B,
+    C,
+    D,
+}
+
+/// How multi-channel audio is fed to the onset analysis
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum ChannelMode {
+    Downmix, // Average all channels into one mono signal
+    PerChannel, // Analyse every channel on its own and merge the onsets
+}
+
+/// Grid division onsets are snapped to, as a fraction of a beat
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum SnapDivision {
+    Whole,
+    Half,
+    Third,
+    Quarter,
+}
+
+/// Tempo and phase of a song
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BeatGrid {
+    pub bpm: f64,
+    pub offset: f64, // Time of the first beat in seconds
+}
+
+/// Frequency band an onset was detected in
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Band {
+    Kick,
+    Snare,
+    HiHat,
+}
+
+/// Filter and detection settings of one analysis band
+#[derive(Clone, Debug, PartialEq)]
+pub struct BandParams {
+    pub band: Band,
+    pub center_freq: f32, // Cutoff of the kick low-pass, centre of the other band-passes
+    pub q_factor: f32,
+    pub threshold: f32,
+}
+
+/// An onset found by the analysis
+#[derive(Clone, Copy, Debug)]
+pub struct Onset {
+    pub time: f64,
+    pub band: Band,
+    pub strength: f32, // 0 to 1, relative to the strongest onset of the band
+}
+
+/// A stretch of sustained low-frequency energy, like a held bass note
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Sustain {
+    pub start: f64,
+    pub end: f64,
+}
+
+/// Result of analysing a song
+pub struct SongAnalysis {
+    pub onsets: Vec<Onset>,
+    pub sustains: Vec<Sustain>, // Where generated maps place sliders and spinners
+    pub beat_grid: Option<BeatGrid>,
+}
+
+/// Parameters used by the onset analysis, stored with every beatmap so a cached
+/// map can be invalidated when the analysis changes.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AnalysisParams {
+    pub channel_mode: ChannelMode,
+    pub buffer_size: usize,
+    pub hop_size: usize,
+    pub silence: f32,
+    pub min_beat_gap: f64,
+    pub min_strength: f32, // Drop weaker onsets, mostly leakage from neighbouring bands
+    pub snap_division: Option<SnapDivision>, // Snap onsets to the beat grid when set
+    pub sustain_level: f32, // Share of the loudest low-frequency level that counts as sustained
+    pub min_sustain: f64, // Shortest sustain in seconds
+    pub bands: Vec<BandParams>, // Bands analysed in parallel
+}
+
+/// Difficulty a map is generated at from a song's analysis
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DifficultyLevel {
+    Easy,
+    Normal,
+    Hard,
+    Insane,
+}
+
+pub struct BeatmapMetadata {
+    pub title: String,
+    pub audio_path: String,
+    pub level: Option<DifficultyLevel>, // None for imported charts
+    pub chart_path: Option<String>, // The .osu file of an imported chart, None for generated maps
+}
+
+/// Gameplay difficulty settings of a beatmap
+#[derive(Clone, PartialEq)]
+pub struct BeatmapDifficulty {
+    pub approach_time: f64, // Seconds a circle is visible before its hit time
+    pub circle_radius: f32, // Maximum radius of the circles in pixels
+    pub overall_difficulty: f32, // osu!-style OD, 0 to 10
+}
+
+/// A circle as stored in a beatmap. The position is normalised to the unit disk
+/// around the playfield centre so the map is independent of the window size.
+pub struct BeatmapCircle {
+    pub time: f64,
+    pub x: f32,
+    pub y: f32,
+    pub band: Band,
+    pub strength: f32,
+    pub slider: Option<SliderPath>, // None for plain circles
+    pub spinner_duration: Option<f64>, // Seconds a spinner lasts, None for circles and sliders
+}
+
+/// Shape of a slider's path through its control points
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CurveType {
+    Linear,
+    PerfectCircle, // An arc through exactly three points
+    Bezier, // Bézier segments, split where a control point repeats
+}
+
+/// The path and timing of a slider in a beatmap, normalised like its head circle
+#[derive(Clone, Debug, PartialEq)]
+pub struct SliderPath {
+    pub curve: CurveType,
+    pub control_points: Vec<Vec2>, // After the head, which is the circle's position
+    pub length: f32, // The curve is cut or extended to this length
+    pub spans: u32, // Times the ball travels the path, turning back at each end
+    pub span_duration: f64, // Seconds per span
+    pub tick_interval: f64, // Seconds between slider ticks
+}
+
+pub struct Beatmap {
+    pub version: u32,
+    pub metadata: BeatmapMetadata,
+    pub difficulty: BeatmapDifficulty,
+    pub beat_grid: Option<BeatGrid>,
+    pub analysis: Option<AnalysisParams>, // None for imported maps
+    pub audio_hash: u64,
+    pub seed: Option<u64>, // Seed the circles were placed with, None for imported maps
+    pub star_rating: Option<StarRating>, // Computed once when the map is generated or imported
+    pub circles: Vec<BeatmapCircle>,
+}
+
+/// How hard a beatmap is, from the strain of aiming at and tapping its circles
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct StarRating {
+    pub aim: f64,
+    pub speed: f64,
+    pub stars: f64,
 }
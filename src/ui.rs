@@ -1,15 +1,44 @@
 use macroquad::{
     color::WHITE,
-    input::{ is_key_down, is_mouse_button_pressed, mouse_position, KeyCode, MouseButton },
-    prelude::Color,
-    shapes::{ draw_line, draw_rectangle, draw_rectangle_lines },
+    input::{
+        get_last_key_pressed,
+        is_key_down,
+        is_key_pressed,
+        is_mouse_button_pressed,
+        mouse_position,
+        KeyCode,
+        MouseButton,
+    },
+    prelude::{ Color, Vec2 },
+    shapes::{ draw_circle, draw_circle_lines, draw_line, draw_rectangle, draw_rectangle_lines },
     text::{ draw_text_ex, load_ttf_font, measure_text, TextParams },
     time::get_time,
     window::{ clear_background, screen_height, screen_width },
 };
-use crate::structs::{ Assets, SongSelectionState, FloatingText };
+use crate::structs::{
+    Assets,
+    CalibrationState,
+    Circle,
+    DifficultyLevel,
+    SongEntry,
+    SongSelectionState,
+    FloatingText,
+    Grade,
+    LoadError,
+    Mods,
+    PlayStats,
+    Profile,
+    ReplayBrowserState,
+    Settings,
+    SettingsRow,
+    SettingsScreenState,
+};
 use crate::constants::*;
+use crate::beatmap::song_entry;
+use crate::osu::import_osu_maps;
+use crate::settings::is_bindable;
 use std::fs;
+use std::path::Path;
 
 /// Load all UI assets, such as textures and fonts.
 ///
@@ -49,8 +78,7 @@ pub fn draw_menu(assets: &Assets) -> Option<String> {
     let scr_height = screen_height();
 
     // Set the animated gradient background (optional)
-    let elapsed = get_time(); // Assuming this tracks elapsed time
-    //draw_background(scr_width, scr_height, elapsed);
+    //draw_background(scr_width, scr_height, get_time());
 
     // Draw the title with neon glow
     let title_text = "YumOsu!";
@@ -75,16 +103,18 @@ pub fn draw_menu(assets: &Assets) -> Option<String> {
     // Button properties
     let button_width = 200.0;
     let button_height = 60.0;
-    let button_spacing = scr_height * 0.05; // 5% of screen height as spacing
+    let button_spacing = scr_height * 0.04; // 4% of screen height as spacing
 
-    // Calculate starting Y position for the buttons (start at 40% of the screen height)
-    let start_y = scr_height * 0.4;
+    // Calculate starting Y position for the buttons (start at 32% of the screen height)
+    let start_y = scr_height * 0.32;
 
     // Create a vector of buttons with labels and corresponding y-positions
-    let buttons = vec![
+    let buttons = [
         ("Start Game", start_y),
         ("Settings", start_y + button_height + button_spacing),
-        ("Exit", start_y + 2.0 * (button_height + button_spacing))
+        ("Profile", start_y + 2.0 * (button_height + button_spacing)),
+        ("Replays", start_y + 3.0 * (button_height + button_spacing)),
+        ("Exit", start_y + 4.0 * (button_height + button_spacing))
     ];
 
     // Loop through buttons and draw them
@@ -154,7 +184,6 @@ impl SongSelectionState {
     pub fn new() -> Self {
         Self {
             scroll_pos: 0.0,
-            selected_song: None,
         }
     }
 }
@@ -165,9 +194,9 @@ impl SongSelectionState {
 ///
 /// The `state` parameter is a `SongSelectionState` struct containing the state of the song selection menu.
 ///
-/// The `songs` parameter is a vector of strings containing the names of all the songs.
+/// The `songs` parameter is a slice of `SongEntry` structs with the path, tempo and star ratings of every song.
 ///
-/// The `selected_song` parameter is an `Option<String>` containing the selected song, if any.
+/// The `level` parameter picks which star rating of generated maps is shown.
 ///
 /// If the player has selected a song, the function returns `Some(song)`, where `song` is the selected song.
 ///
@@ -176,7 +205,8 @@ impl SongSelectionState {
 /// The function also draws the UI elements, such as the list of songs and the selected song.
 pub fn draw_choose_audio(
     state: &mut SongSelectionState,
-    songs: &[String],
+    songs: &[SongEntry],
+    level: DifficultyLevel,
     assets: &Assets
 ) -> Option<String> {
     clear_background(DARK_BACKGROUND);
@@ -212,7 +242,8 @@ pub fn draw_choose_audio(
     let vertical_gap = 20.0;
 
     // Iterate through the songs and draw them as buttons
-    for (i, song) in songs.iter().enumerate() {
+    for (i, entry) in songs.iter().enumerate() {
+        let song = &entry.path;
         let button_x = screen_w * 0.05; // 5% from the left edge
         let button_y =
             screen_h * 0.2 + (i as f32) * (SONG_ENTRY_HEIGHT + vertical_gap) - state.scroll_pos;
@@ -262,13 +293,14 @@ pub fn draw_choose_audio(
                 );
             }
 
-            // Extract the song name (last part of the path) and remove .mp3 from the end of it
+            // Extract the song name (last part of the path) and remove .mp3/.osu from the end of it
             let song_name = song
                 .split('/')
-                .last()
+                .next_back()
                 .unwrap_or(song)
                 .to_uppercase()
-                .replace(".MP3", "");
+                .replace(".MP3", "")
+                .replace(".OSU", "");
 
             // Measure text to center it within the scaled button
             let text_dimensions = measure_text(
@@ -288,6 +320,35 @@ pub fn draw_choose_audio(
                 ..Default::default()
             });
 
+            // Draw the star rating and tempo on the right side of the entry when they are known
+            let details: Vec<String> = [
+                entry.stars(level).map(|stars| format!("{:.2}*", stars)),
+                entry.bpm.map(|bpm| format!("{:.0} BPM", bpm)),
+            ]
+                .into_iter()
+                .flatten()
+                .collect();
+            if !details.is_empty() {
+                let details_text = details.join("  ");
+                let details_dimensions = measure_text(
+                    &details_text,
+                    Some(&assets.cyberpunk_font),
+                    CYBERPUNK_FONT_SIZE as u16,
+                    1.0
+                );
+                draw_text_ex(
+                    &details_text,
+                    scaled_button_x + scaled_button_width - details_dimensions.width - 10.0,
+                    text_y,
+                    TextParams {
+                        font: Some(&assets.cyberpunk_font),
+                        font_size: CYBERPUNK_FONT_SIZE as u16,
+                        color: NEON_PINK,
+                        ..Default::default()
+                    }
+                );
+            }
+
             // Check if the song entry is clicked
             if is_mouse_button_pressed(MouseButton::Left) && is_hovered {
                 return Some(song.clone());
@@ -302,8 +363,10 @@ pub fn draw_choose_audio(
 ///
 /// The function reads the `src/assets/music/` directory and loads all the songs with the `.mp3` extension.
 ///
-/// The function returns a vector of strings containing the names of all the songs.
-pub fn load_songs_from_assets() -> Vec<String> {
+/// Imported osu! charts from `src/assets/maps/` are listed after the songs.
+///
+/// The function returns a vector of `SongEntry` structs with the path, known tempo and star ratings of all the songs.
+pub fn load_songs_from_assets() -> Vec<SongEntry> {
     let mut songs = Vec::new();
     if let Ok(entries) = fs::read_dir("src/assets/music/") {
         for entry in entries.flatten() {
@@ -316,19 +379,23 @@ pub fn load_songs_from_assets() -> Vec<String> {
             }
         }
     }
+    songs.extend(import_osu_maps());
     songs
+        .into_iter()
+        .map(|path| song_entry(&path))
+        .collect()
 }
 
 /// Draw a loading bar.
 ///
 /// The loading bar is drawn in the center of the screen.
 ///
-/// The `elapsed_time` parameter is the elapsed time since the loading started.
+/// The `label` parameter describes the current loading step.
 ///
-/// The function draws a black rectangle with a white border and a loading bar inside.
+/// The `progress` parameter is the overall loading progress, from 0 to 1.
 ///
-/// The loading bar is a gray rectangle that fills up the black rectangle as the elapsed time increases.
-pub fn draw_loading_bar(elapsed_time: f32, assets: &Assets) {
+/// The function draws the label with a percentage above a purple bar that fills up as the progress increases.
+pub fn draw_loading_bar(label: &str, progress: f32, assets: &Assets) {
     let scr_width = screen_width();
     let scr_height = screen_height();
 
@@ -340,10 +407,11 @@ pub fn draw_loading_bar(elapsed_time: f32, assets: &Assets) {
     let bar_x = scr_width / 2.0 - bar_width / 2.0;
     let bar_y = scr_height / 2.0;
 
-    // Measure the "Loading..." text width to center it above the loading bar
-    let loading_text = "Loading...";
+    // Measure the loading text width to center it above the loading bar
+    let progress = progress.clamp(0.0, 1.0);
+    let loading_text = format!("{}... {:.0}%", label, progress * 100.0);
     let text_dimensions = measure_text(
-        loading_text,
+        &loading_text,
         Some(&assets.cyberpunk_font),
         CYBERPUNK_FONT_SIZE as u16,
         1.0
@@ -351,17 +419,25 @@ pub fn draw_loading_bar(elapsed_time: f32, assets: &Assets) {
     let text_x = (scr_width - text_dimensions.width) / 2.0; // Center horizontally
     let text_y = bar_y - 40.0; // Position 40 pixels above the loading bar
 
-    // Draw "Loading..." text centered above the loading bar
-    draw_text_ex(loading_text, text_x, text_y, TextParams {
+    // Draw the loading text centered above the loading bar
+    draw_text_ex(&loading_text, text_x, text_y, TextParams {
         font: Some(&assets.cyberpunk_font),
         font_size: CYBERPUNK_FONT_SIZE as u16,
         color: NEON_PINK,
         ..Default::default()
     });
 
-    // Draw neon loading bar
-    let progress = (elapsed_time % 2.0) / 2.0;
+    // Hint that loading can be cancelled
+    let hint_text = "Press Escape to cancel";
+    let hint_dimensions = measure_text(hint_text, Some(&assets.cyberpunk_font), 18, 1.0);
+    draw_text_ex(hint_text, (scr_width - hint_dimensions.width) / 2.0, bar_y + 70.0, TextParams {
+        font: Some(&assets.cyberpunk_font),
+        font_size: 18,
+        color: NEON_PURPLE,
+        ..Default::default()
+    });
 
+    // Draw neon loading bar
     draw_rectangle(bar_x, bar_y, bar_width, bar_height, NEON_PURPLE);
 
     // Draw the progress
@@ -381,6 +457,602 @@ pub fn draw_loading_bar(elapsed_time: f32, assets: &Assets) {
     }
 }
 
+/// Draw the screen shown when a song could not be loaded.
+///
+/// Returns true once the player asks to go back to the song selection.
+pub fn draw_error_screen(error: &LoadError, assets: &Assets) -> bool {
+    let scr_width = screen_width();
+    let scr_height = screen_height();
+
+    clear_background(DARK_BACKGROUND);
+
+    // Draw the heading in the error colour
+    let title_text = "Could not load song";
+    let title_dimensions = measure_text(title_text, Some(&assets.cyberpunk_font), FONT_SIZE, 1.0);
+    draw_text_ex(title_text, (scr_width - title_dimensions.width) / 2.0, scr_height * 0.25, TextParams {
+        font: Some(&assets.cyberpunk_font),
+        font_size: FONT_SIZE,
+        color: NEON_ORANGE,
+        ..Default::default()
+    });
+
+    // Name the failing file, then explain what went wrong below it
+    let file_name = error
+        .path()
+        .map(|path| {
+            std::path::Path
+                ::new(path)
+                .file_name()
+                .map_or(path.to_string(), |name| name.to_string_lossy().to_string())
+        })
+        .unwrap_or_default();
+    let file_dimensions = measure_text(
+        &file_name,
+        Some(&assets.cyberpunk_font),
+        CYBERPUNK_FONT_SIZE as u16,
+        1.0
+    );
+    draw_text_ex(&file_name, (scr_width - file_dimensions.width) / 2.0, scr_height * 0.35, TextParams {
+        font: Some(&assets.cyberpunk_font),
+        font_size: CYBERPUNK_FONT_SIZE as u16,
+        color: NEON_PINK,
+        ..Default::default()
+    });
+
+    // Wrap the message so long paths stay on screen
+    let max_width = scr_width * 0.8;
+    let mut lines: Vec<String> = Vec::new();
+    for word in error.to_string().split_whitespace() {
+        let candidate = match lines.last() {
+            Some(line) => format!("{} {}", line, word),
+            None => word.to_string(),
+        };
+        let fits = measure_text(&candidate, Some(&assets.cyberpunk_font), 18, 1.0).width <= max_width;
+        match lines.last_mut() {
+            Some(line) if fits => {
+                *line = candidate;
+            }
+            _ => lines.push(word.to_string()),
+        }
+    }
+    for (i, line) in lines.iter().enumerate() {
+        let line_dimensions = measure_text(line, Some(&assets.cyberpunk_font), 18, 1.0);
+        let line_y = scr_height * 0.45 + (i as f32) * 26.0;
+        draw_text_ex(line, (scr_width - line_dimensions.width) / 2.0, line_y, TextParams {
+            font: Some(&assets.cyberpunk_font),
+            font_size: 18,
+            color: WHITE,
+            ..Default::default()
+        });
+    }
+
+    // Draw the back button
+    let button_width = 200.0;
+    let button_x = (scr_width - button_width) / 2.0;
+    let back_clicked = draw_button("Back", button_x, scr_height * 0.7, button_width, assets);
+
+    back_clicked || is_key_pressed(KeyCode::Enter) || is_key_pressed(KeyCode::Escape)
+}
+
+/// Draw the settings screen and apply the player's changes to `settings`.
+///
+/// Up and Down select a row, Left and Right change its value and Enter rebinds
+/// a key. Clicking a row steps it up, right-clicking steps it down.
+/// Returns `Back` once the player leaves the screen and `Calibrate` when the
+/// offset calibration should start.
+pub fn draw_settings(
+    state: &mut SettingsScreenState,
+    settings: &mut Settings,
+    assets: &Assets
+) -> Option<SettingsRow> {
+    let scr_width = screen_width();
+    let scr_height = screen_height();
+
+    clear_background(DARK_BACKGROUND);
+
+    draw_text_ex("Settings", 20.0, scr_height * 0.1, TextParams {
+        font: Some(&assets.cyberpunk_font),
+        font_size: CYBERPUNK_FONT_SIZE as u16,
+        color: NEON_PINK,
+        ..Default::default()
+    });
+
+    let rows = SettingsRow::ALL;
+    // Rows shrink to keep the whole list on small windows
+    let row_height = ((scr_height * 0.85) / (rows.len() as f32) - 8.0).min(40.0);
+    let start_y = scr_height * 0.13;
+    let row_x = scr_width * 0.1;
+    let row_width = scr_width * 0.8;
+
+    // While rebinding, the next bindable key press goes to the selected row
+    if state.rebinding {
+        if let Some(key) = get_last_key_pressed() {
+            if let Some(slot) = rows[state.selected].key_slot() {
+                if is_bindable(key) {
+                    settings.bind_key(slot, key);
+                }
+            }
+            state.rebinding = false; // Any other key, like Escape, cancels
+        }
+    } else {
+        if is_key_pressed(KeyCode::Escape) {
+            return Some(SettingsRow::Back);
+        }
+        if is_key_pressed(KeyCode::Down) {
+            state.selected = (state.selected + 1) % rows.len();
+        }
+        if is_key_pressed(KeyCode::Up) {
+            state.selected = (state.selected + rows.len() - 1) % rows.len();
+        }
+
+        let mut step = 0;
+        if is_key_pressed(KeyCode::Right) {
+            step += 1;
+        }
+        if is_key_pressed(KeyCode::Left) {
+            step -= 1;
+        }
+        let mut activate = is_key_pressed(KeyCode::Enter);
+
+        // The mouse selects the hovered row and clicks step it
+        let mouse_pos = mouse_position();
+        for (i, _) in rows.iter().enumerate() {
+            let row_y = start_y + (i as f32) * (row_height + 8.0);
+            let is_hovered =
+                mouse_pos.0 >= row_x &&
+                mouse_pos.0 <= row_x + row_width &&
+                mouse_pos.1 >= row_y &&
+                mouse_pos.1 <= row_y + row_height;
+            if is_hovered {
+                if is_mouse_button_pressed(MouseButton::Left) {
+                    state.selected = i;
+                    step += 1;
+                    activate = true;
+                } else if is_mouse_button_pressed(MouseButton::Right) {
+                    state.selected = i;
+                    step -= 1;
+                }
+            }
+        }
+
+        let row = rows[state.selected];
+        if matches!(row, SettingsRow::Back | SettingsRow::Calibrate) && activate {
+            return Some(row);
+        }
+        if row.key_slot().is_some() && activate {
+            state.rebinding = true;
+        } else if step != 0 {
+            row.adjust(settings, step);
+        } else if row == SettingsRow::Fullscreen && activate {
+            row.adjust(settings, 1);
+        }
+    }
+
+    // Draw every row with its current value
+    for (i, row) in rows.iter().enumerate() {
+        let row_y = start_y + (i as f32) * (row_height + 8.0);
+        let is_selected = i == state.selected;
+        let row_color = if is_selected { NEON_PURPLE } else { Color::new(0.1, 0.1, 0.2, 1.0) };
+        draw_rectangle(row_x, row_y, row_width, row_height, row_color);
+
+        let text_y = row_y + row_height * 0.7;
+        draw_text_ex(row.label(), row_x + 15.0, text_y, TextParams {
+            font: Some(&assets.cyberpunk_font),
+            font_size: CYBERPUNK_FONT_SIZE as u16,
+            color: WHITE,
+            ..Default::default()
+        });
+
+        let value = if is_selected && state.rebinding {
+            "Press a key...".to_string()
+        } else {
+            row.value(settings)
+        };
+        let value_dimensions = measure_text(
+            &value,
+            Some(&assets.cyberpunk_font),
+            CYBERPUNK_FONT_SIZE as u16,
+            1.0
+        );
+        draw_text_ex(&value, row_x + row_width - value_dimensions.width - 15.0, text_y, TextParams {
+            font: Some(&assets.cyberpunk_font),
+            font_size: CYBERPUNK_FONT_SIZE as u16,
+            color: if is_selected { NEON_GREEN } else { NEON_BLUE },
+            ..Default::default()
+        });
+    }
+
+    None
+}
+
+/// Draw the offset calibration: a pulse on every click while it runs, then the result
+pub fn draw_calibration(calibration: &CalibrationState, position: f64, assets: &Assets) {
+    let scr_width = screen_width();
+    let scr_height = screen_height();
+
+    clear_background(DARK_BACKGROUND);
+
+    let draw_centered = |text: &str, y: f32, font_size: u16, color: Color| {
+        let dimensions = measure_text(text, Some(&assets.cyberpunk_font), font_size, 1.0);
+        draw_text_ex(text, (scr_width - dimensions.width) / 2.0, y, TextParams {
+            font: Some(&assets.cyberpunk_font),
+            font_size,
+            color,
+            ..Default::default()
+        });
+    };
+
+    draw_centered("Offset calibration", scr_height * 0.15, FONT_SIZE, NEON_PINK);
+
+    if !calibration.finished {
+        let beat = calibration.beat_times.iter().filter(|&&time| time <= position).count();
+        let instruction = if beat <= CALIBRATION_LEAD_IN {
+            "Listen to the clicks..."
+        } else {
+            "Tap along with your hit keys"
+        };
+        draw_centered(instruction, scr_height * 0.3, CYBERPUNK_FONT_SIZE as u16, WHITE);
+
+        // Flash a circle on every click so the beat is visible as well as audible
+        let since_click = calibration.beat_times
+            .iter()
+            .rev()
+            .find(|&&time| time <= position)
+            .map_or(f64::INFINITY, |time| position - time);
+        let flash = (1.0 - (since_click / 0.2)).clamp(0.0, 1.0) as f32;
+        draw_circle(
+            scr_width / 2.0,
+            scr_height * 0.55,
+            40.0 + 20.0 * flash,
+            Color::new(NEON_BLUE.r, NEON_BLUE.g, NEON_BLUE.b, 0.3 + 0.7 * flash)
+        );
+
+        let progress = format!("{} / {}", beat, calibration.beat_times.len());
+        draw_centered(&progress, scr_height * 0.8, 18, NEON_PURPLE);
+        draw_centered("Press Escape to cancel", scr_height * 0.9, 18, NEON_PURPLE);
+        return;
+    }
+
+    match calibration.result {
+        Some(result) => {
+            let direction = if result.offset < 0.0 { "early" } else { "late" };
+            let offset = format!("Offset: {:+.0} ms ({})", result.offset * 1000.0, direction);
+            let jitter = format!("Jitter: {:.0} ms over {} taps", result.jitter * 1000.0, result.taps);
+            draw_centered(&offset, scr_height * 0.4, CYBERPUNK_FONT_SIZE as u16, NEON_GREEN);
+            draw_centered(&jitter, scr_height * 0.5, CYBERPUNK_FONT_SIZE as u16, WHITE);
+            draw_centered(
+                "Press Enter to save, Escape to discard",
+                scr_height * 0.7,
+                18,
+                NEON_PURPLE
+            );
+        }
+        None => {
+            let font_size = CYBERPUNK_FONT_SIZE as u16;
+            draw_centered("No taps were recorded", scr_height * 0.4, font_size, NEON_ORANGE);
+            draw_centered("Press Enter to go back", scr_height * 0.7, 18, NEON_PURPLE);
+        }
+    }
+}
+
+/// Draw a labelled button and return whether it was clicked this frame
+fn draw_button(label: &str, button_x: f32, button_y: f32, button_width: f32, assets: &Assets) -> bool {
+    let button_height = 60.0;
+
+    let mouse_pos = mouse_position();
+    let is_hovered =
+        mouse_pos.0 >= button_x &&
+        mouse_pos.0 <= button_x + button_width &&
+        mouse_pos.1 >= button_y &&
+        mouse_pos.1 <= button_y + button_height;
+    let button_color = if is_hovered { NEON_GREEN } else { NEON_BLUE };
+    draw_rectangle(button_x, button_y, button_width, button_height, button_color);
+
+    let label_dimensions = measure_text(
+        label,
+        Some(&assets.cyberpunk_font),
+        CYBERPUNK_FONT_SIZE as u16,
+        1.0
+    );
+    draw_text_ex(
+        label,
+        button_x + (button_width - label_dimensions.width) / 2.0,
+        button_y + (button_height + label_dimensions.height) / 2.0,
+        TextParams {
+            font: Some(&assets.cyberpunk_font),
+            font_size: CYBERPUNK_FONT_SIZE as u16,
+            color: WHITE,
+            ..Default::default()
+        }
+    );
+
+    is_mouse_button_pressed(MouseButton::Left) && is_hovered
+}
+
+/// Draw the local profile: totals on top and the best plays below.
+///
+/// Returns true when the player goes back to the menu.
+pub fn draw_profile(profile: &Profile, assets: &Assets) -> bool {
+    let scr_width = screen_width();
+    let scr_height = screen_height();
+
+    clear_background(DARK_BACKGROUND);
+
+    draw_text_ex("Profile", 20.0, scr_height * 0.1, TextParams {
+        font: Some(&assets.cyberpunk_font),
+        font_size: CYBERPUNK_FONT_SIZE as u16,
+        color: NEON_PINK,
+        ..Default::default()
+    });
+
+    let minutes = (profile.play_time / 60.0).round() as u64;
+    let totals = [
+        format!("Total: {:.0} pp", profile.total_pp()),
+        format!("Plays: {}", profile.play_count),
+        format!("Hits: {}", profile.total_hits),
+        format!("Play time: {}h {:02}m", minutes / 60, minutes % 60),
+    ];
+    for (i, line) in totals.iter().enumerate() {
+        draw_text_ex(line, 20.0, scr_height * 0.18 + (i as f32) * STATS_LINE_HEIGHT, TextParams {
+            font: Some(&assets.cyberpunk_font),
+            font_size: STATS_FONT_SIZE as u16,
+            color: if i == 0 { NEON_GREEN } else { WHITE },
+            ..Default::default()
+        });
+    }
+
+    // The best plays that fit above the back button, highest pp first
+    let list_y = scr_height * 0.18 + 5.0 * STATS_LINE_HEIGHT;
+    let rows = ((scr_height * 0.82 - list_y) / STATS_LINE_HEIGHT).max(0.0) as usize;
+    if profile.best_plays.is_empty() {
+        draw_text_ex("No plays yet", 20.0, list_y, TextParams {
+            font: Some(&assets.cyberpunk_font),
+            font_size: STATS_FONT_SIZE as u16,
+            color: NEON_PURPLE,
+            ..Default::default()
+        });
+    }
+    for (i, play) in profile.best_plays.iter().take(rows).enumerate() {
+        let line = format!(
+            "{}. {}  {:.2}*  {:.0} pp  {:.2}%  {}",
+            i + 1,
+            play.title,
+            play.stars,
+            play.pp,
+            play.accuracy * 100.0,
+            play.grade.label()
+        );
+        draw_text_ex(&line, 20.0, list_y + (i as f32) * STATS_LINE_HEIGHT, TextParams {
+            font: Some(&assets.cyberpunk_font),
+            font_size: STATS_FONT_SIZE as u16,
+            color: NEON_BLUE,
+            ..Default::default()
+        });
+    }
+
+    let button_width = 200.0;
+    let back_clicked = draw_button(
+        "Back",
+        (scr_width - button_width) / 2.0,
+        scr_height * 0.85,
+        button_width,
+        assets
+    );
+    back_clicked || is_key_pressed(KeyCode::Escape)
+}
+
+/// Draw the saved replays, newest first, with the selected one highlighted.
+///
+/// Up and down move the selection and Enter or a click watches a replay.
+/// Returns "Watch" or "Back" when the player picks a replay or goes back.
+pub fn draw_replays(browser: &mut ReplayBrowserState, assets: &Assets) -> Option<String> {
+    let scr_width = screen_width();
+    let scr_height = screen_height();
+
+    clear_background(DARK_BACKGROUND);
+
+    draw_text_ex("Replays", 20.0, scr_height * 0.1, TextParams {
+        font: Some(&assets.cyberpunk_font),
+        font_size: CYBERPUNK_FONT_SIZE as u16,
+        color: NEON_PINK,
+        ..Default::default()
+    });
+
+    let count = browser.replays.len();
+    if count > 0 {
+        if is_key_pressed(KeyCode::Down) {
+            browser.selected = (browser.selected + 1) % count;
+        }
+        if is_key_pressed(KeyCode::Up) {
+            browser.selected = (browser.selected + count - 1) % count;
+        }
+    }
+    let mut choice = None;
+    if count > 0 && is_key_pressed(KeyCode::Enter) {
+        choice = Some("Watch".to_string());
+    }
+
+    // The replays that fit above the back button, scrolled to keep the selection visible
+    let list_y = scr_height * 0.18;
+    let rows = ((scr_height * 0.82 - list_y) / STATS_LINE_HEIGHT).max(1.0) as usize;
+    let first = (browser.selected + 1).saturating_sub(rows);
+    if count == 0 {
+        draw_text_ex("No replays yet", 20.0, list_y, TextParams {
+            font: Some(&assets.cyberpunk_font),
+            font_size: STATS_FONT_SIZE as u16,
+            color: NEON_PURPLE,
+            ..Default::default()
+        });
+    }
+    let mouse_pos = mouse_position();
+    for (i, entry) in browser.replays.iter().enumerate().skip(first).take(rows) {
+        let row_y = list_y + ((i - first) as f32) * STATS_LINE_HEIGHT;
+        let is_hovered =
+            mouse_pos.1 >= row_y - STATS_LINE_HEIGHT * 0.75 &&
+            mouse_pos.1 < row_y + STATS_LINE_HEIGHT * 0.25;
+        if is_hovered && is_mouse_button_pressed(MouseButton::Left) {
+            browser.selected = i;
+            choice = Some("Watch".to_string());
+        }
+
+        let song_name = Path::new(&entry.song_path)
+            .file_stem()
+            .map_or_else(|| entry.song_path.clone(), |stem| stem.to_string_lossy().to_string());
+        let mut line = match entry.level {
+            Some(level) => format!("{} [{:?}]", song_name, level),
+            None => song_name,
+        };
+        line.push_str(&format!("  {}", entry.score));
+        if entry.mods.auto() {
+            line.push_str("  Auto");
+        }
+        draw_text_ex(&line, 20.0, row_y, TextParams {
+            font: Some(&assets.cyberpunk_font),
+            font_size: STATS_FONT_SIZE as u16,
+            color: if i == browser.selected { NEON_GREEN } else { NEON_BLUE },
+            ..Default::default()
+        });
+    }
+
+    let button_width = 200.0;
+    let back_clicked = draw_button(
+        "Back",
+        (scr_width - button_width) / 2.0,
+        scr_height * 0.85,
+        button_width,
+        assets
+    );
+    if back_clicked || is_key_pressed(KeyCode::Escape) {
+        choice = Some("Back".to_string());
+    }
+    choice
+}
+
+/// Draw the results of a finished play.
+///
+/// `pp` is the performance of the play, None on unrated maps.
+/// `verified` tells whether a watched replay reproduced its recorded score.
+/// Returns "Retry", "Replay" or "Back" when the matching button is clicked.
+pub fn draw_results(
+    stats: &PlayStats,
+    pp: Option<f64>,
+    verified: Option<bool>,
+    assets: &Assets
+) -> Option<String> {
+    let scr_width = screen_width();
+    let scr_height = screen_height();
+
+    clear_background(DARK_BACKGROUND);
+
+    // Draw the grade large on the left
+    let grade = stats.grade();
+    let grade_color = match grade {
+        Grade::SS | Grade::S => NEON_PINK,
+        Grade::A => NEON_GREEN,
+        Grade::B => NEON_BLUE,
+        Grade::C => NEON_PURPLE,
+        Grade::D => NEON_ORANGE,
+    };
+    draw_text_ex(grade.label(), scr_width * 0.1, scr_height * 0.35, TextParams {
+        font: Some(&assets.cyberpunk_font),
+        font_size: 120,
+        color: grade_color,
+        ..Default::default()
+    });
+
+    // List the numbers on the right
+    let offset_ms = stats.average_offset() * 1000.0;
+    let offset_hint = if offset_ms < 0.0 { "early" } else { "late" };
+    let lines = [
+        format!("Score: {}", stats.score),
+        format!("Accuracy: {:.2}%", stats.accuracy() * 100.0),
+        format!("Max combo: {}x", stats.max_combo),
+        format!(
+            "300: {}  100: {}  50: {}  Miss: {}",
+            stats.count_300,
+            stats.count_100,
+            stats.count_50,
+            stats.count_miss
+        ),
+        format!("Average offset: {:+.1} ms ({})", offset_ms, offset_hint),
+        format!("Unstable rate: {:.1}", stats.unstable_rate()),
+        pp.map_or_else(|| "Performance: unrated".to_string(), |pp| format!("Performance: {:.0} pp", pp)),
+    ];
+    for (i, line) in lines.iter().enumerate() {
+        draw_text_ex(line, scr_width * 0.4, scr_height * 0.12 + (i as f32) * 34.0, TextParams {
+            font: Some(&assets.cyberpunk_font),
+            font_size: CYBERPUNK_FONT_SIZE as u16,
+            color: if i == 0 { NEON_PINK } else { WHITE },
+            ..Default::default()
+        });
+    }
+
+    // Report whether a watched replay judged the same as the recorded play
+    if let Some(verified) = verified {
+        let (text, color) = if verified {
+            ("Replay verified", NEON_GREEN)
+        } else {
+            ("Replay score differs from the recorded play", NEON_ORANGE)
+        };
+        draw_text_ex(text, scr_width * 0.1, scr_height * 0.45, TextParams {
+            font: Some(&assets.cyberpunk_font),
+            font_size: 18,
+            color,
+            ..Default::default()
+        });
+    }
+
+    draw_hit_error_histogram(
+        &stats.hit_errors,
+        scr_width * 0.1,
+        scr_height * 0.5,
+        scr_width * 0.8,
+        scr_height * 0.25
+    );
+
+    // Draw the retry, replay and back buttons side by side
+    let button_width = 200.0;
+    let button_spacing = 20.0;
+    let button_y = scr_height * 0.82;
+    let start_x = (scr_width - 3.0 * button_width - 2.0 * button_spacing) / 2.0;
+    let mut selected_button = None;
+    for (i, label) in ["Retry", "Replay", "Back"].iter().enumerate() {
+        let button_x = start_x + (i as f32) * (button_width + button_spacing);
+        if draw_button(label, button_x, button_y, button_width, assets) {
+            selected_button = Some(label.to_string());
+        }
+    }
+    selected_button
+}
+
+/// Draw a histogram of hit errors, early hits on the left and late hits on the right
+fn draw_hit_error_histogram(hit_errors: &[f64], x: f32, y: f32, width: f32, height: f32) {
+    let mut bins = [0usize; HIT_ERROR_BINS];
+    for error in hit_errors {
+        let position = (error / HIT_ERROR_RANGE + 1.0) / 2.0; // 0 at the earliest, 1 at the latest
+        let bin = (position * (HIT_ERROR_BINS as f64)).floor() as isize;
+        bins[bin.clamp(0, (HIT_ERROR_BINS as isize) - 1) as usize] += 1;
+    }
+
+    // Scale the bars so the fullest bin fills the height
+    let max_count = bins.iter().copied().max().unwrap_or(0).max(1);
+    let bin_width = width / (HIT_ERROR_BINS as f32);
+    for (i, &count) in bins.iter().enumerate() {
+        let bar_height = height * ((count as f32) / (max_count as f32));
+        draw_rectangle(
+            x + (i as f32) * bin_width + 1.0,
+            y + height - bar_height,
+            bin_width - 2.0,
+            bar_height,
+            NEON_BLUE
+        );
+    }
+
+    // Mark a perfectly timed hit in the middle
+    draw_line(x + width / 2.0, y, x + width / 2.0, y + height, 2.0, NEON_PINK);
+    draw_line(x, y + height, x + width, y + height, 1.0, NEON_PURPLE);
+}
+
 /// Draw the score.
 ///
 /// The score is drawn in the top right corner of the screen.
@@ -388,7 +1060,7 @@ pub fn draw_loading_bar(elapsed_time: f32, assets: &Assets) {
 /// The `score` parameter is the current score.
 ///
 /// The function draws a black rectangle with a white border and the score inside.
-pub fn draw_score(score: i32, assets: &Assets) {
+pub fn draw_score(score: u64, assets: &Assets) {
     let score_text = format!("Score: {}", score);
 
     // Neon glow effect behind the score
@@ -408,6 +1080,97 @@ pub fn draw_score(score: i32, assets: &Assets) {
     });
 }
 
+/// Draw the combo, accuracy and judgement counts below the score.
+///
+/// The combo turns pink while it is the best combo of the play.
+pub fn draw_play_stats(stats: &PlayStats, assets: &Assets) {
+    let combo_color = if stats.combo > 0 && stats.combo == stats.max_combo {
+        NEON_PINK
+    } else {
+        NEON_GREEN
+    };
+    let lines = [
+        (format!("{}x", stats.combo), combo_color),
+        (format!("{:.2}%", stats.accuracy() * 100.0), NEON_BLUE),
+        (
+            format!(
+                "300: {}  100: {}  50: {}  Miss: {}",
+                stats.count_300,
+                stats.count_100,
+                stats.count_50,
+                stats.count_miss
+            ),
+            NEON_PURPLE,
+        ),
+    ];
+
+    for (i, (text, color)) in lines.iter().enumerate() {
+        let text_y = DRAW_SCORE_Y + STATS_LINE_HEIGHT * ((i + 1) as f32);
+        draw_text_ex(text, DRAW_SCORE_X, text_y, TextParams {
+            font: Some(&assets.cyberpunk_font),
+            font_size: STATS_FONT_SIZE as u16,
+            color: *color,
+            ..Default::default()
+        });
+    }
+}
+
+/// Show how fast the spinner in progress is spun below the playfield centre
+pub fn draw_spinner_rate(circles: &[Circle], elapsed: f64, assets: &Assets) {
+    let spinning = circles.iter().find(|circle| {
+        circle.spinner
+            .as_ref()
+            .is_some_and(|spinner| !spinner.finished && elapsed >= spinner.start_time)
+    });
+    let Some(spinner) = spinning.and_then(|circle| circle.spinner.as_ref()) else {
+        return;
+    };
+    let text = format!("{:.1} RPS", spinner.rate);
+    let dimensions = measure_text(&text, Some(&assets.cyberpunk_font), STATS_FONT_SIZE as u16, 1.0);
+    let x = screen_width() / 2.0 - dimensions.width / 2.0;
+    let y = screen_height() / 2.0 + SPINNER_RADIUS + STATS_LINE_HEIGHT * 2.0;
+    draw_text_ex(&text, x, y, TextParams {
+        font: Some(&assets.cyberpunk_font),
+        font_size: STATS_FONT_SIZE as u16,
+        color: NEON_GREEN,
+        ..Default::default()
+    });
+}
+
+/// Show which mods the next play uses in the bottom right corner
+pub fn draw_mods(mods: Mods, assets: &Assets) {
+    let (text, color) = if mods.auto() {
+        ("Auto: On (Tab)", NEON_GREEN)
+    } else {
+        ("Auto: Off (Tab)", NEON_PURPLE)
+    };
+    let dimensions = measure_text(text, Some(&assets.cyberpunk_font), 18, 1.0);
+    draw_text_ex(text, screen_width() - dimensions.width - 20.0, screen_height() - 20.0, TextParams {
+        font: Some(&assets.cyberpunk_font),
+        font_size: 18,
+        color,
+        ..Default::default()
+    });
+}
+
+/// Draw the difficulty level generated maps are played at, above the mods
+pub fn draw_level(level: DifficultyLevel, assets: &Assets) {
+    let text = format!("Difficulty: {:?} (Left/Right)", level);
+    let dimensions = measure_text(&text, Some(&assets.cyberpunk_font), 18, 1.0);
+    draw_text_ex(&text, screen_width() - dimensions.width - 20.0, screen_height() - 45.0, TextParams {
+        font: Some(&assets.cyberpunk_font),
+        font_size: 18,
+        color: NEON_BLUE,
+        ..Default::default()
+    });
+}
+
+/// Draw the cursor of a replay being watched or of the autoplay bot
+pub fn draw_ghost_cursor(position: Vec2) {
+    draw_circle(position.x, position.y, 8.0, Color::new(NEON_PINK.r, NEON_PINK.g, NEON_PINK.b, 0.6));
+    draw_circle_lines(position.x, position.y, 12.0, 2.0, NEON_PINK);
+}
+
 /// Draw the floating texts.
 ///
 /// The `floating_texts` parameter is a vector of `FloatingText` structs containing the texts to draw.